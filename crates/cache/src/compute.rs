@@ -0,0 +1,385 @@
+use std::{
+    fmt::{Debug, Display},
+    future::Future,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rand::Rng;
+use tokio::{sync::OnceCell, time::sleep};
+
+use crate::{lock::Lock, Cache, CacheStorage, Key};
+
+/// Interval between reads while waiting on another instance to fill the key
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Options for `CacheStorage::get_or_compute_with`
+#[derive(Clone, Debug)]
+pub struct ComputeOptions {
+    ttl: Duration,
+    stale_ttl: Option<Duration>,
+    beta: f64,
+    lock_ttl: Duration,
+}
+
+impl ComputeOptions {
+    pub fn new(ttl: Duration) -> Self {
+        ComputeOptions {
+            ttl,
+            stale_ttl: None,
+            beta: 1.0,
+            lock_ttl: Duration::from_secs(5),
+        }
+    }
+
+    /// Keep the value for `d` after it expires and serve it when the loader fails
+    pub fn serve_stale_for(mut self, d: Duration) -> Self {
+        self.stale_ttl = Some(d);
+        self
+    }
+
+    /// Weight of the probabilistic early refresh, `0.0` disables it
+    pub fn beta(mut self, beta: f64) -> Self {
+        self.beta = beta;
+        self
+    }
+
+    /// How long other instances wait on the recomputation lock
+    pub fn lock_ttl(mut self, ttl: Duration) -> Self {
+        self.lock_ttl = ttl;
+        self
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ComputeError<E> {
+    /// The cache backend failed
+    Cache(E),
+    /// The loader returned an error and no stale value was available
+    Loader(String),
+    /// The cached value could not be parsed back into the requested type
+    Decode(String),
+}
+
+impl<E: Debug> Display for ComputeError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cache(e) => write!(f, "cache error: {:?}", e),
+            Self::Loader(e) => write!(f, "loader error: {}", e),
+            Self::Decode(e) => write!(f, "decode error: {}", e),
+        }
+    }
+}
+
+/// Cached value together with the metadata needed for early refresh.
+///
+/// The value is stored as is, so that plain reads of the key see it, and the
+/// metadata as `{expires_at_ms}:{delta_ms}` under `{key}:meta`.
+struct Entry {
+    expires_at: u128,
+    delta: u128,
+    value: String,
+}
+
+impl Entry {
+    fn parse(meta: &str, value: String) -> Option<Self> {
+        let (expires_at, delta) = meta.split_once(':')?;
+        Some(Entry {
+            expires_at: expires_at.parse().ok()?,
+            delta: delta.parse().ok()?,
+            value,
+        })
+    }
+
+    fn meta(&self) -> String {
+        format!("{}:{}", self.expires_at, self.delta)
+    }
+
+    fn is_fresh(&self) -> bool {
+        now_ms() < self.expires_at
+    }
+
+    /// XFetch: refresh early with a probability that grows as expiry gets closer
+    /// and with the time the last computation took
+    fn should_refresh(&self, beta: f64) -> bool {
+        if beta <= 0.0 {
+            return false;
+        }
+        let r: f64 = rand::thread_rng().gen_range(f64::EPSILON..1.0);
+        let gap = self.delta as f64 * beta * -r.ln();
+        now_ms() as f64 + gap >= self.expires_at as f64
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Key of the refresh metadata of `key`, a full key
+fn meta_key(key: &str) -> String {
+    format!("{key}:meta")
+}
+
+fn decode<V, E>(s: &str) -> Result<V, ComputeError<E>>
+where
+    V: FromStr,
+    <V as FromStr>::Err: Display,
+{
+    V::from_str(s).map_err(|e| ComputeError::Decode(e.to_string()))
+}
+
+impl<C: Cache> CacheStorage<C> {
+    /// Returns the value at `key`, computing and caching it with `f` on a miss.
+    ///
+    /// Concurrent callers in this process share one in-flight computation and
+    /// other instances wait on a short lock in the cache. The value can also be
+    /// read with `get`, a value set directly is computed again unless an
+    /// earlier computation left its refresh metadata.
+    pub async fn get_or_compute<V, F, Fut, E>(
+        &self,
+        key: impl Into<Key>,
+        ttl: Duration,
+        f: F,
+    ) -> Result<V, ComputeError<C::Err>>
+    where
+        V: FromStr + ToString,
+        <V as FromStr>::Err: Display,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+        E: Display,
+    {
+        self.get_or_compute_with(key, ComputeOptions::new(ttl), f)
+            .await
+    }
+
+    pub async fn get_or_compute_with<V, F, Fut, E>(
+        &self,
+        key: impl Into<Key>,
+        opts: ComputeOptions,
+        f: F,
+    ) -> Result<V, ComputeError<C::Err>>
+    where
+        V: FromStr + ToString,
+        <V as FromStr>::Err: Display,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+        E: Display,
+    {
+        let key = self.key(key);
+        let cached = self.read_entry(&key)?;
+        if let Some(ref entry) = cached {
+            if entry.is_fresh() && !entry.should_refresh(opts.beta) {
+                return decode(&entry.value);
+            }
+        }
+
+        let cell = {
+            let mut inflight = self.inflight.lock().unwrap();
+            Arc::clone(
+                inflight
+                    .entry(key.clone())
+                    .or_insert_with(|| Arc::new(OnceCell::new())),
+            )
+        };
+        let result = cell
+            .get_or_init(|| self.load(&key, &opts, cached.as_ref(), f))
+            .await
+            .clone();
+        {
+            let mut inflight = self.inflight.lock().unwrap();
+            if inflight.get(&key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+                inflight.remove(&key);
+            }
+        }
+
+        match result {
+            Ok(v) => decode(&v),
+            Err(e) => match cached {
+                Some(entry) if entry.is_fresh() || opts.stale_ttl.is_some() => decode(&entry.value),
+                _ => Err(ComputeError::Loader(e)),
+            },
+        }
+    }
+
+    fn read_entry(&self, key: &str) -> Result<Option<Entry>, ComputeError<C::Err>> {
+        let value = match self.inner.get::<String>(key.to_string()) {
+            Ok(Some(v)) => v,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(ComputeError::Cache(e)),
+        };
+        match self.inner.get::<String>(meta_key(key)) {
+            Ok(meta) => Ok(meta.and_then(|m| Entry::parse(&m, value))),
+            Err(e) => Err(ComputeError::Cache(e)),
+        }
+    }
+
+    /// Runs the loader once for this process, `key` is the full key. Failures are returned as strings
+    /// so they can be shared with every waiter on the same key.
+    async fn load<V, F, Fut, E>(
+        &self,
+        key: &str,
+        opts: &ComputeOptions,
+        cached: Option<&Entry>,
+        f: F,
+    ) -> Result<String, String>
+    where
+        V: ToString,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+        E: Display,
+    {
+        let guard = Lock::new(self, format!("{key}:lock"), opts.lock_ttl)
+            .try_acquire()
+            .map_err(|e| format!("{:?}", e))?;
+        if guard.is_none() {
+            // Another instance is computing, a usable value beats waiting for it
+            if let Some(entry) = cached {
+                if entry.is_fresh() || opts.stale_ttl.is_some() {
+                    return Ok(entry.value.clone());
+                }
+            }
+            let deadline = Instant::now() + opts.lock_ttl;
+            while Instant::now() < deadline {
+                sleep(LOCK_POLL_INTERVAL).await;
+                if let Ok(Some(entry)) = self.read_entry(key) {
+                    if entry.is_fresh() {
+                        return Ok(entry.value);
+                    }
+                }
+            }
+        }
+
+        let started = Instant::now();
+        let result = f().await;
+        let delta = started.elapsed().as_millis();
+        match result {
+            Ok(v) => {
+                let entry = Entry {
+                    expires_at: now_ms() + opts.ttl.as_millis(),
+                    delta,
+                    value: v.to_string(),
+                };
+                let ttl = opts.ttl + opts.stale_ttl.unwrap_or_default();
+                // metadata first, a value without it is computed again
+                if let Err(e) = self.inner.set_ex(meta_key(key), entry.meta(), ttl) {
+                    return Err(format!("{:?}", e));
+                }
+                match self.inner.set_ex(key.to_string(), &entry.value, ttl) {
+                    Ok(_) => Ok(entry.value),
+                    Err(e) => Err(format!("{:?}", e)),
+                }
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::testing::MemoryCache;
+
+    #[test]
+    fn test_entry_roundtrip() {
+        let entry = Entry {
+            expires_at: 10,
+            delta: 2,
+            value: "a:b".to_string(),
+        };
+        let parsed = Entry::parse(&entry.meta(), entry.value).unwrap();
+        assert_eq!(parsed.expires_at, 10);
+        assert_eq!(parsed.delta, 2);
+        assert_eq!(parsed.value, "a:b");
+        assert!(Entry::parse("garbage", "a".to_string()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_or_compute_caches_value() {
+        let cache = CacheStorage::new(MemoryCache::default());
+        let calls = AtomicUsize::new(0);
+        for _ in 0..3 {
+            let v: u32 = cache
+                .get_or_compute("answer".to_string(), Duration::from_secs(60), || async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, String>(42)
+                })
+                .await
+                .unwrap();
+            assert_eq!(v, 42);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get::<u32>("answer").unwrap(), Some(42));
+
+        // values set directly carry no metadata and are computed again
+        cache.set("other", 7).unwrap();
+        let v: u32 = cache
+            .get_or_compute("other".to_string(), Duration::from_secs(60), || async {
+                Ok::<_, String>(43)
+            })
+            .await
+            .unwrap();
+        assert_eq!(v, 43);
+    }
+
+    #[tokio::test]
+    async fn test_get_or_compute_single_flight() {
+        let cache = CacheStorage::new(MemoryCache::default());
+        let calls = AtomicUsize::new(0);
+        let load = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            sleep(Duration::from_millis(50)).await;
+            Ok::<_, String>("value".to_string())
+        };
+        let ttl = Duration::from_secs(60);
+        let (a, b, c) = tokio::join!(
+            cache.get_or_compute::<String, _, _, _>("k".to_string(), ttl, load),
+            cache.get_or_compute::<String, _, _, _>("k".to_string(), ttl, load),
+            cache.get_or_compute::<String, _, _, _>("k".to_string(), ttl, load),
+        );
+        assert_eq!(a.unwrap(), "value");
+        assert_eq!(b.unwrap(), "value");
+        assert_eq!(c.unwrap(), "value");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_or_compute_serves_stale_on_error() {
+        let cache = CacheStorage::new(MemoryCache::default());
+        let opts = ComputeOptions::new(Duration::from_millis(10))
+            .serve_stale_for(Duration::from_secs(60))
+            .beta(0.0);
+        let v: String = cache
+            .get_or_compute_with("k".to_string(), opts.clone(), || async {
+                Ok::<_, String>("old".to_string())
+            })
+            .await
+            .unwrap();
+        assert_eq!(v, "old");
+
+        sleep(Duration::from_millis(20)).await;
+        let v: String = cache
+            .get_or_compute_with("k".to_string(), opts, || async {
+                Err::<String, _>("db down")
+            })
+            .await
+            .unwrap();
+        assert_eq!(v, "old");
+    }
+
+    #[tokio::test]
+    async fn test_get_or_compute_loader_error() {
+        let cache = CacheStorage::new(MemoryCache::default());
+        let r: Result<String, _> = cache
+            .get_or_compute("k".to_string(), Duration::from_secs(1), || async {
+                Err::<String, _>("db down")
+            })
+            .await;
+        assert_eq!(r, Err(ComputeError::Loader("db down".to_string())));
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt::Display;
+
+/// Separator between key segments and between the storage prefix and a key
+pub const SEPARATOR: char = ':';
+
+/// Cache key built from segments, e.g. `Key::new("seller").push(42)` is `seller:42`.
+///
+/// Modules can expose typed builders by implementing `From<TheirId> for Key`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Key(String);
+
+impl Key {
+    pub fn new(namespace: impl Display) -> Self {
+        Key(namespace.to_string())
+    }
+
+    /// Appends a segment to the key
+    pub fn push(mut self, segment: impl Display) -> Self {
+        self.0.push(SEPARATOR);
+        self.0.push_str(&segment.to_string());
+        self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<String> for Key {
+    fn from(s: String) -> Self {
+        Key(s)
+    }
+}
+
+impl From<&str> for Key {
+    fn from(s: &str) -> Self {
+        Key(s.to_string())
+    }
+}
+
+impl From<&String> for Key {
+    fn from(s: &String) -> Self {
+        Key(s.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::MemoryCache, Cache, CacheStorage};
+
+    #[test]
+    fn test_key_segments() {
+        let key = Key::new("seller").push(42).push("products");
+        assert_eq!(key.as_str(), "seller:42:products");
+        assert_eq!(Key::from("raw"), Key::new("raw"));
+    }
+
+    #[test]
+    fn test_prefix_isolates_storages() {
+        let inner = MemoryCache::default();
+        inner.set("dev:user:1".to_string(), "dev").unwrap();
+        let cache = CacheStorage::new(inner).with_prefix("prod");
+        assert_eq!(cache.key("user:1"), "prod:user:1");
+        assert_eq!(cache.get::<String>("user:1").unwrap(), None);
+    }
+}
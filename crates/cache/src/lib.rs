@@ -1,11 +1,40 @@
-use std::{fmt::Debug, str::FromStr};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+    future::Future,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use futures_util::StreamExt;
+use key::SEPARATOR;
+use pubsub::{Subscription, Topic};
+use tokio::sync::OnceCell;
+
+pub use key::Key;
+
+pub mod compute;
+pub mod key;
+pub mod lock;
+pub mod pubsub;
 pub mod redis;
+pub mod tag;
+#[cfg(test)]
+mod testing;
 
 pub trait Cache {
     type Err: Debug;
 
     fn set<V>(&self, key: String, value: V) -> Result<(), Self::Err>
+    where
+        V: ToString;
+    /// Sets `key` to `value`, expiring after `ttl`
+    fn set_ex<V>(&self, key: String, value: V, ttl: Duration) -> Result<(), Self::Err>
+    where
+        V: ToString;
+    /// Sets `key` to `value` only if it does not exist yet, returns whether it was set
+    fn set_nx<V>(&self, key: String, value: V, ttl: Duration) -> Result<bool, Self::Err>
     where
         V: ToString;
     fn get<V>(&self, key: String) -> Result<Option<V>, Self::Err>
@@ -16,63 +45,156 @@ pub trait Cache {
     where
         V: FromStr,
         <V as FromStr>::Err: std::fmt::Display;
-    fn subscribe<V, F>(&self, topic: String, f: F) -> Result<Option<V>, Self::Err>
+    /// Deletes `key` only if it currently holds `value`, returns whether it was deleted
+    fn compare_and_delete<V>(&self, key: String, value: V) -> Result<bool, Self::Err>
+    where
+        V: ToString;
+    /// Resets the expiry of `key` only if it currently holds `value`
+    fn compare_and_expire<V>(
+        &self,
+        key: String,
+        value: V,
+        ttl: Duration,
+    ) -> Result<bool, Self::Err>
+    where
+        V: ToString;
+    /// Adds `members` to the set stored at `key`, keeping the set for at least
+    /// `ttl`, or without expiry when `None`
+    fn set_add(
+        &self,
+        key: String,
+        members: Vec<String>,
+        ttl: Option<Duration>,
+    ) -> Result<(), Self::Err>;
+    fn set_members(&self, key: String) -> Result<Vec<String>, Self::Err>;
+    fn set_remove(&self, key: String, members: Vec<String>) -> Result<(), Self::Err>;
+    /// Deletes `keys`, returns how many existed
+    fn delete(&self, keys: Vec<String>) -> Result<usize, Self::Err>;
+    /// Subscribes to `topics`, yielding decoded messages until the stream is dropped.
+    ///
+    /// Messages are buffered up to a limit, past which new ones are dropped
+    /// and an `Err` item reports how many were lost.
+    fn subscribe<V>(
+        &self,
+        topics: Vec<Topic>,
+    ) -> impl Future<Output = Result<Subscription<V, Self::Err>, Self::Err>> + Send
     where
-        V: FromStr + Clone,
-        <V as FromStr>::Err: std::fmt::Debug,
-        F: FnMut(V) -> ControlFlow<V>;
+        V: FromStr + Send + 'static,
+        <V as FromStr>::Err: Display;
 
     fn publish<V: ToString>(&self, topic: String, v: V) -> Result<(), Self::Err>;
 }
 
-pub enum ControlFlow<T> {
-    Continue,
-    Break(T),
-}
+/// Computation shared by every caller waiting on the same key
+type InFlight = Arc<OnceCell<Result<String, String>>>;
 
-/// Wrapper for struct implementing Cache 
+/// Wrapper for struct implementing Cache.
+///
+/// Keys and channels are namespaced with the configured prefix, so that
+/// environments and modules sharing one server do not collide.
 pub struct CacheStorage<C: Cache> {
     inner: C,
+    prefix: Option<String>,
+    inflight: Mutex<HashMap<String, InFlight>>,
 }
 
 impl<C: Cache> CacheStorage<C> {
     pub fn new(cache: C) -> Self {
-        CacheStorage { inner: cache }
+        CacheStorage {
+            inner: cache,
+            prefix: None,
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Prefixes every key and channel with `prefix`, e.g. `prod` turns `seller:1` into `prod:seller:1`
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Full key as stored in the backend
+    pub fn key(&self, key: impl Into<Key>) -> String {
+        let key = key.into();
+        match &self.prefix {
+            Some(prefix) => format!("{prefix}{SEPARATOR}{key}"),
+            None => key.to_string(),
+        }
+    }
+
+    pub fn set<V>(&self, key: impl Into<Key>, value: V) -> Result<(), C::Err>
+    where
+        V: ToString,
+    {
+        self.inner.set(self.key(key), value)
+    }
+
+    pub fn set_ex<V>(&self, key: impl Into<Key>, value: V, ttl: Duration) -> Result<(), C::Err>
+    where
+        V: ToString,
+    {
+        self.inner.set_ex(self.key(key), value, ttl)
     }
 
-    pub fn set<V>(&self, key: String, value: V) -> Result<(), C::Err>
+    pub fn set_nx<V>(&self, key: impl Into<Key>, value: V, ttl: Duration) -> Result<bool, C::Err>
     where
         V: ToString,
     {
-        self.inner.set(key, value)
+        self.inner.set_nx(self.key(key), value, ttl)
     }
 
-    pub fn get<V>(&self, key: String) -> Result<Option<V>, C::Err>
+    pub fn get<V>(&self, key: impl Into<Key>) -> Result<Option<V>, C::Err>
     where
         V: FromStr + Debug,
         <V as FromStr>::Err: std::fmt::Display,
     {
-        self.inner.get(key)
+        self.inner.get(self.key(key))
     }
 
-    pub fn forget<V>(&self, key: String) -> Result<Option<V>, C::Err>
+    pub fn forget<V>(&self, key: impl Into<Key>) -> Result<Option<V>, C::Err>
     where
         V: FromStr,
         <V as FromStr>::Err: std::fmt::Display,
     {
-        self.inner.forget(key)
+        self.inner.forget(self.key(key))
     }
 
-    pub fn subscribe<V, F>(&self, topic: String, f: F) -> Result<Option<V>, C::Err>
+    pub async fn subscribe<V>(&self, topics: Vec<Topic>) -> Result<Subscription<V, C::Err>, C::Err>
     where
-        V: FromStr + Clone,
-        <V as FromStr>::Err: std::fmt::Debug,
-        F: FnMut(V) -> ControlFlow<V>,
+        V: FromStr + Send + 'static,
+        <V as FromStr>::Err: Display,
+        C::Err: Send + 'static,
     {
-        self.inner.subscribe(topic, f)
+        let topics = topics
+            .into_iter()
+            .map(|topic| match topic {
+                Topic::Channel(c) => Topic::Channel(self.key(c)),
+                Topic::Pattern(p) => Topic::Pattern(self.key(p)),
+            })
+            .collect();
+        let messages = self.inner.subscribe(topics).await?;
+        if self.prefix.is_none() {
+            return Ok(messages);
+        }
+        let prefix = self.prefix.clone();
+        Ok(Box::pin(messages.map(move |msg| {
+            msg.map(|mut msg| {
+                msg.channel = unprefixed(prefix.as_deref(), msg.channel);
+                msg.pattern = msg.pattern.map(|p| unprefixed(prefix.as_deref(), p));
+                msg
+            })
+        })))
     }
 
-    pub fn publish<V: ToString>(&self, topic: String, v: V) -> Result<(), C::Err> {
-        self.inner.publish(topic, v)
+    pub fn publish<V: ToString>(&self, topic: impl Into<Key>, v: V) -> Result<(), C::Err> {
+        self.inner.publish(self.key(topic), v)
+    }
+}
+
+/// Strips the storage prefix from a key or channel returned by the backend
+fn unprefixed(prefix: Option<&str>, key: String) -> String {
+    match prefix.and_then(|p| key.strip_prefix(p)?.strip_prefix(SEPARATOR)) {
+        Some(k) => k.to_string(),
+        None => key,
     }
 }
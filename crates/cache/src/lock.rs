@@ -0,0 +1,248 @@
+use std::{
+    fmt::{Debug, Display},
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::time::{interval_at, sleep};
+
+use crate::{Cache, CacheStorage, Key};
+
+/// Interval between attempts while waiting for a held lock
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Shortest lease of `with_lock`, renewed every half of it
+const MIN_TTL: Duration = Duration::from_millis(2);
+
+#[derive(Debug, PartialEq)]
+pub enum LockError<E> {
+    /// The cache backend failed
+    Cache(E),
+    /// The lock is held by another owner
+    Busy,
+    /// The lease expired or was taken over before the work finished
+    Lost,
+    /// The lease is shorter than `with_lock` can renew
+    InvalidTtl(Duration),
+}
+
+impl<E: Debug> Display for LockError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cache(e) => write!(f, "cache error: {:?}", e),
+            Self::Busy => f.write_str("lock is busy"),
+            Self::Lost => f.write_str("lock was lost"),
+            Self::InvalidTtl(ttl) => write!(f, "lock ttl {ttl:?} is below {MIN_TTL:?}"),
+        }
+    }
+}
+
+/// Distributed lock on a cache key, built on `SET NX PX` with a random owner token
+pub struct Lock<'a, C: Cache> {
+    cache: &'a CacheStorage<C>,
+    key: String,
+    ttl: Duration,
+}
+
+impl<'a, C: Cache> Lock<'a, C> {
+    /// `key` is used as is, `CacheStorage::lock` applies the storage prefix
+    pub(crate) fn new(cache: &'a CacheStorage<C>, key: String, ttl: Duration) -> Self {
+        Lock { cache, key, ttl }
+    }
+
+    /// Acquires the lock if it is free, returns `None` when another owner holds it
+    pub fn try_acquire(&self) -> Result<Option<LockGuard<'a, C>>, C::Err> {
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        if !self
+            .cache
+            .inner
+            .set_nx(self.key.clone(), &token, self.ttl)?
+        {
+            return Ok(None);
+        }
+        Ok(Some(LockGuard {
+            cache: self.cache,
+            key: self.key.clone(),
+            token,
+            released: false,
+        }))
+    }
+
+    /// Retries until the lock is acquired or `timeout` elapses
+    pub async fn acquire(&self, timeout: Duration) -> Result<Option<LockGuard<'a, C>>, C::Err> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(guard) = self.try_acquire()? {
+                return Ok(Some(guard));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            sleep(RETRY_INTERVAL).await;
+        }
+    }
+}
+
+/// Held lock, released when dropped
+pub struct LockGuard<'a, C: Cache> {
+    cache: &'a CacheStorage<C>,
+    key: String,
+    token: String,
+    released: bool,
+}
+
+impl<C: Cache> LockGuard<'_, C> {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Extends the lease to `ttl` from now, returns `false` if the lock is no longer ours
+    pub fn extend(&self, ttl: Duration) -> Result<bool, C::Err> {
+        self.cache
+            .inner
+            .compare_and_expire(self.key.clone(), &self.token, ttl)
+    }
+
+    /// Releases the lock, returns `false` if it had already expired or changed owner
+    pub fn release(mut self) -> Result<bool, C::Err> {
+        self.released = true;
+        self.cache
+            .inner
+            .compare_and_delete(self.key.clone(), &self.token)
+    }
+}
+
+impl<C: Cache> Drop for LockGuard<'_, C> {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self
+                .cache
+                .inner
+                .compare_and_delete(self.key.clone(), &self.token);
+        }
+    }
+}
+
+impl<C: Cache> CacheStorage<C> {
+    pub fn lock(&self, key: impl Into<Key>, ttl: Duration) -> Lock<'_, C> {
+        Lock::new(self, self.key(key), ttl)
+    }
+
+    /// Runs `fut` while holding the lock on `key`, renewing the lease every `ttl / 2`.
+    ///
+    /// Returns `LockError::Busy` without running `fut` when the lock is held elsewhere,
+    /// and drops `fut` with `LockError::Lost` if the lease cannot be renewed. A `ttl`
+    /// under 2ms is rejected with `LockError::InvalidTtl`.
+    pub async fn with_lock<T, Fut>(
+        &self,
+        key: impl Into<Key>,
+        ttl: Duration,
+        fut: Fut,
+    ) -> Result<T, LockError<C::Err>>
+    where
+        Fut: Future<Output = T>,
+    {
+        if ttl < MIN_TTL {
+            return Err(LockError::InvalidTtl(ttl));
+        }
+        let guard = match self.lock(key, ttl).try_acquire() {
+            Ok(Some(guard)) => guard,
+            Ok(None) => return Err(LockError::Busy),
+            Err(e) => return Err(LockError::Cache(e)),
+        };
+
+        let period = ttl / 2;
+        let mut renew = interval_at((Instant::now() + period).into(), period);
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                v = &mut fut => {
+                    let _ = guard.release();
+                    return Ok(v);
+                }
+                _ = renew.tick() => match guard.extend(ttl) {
+                    Ok(true) => (),
+                    Ok(false) => return Err(LockError::Lost),
+                    Err(e) => return Err(LockError::Cache(e)),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemoryCache;
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let cache = CacheStorage::new(MemoryCache::default());
+        let lock = cache.lock("payout:1".to_string(), Duration::from_secs(10));
+        let guard = lock.try_acquire().unwrap().unwrap();
+        assert!(lock.try_acquire().unwrap().is_none());
+        assert!(guard.release().unwrap());
+        assert!(lock.try_acquire().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_lock_released_on_drop() {
+        let cache = CacheStorage::new(MemoryCache::default());
+        let lock = cache.lock("payout:1".to_string(), Duration::from_secs(10));
+        {
+            let _guard = lock.try_acquire().unwrap().unwrap();
+        }
+        assert!(lock.try_acquire().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_release_does_not_remove_other_owner() {
+        let cache = CacheStorage::new(MemoryCache::default());
+        let lock = cache.lock("payout:1".to_string(), Duration::from_millis(10));
+        let stale = lock.try_acquire().unwrap().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        let current = lock.try_acquire().unwrap().unwrap();
+        assert!(!stale.extend(Duration::from_secs(10)).unwrap());
+        assert!(!stale.release().unwrap());
+        assert!(current.extend(Duration::from_secs(10)).unwrap());
+        assert!(lock.try_acquire().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_with_lock() {
+        let cache = CacheStorage::new(MemoryCache::default());
+        let ttl = Duration::from_millis(40);
+        let v = cache
+            .with_lock("payout:1".to_string(), ttl, async {
+                // outlive the initial lease, renewal must keep the lock ours
+                sleep(Duration::from_millis(100)).await;
+                let busy = cache.with_lock("payout:1".to_string(), ttl, async {}).await;
+                assert_eq!(busy, Err(LockError::Busy));
+                1
+            })
+            .await;
+        assert_eq!(v, Ok(1));
+        assert!(cache
+            .lock("payout:1".to_string(), ttl)
+            .try_acquire()
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_with_lock_rejects_short_ttl() {
+        let cache = CacheStorage::new(MemoryCache::default());
+        for ttl in [Duration::ZERO, Duration::from_nanos(1)] {
+            let v = cache
+                .with_lock("payout:1".to_string(), ttl, async { 1 })
+                .await;
+            assert_eq!(v, Err(LockError::InvalidTtl(ttl)));
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use std::pin::Pin;
+
+use futures_util::Stream;
+
+/// What to subscribe to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Topic {
+    /// A single channel name
+    Channel(String),
+    /// A glob-style pattern such as `orders.*`
+    Pattern(String),
+}
+
+/// Message received on a subscription
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message<V> {
+    /// Channel the message was published on
+    pub channel: String,
+    /// Pattern that matched, for pattern subscriptions
+    pub pattern: Option<String>,
+    pub payload: V,
+}
+
+/// Stream of messages, decode and connection failures are yielded as `Err` items
+pub type Subscription<V, E> = Pin<Box<dyn Stream<Item = Result<Message<V>, E>> + Send>>;
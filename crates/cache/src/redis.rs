@@ -1,19 +1,308 @@
-use super::Cache;
-use redis::{Client, Commands, PubSubCommands};
-use std::{fmt::Debug, str::FromStr};
+use super::{
+    pubsub::{Message, Subscription, Topic},
+    Cache,
+};
+use futures_util::{stream, StreamExt};
+use redis::{
+    aio::PubSub, cluster::ClusterClient, cluster::ClusterConnection, sentinel::Sentinel, Client,
+    Commands, ConnectionLike, ErrorKind, RedisResult,
+};
+use std::{
+    fmt::{Debug, Display},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::sleep,
+};
+
+/// Delay bounds between reconnect attempts of a subscription
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Most idle connections kept for reuse
+const MAX_IDLE: usize = 16;
+
+/// Messages a subscription buffers before dropping new ones
+const SUBSCRIPTION_BUFFER: usize = 1024;
 
 #[derive(Clone)]
 pub struct RedisCache {
-    inner: Client,
+    inner: Topology,
+    /// Connections returned by finished commands, dropped on the first
+    /// connection error
+    idle: Arc<Mutex<Vec<Connection>>>,
+}
+
+/// How the cache reaches redis
+#[derive(Clone)]
+enum Topology {
+    Single(Client),
+    /// The master is resolved through the sentinels once, then again after a
+    /// connection error or a write refused by a demoted master
+    Sentinel {
+        sentinel: Arc<Mutex<Sentinel>>,
+        service_name: String,
+        master: Arc<Mutex<Option<Client>>>,
+    },
+    /// `ClusterConnection` follows MOVED and ASK redirections. Published messages
+    /// reach every node, so subscriptions use a plain connection to a seed node.
+    Cluster {
+        client: ClusterClient,
+        nodes: Vec<Client>,
+    },
+}
+
+/// Connection to whichever topology the cache was built with
+enum Connection {
+    Single(redis::Connection),
+    Cluster(Box<ClusterConnection>),
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<redis::Value> {
+        match self {
+            Self::Single(c) => c.req_packed_command(cmd),
+            Self::Cluster(c) => c.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<redis::Value>> {
+        match self {
+            Self::Single(c) => c.req_packed_commands(cmd, offset, count),
+            Self::Cluster(c) => c.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Single(c) => c.get_db(),
+            Self::Cluster(c) => c.get_db(),
+        }
+    }
+
+    fn check_connection(&mut self) -> bool {
+        match self {
+            Self::Single(c) => c.check_connection(),
+            Self::Cluster(c) => c.check_connection(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            Self::Single(c) => c.is_open(),
+            Self::Cluster(c) => c.is_open(),
+        }
+    }
 }
 
 impl RedisCache {
     pub async fn new(address: String) -> Self {
-        let client = match redis::Client::open(address) {
+        match Self::try_new(address).await {
             Ok(c) => c,
-            Err(e) => panic!("Failed to initailize cache:redis, err={}", e.to_string()),
+            Err(e) => panic!("Failed to initailize cache:redis, err={}", e),
+        }
+    }
+
+    /// Same as `new`, but returns the error instead of panicking
+    pub async fn try_new(address: String) -> Result<Self, String> {
+        match redis::Client::open(address) {
+            Ok(c) => Ok(RedisCache::from(Topology::Single(c))),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Connects to the master named `service_name`, as reported by `sentinels`
+    pub async fn sentinel(sentinels: Vec<String>, service_name: String) -> Result<Self, String> {
+        let mut sentinel = match Sentinel::build(sentinels) {
+            Ok(s) => s,
+            Err(e) => return Err(e.to_string()),
         };
-        RedisCache { inner: client }
+        // Fail early when no sentinel knows the master
+        let master = match sentinel.master_for(&service_name, None) {
+            Ok(m) => m,
+            Err(e) => return Err(e.to_string()),
+        };
+        Ok(RedisCache::from(Topology::Sentinel {
+            sentinel: Arc::new(Mutex::new(sentinel)),
+            service_name,
+            master: Arc::new(Mutex::new(Some(master))),
+        }))
+    }
+
+    /// Connects to a redis cluster through the seed `nodes`
+    pub async fn cluster(nodes: Vec<String>) -> Result<Self, String> {
+        let client = match ClusterClient::new(nodes.clone()) {
+            Ok(c) => c,
+            Err(e) => return Err(e.to_string()),
+        };
+        let nodes = match nodes.into_iter().map(Client::open).collect() {
+            Ok(n) => n,
+            Err(e) => return Err(e.to_string()),
+        };
+        Ok(RedisCache::from(Topology::Cluster { client, nodes }))
+    }
+
+    /// An idle connection, or a new one
+    fn connection(&self) -> Result<Pooled<'_>, String> {
+        let idle = self.idle.lock().unwrap().pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => self.connect()?,
+        };
+        Ok(Pooled {
+            cache: self,
+            conn: Some(conn),
+            broken: false,
+        })
+    }
+
+    fn connect(&self) -> Result<Connection, String> {
+        let conn = match &self.inner {
+            Topology::Single(client) => client.get_connection().map(Connection::Single),
+            Topology::Sentinel { .. } => self
+                .master()
+                .and_then(|client| client.get_connection())
+                .map(Connection::Single),
+            Topology::Cluster { client, .. } => client
+                .get_connection()
+                .map(|c| Connection::Cluster(Box::new(c))),
+        };
+        conn.map_err(|e| {
+            self.reset();
+            e.to_string()
+        })
+    }
+
+    fn master(&self) -> RedisResult<Client> {
+        match &self.inner {
+            Topology::Sentinel {
+                sentinel,
+                service_name,
+                master,
+            } => {
+                if let Some(client) = master.lock().unwrap().as_ref() {
+                    return Ok(client.clone());
+                }
+                let client = sentinel.lock().unwrap().master_for(service_name, None)?;
+                *master.lock().unwrap() = Some(client.clone());
+                Ok(client)
+            }
+            Topology::Single(client) => Ok(client.clone()),
+            Topology::Cluster { nodes, .. } => Ok(nodes[0].clone()),
+        }
+    }
+
+    /// Drops idle connections and the resolved master after a connection error
+    fn reset(&self) {
+        self.idle.lock().unwrap().clear();
+        if let Topology::Sentinel { master, .. } = &self.inner {
+            *master.lock().unwrap() = None;
+        }
+    }
+
+    /// Client used for pub/sub, trying each seed node of a cluster in turn.
+    /// Failures reset the cache like a broken connection does.
+    async fn pubsub(&self, topics: &[Topic]) -> RedisResult<PubSub> {
+        let nodes = match &self.inner {
+            Topology::Cluster { nodes, .. } => nodes.clone(),
+            _ => vec![self.master()?],
+        };
+        let mut last_err = None;
+        for node in nodes {
+            match open_pubsub(&node, topics).await {
+                Ok(p) => return Ok(p),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        // the master may have moved, resolve it again on the next attempt
+        self.reset();
+        Err(last_err.unwrap_or_else(|| (ErrorKind::ClientError, "no nodes").into()))
+    }
+}
+
+impl From<Topology> for RedisCache {
+    fn from(inner: Topology) -> Self {
+        RedisCache {
+            inner,
+            idle: Arc::new(Mutex::new(vec![])),
+        }
+    }
+}
+
+/// Connection going back to the idle ones once dropped, unless it failed
+struct Pooled<'a> {
+    cache: &'a RedisCache,
+    conn: Option<Connection>,
+    broken: bool,
+}
+
+impl Pooled<'_> {
+    fn conn(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+
+    fn check<T>(&mut self, result: RedisResult<T>) -> RedisResult<T> {
+        if let Err(e) = &result {
+            self.broken |= e.is_io_error()
+                || e.is_connection_dropped()
+                || e.is_unrecoverable_error()
+                || e.kind() == ErrorKind::ReadOnly;
+        }
+        result
+    }
+}
+
+impl ConnectionLike for Pooled<'_> {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<redis::Value> {
+        let result = self.conn().req_packed_command(cmd);
+        self.check(result)
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<redis::Value>> {
+        let result = self.conn().req_packed_commands(cmd, offset, count);
+        self.check(result)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.as_ref().map_or(0, |c| c.get_db())
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.conn().check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        self.conn.as_ref().is_some_and(|c| c.is_open())
+    }
+}
+
+impl Drop for Pooled<'_> {
+    fn drop(&mut self) {
+        let conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => return,
+        };
+        if self.broken {
+            self.cache.reset();
+            return;
+        }
+        let mut idle = self.cache.idle.lock().unwrap();
+        if conn.is_open() && idle.len() < MAX_IDLE {
+            idle.push(conn);
+        }
     }
 }
 
@@ -24,31 +313,68 @@ impl Cache for RedisCache {
     where
         T: ToString,
     {
-        let mut conn = match self.inner.get_connection().map_err(|e| e.to_string()) {
+        let mut conn = match self.connection() {
             Ok(c) => c,
             Err(e) => return Err(e.to_string()),
         };
         if let Some(err) = conn.set::<_, _, ()>(key, value.to_string()).err() {
-            let e = if let Some(e) = err.detail() {
-                e.to_string()
-            } else {
-                err.code().unwrap().to_string()
+            // connection errors have neither a detail nor a code
+            let e = match err.detail().or(err.code()) {
+                Some(e) => e.to_string(),
+                None => err.to_string(),
             };
             return Err(e);
         }
         Ok(())
     }
 
+    fn set_ex<T>(&self, key: String, value: T, ttl: Duration) -> Result<(), Self::Err>
+    where
+        T: ToString,
+    {
+        let mut conn = match self.connection() {
+            Ok(c) => c,
+            Err(e) => return Err(e.to_string()),
+        };
+        conn.pset_ex::<_, _, ()>(key, value.to_string(), ttl.as_millis() as u64)
+            .map_err(|e| e.to_string())
+    }
+
+    fn set_nx<T>(&self, key: String, value: T, ttl: Duration) -> Result<bool, Self::Err>
+    where
+        T: ToString,
+    {
+        let mut conn = match self.connection() {
+            Ok(c) => c,
+            Err(e) => return Err(e.to_string()),
+        };
+        let rv: redis::Value = match redis::cmd("SET")
+            .arg(key)
+            .arg(value.to_string())
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query(&mut conn)
+        {
+            Ok(rv) => rv,
+            Err(e) => return Err(e.to_string()),
+        };
+        Ok(matches!(rv, redis::Value::Okay))
+    }
+
     fn get<V>(&self, key: String) -> Result<Option<V>, Self::Err>
     where
         V: FromStr + Debug,
         <V as FromStr>::Err: std::fmt::Display,
     {
-        let mut conn = match self.inner.get_connection().map_err(|e| e.to_string()) {
+        let mut conn = match self.connection() {
             Ok(c) => c,
             Err(e) => return Err(e.to_string()),
         };
-        let v: redis::Value = conn.get(key).unwrap();
+        let v: redis::Value = match conn.get(key) {
+            Ok(v) => v,
+            Err(e) => return Err(e.to_string()),
+        };
         let v = match v {
             redis::Value::Nil => None,
             redis::Value::Int(i) => match V::from_str(i.to_string().as_str()) {
@@ -77,11 +403,14 @@ impl Cache for RedisCache {
         V: FromStr,
         <V as FromStr>::Err: std::fmt::Display,
     {
-        let mut conn = match self.inner.get_connection().map_err(|e| e.to_string()) {
+        let mut conn = match self.connection() {
             Ok(c) => c,
             Err(e) => return Err(e.to_string()),
         };
-        let v: redis::Value = conn.get_del(key).unwrap();
+        let v: redis::Value = match conn.get_del(key) {
+            Ok(v) => v,
+            Err(e) => return Err(e.to_string()),
+        };
         let v = match v {
             redis::Value::Nil => None,
             redis::Value::Int(i) => match V::from_str(i.to_string().as_str()) {
@@ -105,32 +434,143 @@ impl Cache for RedisCache {
         Ok(v)
     }
 
-    fn subscribe<V, F>(&self, topic: String, mut f: F) -> Result<Option<V>, Self::Err>
+    fn compare_and_delete<V>(&self, key: String, value: V) -> Result<bool, Self::Err>
     where
-        V: FromStr + Clone,
-        <V as FromStr>::Err: std::fmt::Debug,
-        F: FnMut(V) -> super::ControlFlow<V>,
+        V: ToString,
     {
-        let mut conn = match self.inner.get_connection().map_err(|e| e.to_string()) {
+        let mut conn = match self.connection() {
             Ok(c) => c,
             Err(e) => return Err(e.to_string()),
         };
-        let r = conn.subscribe(&[&topic], |msg| {
-            let payload = msg.get_payload::<String>().unwrap();
-            let msg = V::from_str(&payload).unwrap();
-            match f(msg) {
-                super::ControlFlow::Break(v) => redis::ControlFlow::Break(v),
-                super::ControlFlow::Continue => redis::ControlFlow::Continue,
-            }
-        });
-        match r {
-            Ok(v) => Ok(Some(v)),
+        let script = redis::Script::new(
+            r#"if redis.call("GET", KEYS[1]) == ARGV[1] then
+                return redis.call("DEL", KEYS[1])
+            end
+            return 0"#,
+        );
+        match script
+            .key(key)
+            .arg(value.to_string())
+            .invoke::<i64>(&mut conn)
+        {
+            Ok(n) => Ok(n == 1),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn compare_and_expire<V>(&self, key: String, value: V, ttl: Duration) -> Result<bool, Self::Err>
+    where
+        V: ToString,
+    {
+        let mut conn = match self.connection() {
+            Ok(c) => c,
+            Err(e) => return Err(e.to_string()),
+        };
+        let script = redis::Script::new(
+            r#"if redis.call("GET", KEYS[1]) == ARGV[1] then
+                return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+            end
+            return 0"#,
+        );
+        match script
+            .key(key)
+            .arg(value.to_string())
+            .arg(ttl.as_millis() as u64)
+            .invoke::<i64>(&mut conn)
+        {
+            Ok(n) => Ok(n == 1),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn set_add(
+        &self,
+        key: String,
+        members: Vec<String>,
+        ttl: Option<Duration>,
+    ) -> Result<(), Self::Err> {
+        let mut conn = match self.connection() {
+            Ok(c) => c,
+            Err(e) => return Err(e.to_string()),
+        };
+        // the expiry only grows: PTTL is -2 for a new set and -1 for one kept forever
+        let script = redis::Script::new(
+            r#"local current = redis.call("PTTL", KEYS[1])
+            redis.call("SADD", KEYS[1], unpack(ARGV, 2))
+            if ARGV[1] == "" then
+                redis.call("PERSIST", KEYS[1])
+            elseif current == -2 or (current >= 0 and current < tonumber(ARGV[1])) then
+                redis.call("PEXPIRE", KEYS[1], ARGV[1])
+            end
+            return 0"#,
+        );
+        let ttl = ttl.map(|t| t.as_millis().to_string()).unwrap_or_default();
+        match script
+            .key(key)
+            .arg(ttl)
+            .arg(members)
+            .invoke::<i64>(&mut conn)
+        {
+            Ok(_) => Ok(()),
             Err(e) => Err(e.to_string()),
         }
     }
 
+    fn set_members(&self, key: String) -> Result<Vec<String>, Self::Err> {
+        let mut conn = match self.connection() {
+            Ok(c) => c,
+            Err(e) => return Err(e.to_string()),
+        };
+        conn.smembers(key).map_err(|e| e.to_string())
+    }
+
+    fn set_remove(&self, key: String, members: Vec<String>) -> Result<(), Self::Err> {
+        let mut conn = match self.connection() {
+            Ok(c) => c,
+            Err(e) => return Err(e.to_string()),
+        };
+        conn.srem::<_, _, ()>(key, members)
+            .map_err(|e| e.to_string())
+    }
+
+    fn delete(&self, keys: Vec<String>) -> Result<usize, Self::Err> {
+        let mut conn = match self.connection() {
+            Ok(c) => c,
+            Err(e) => return Err(e.to_string()),
+        };
+        // one DEL per key so that keys in different cluster slots can be removed
+        let mut removed = 0;
+        for key in keys {
+            match conn.del::<_, usize>(key) {
+                Ok(n) => removed += n,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn subscribe<V>(
+        &self,
+        topics: Vec<Topic>,
+    ) -> Result<Subscription<V, Self::Err>, Self::Err>
+    where
+        V: FromStr + Send + 'static,
+        <V as FromStr>::Err: Display,
+    {
+        // The first connection is made here so that a bad address is reported to the caller
+        let pubsub = match self.pubsub(&topics).await {
+            Ok(p) => p,
+            Err(e) => return Err(e.to_string()),
+        };
+        let (tx, rx) = mpsc::channel(SUBSCRIPTION_BUFFER);
+        tokio::spawn(forward_messages(self.clone(), topics, pubsub, tx));
+        Ok(Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        })))
+    }
+
     fn publish<V: ToString>(&self, topic: String, v: V) -> Result<(), Self::Err> {
-        let mut conn = match self.inner.get_connection().map_err(|e| e.to_string()) {
+        let mut conn = match self.connection() {
             Ok(c) => c,
             Err(e) => return Err(e.to_string()),
         };
@@ -157,17 +597,144 @@ impl Cache for RedisCache {
     }
 }
 
+async fn open_pubsub(client: &Client, topics: &[Topic]) -> RedisResult<PubSub> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    for topic in topics {
+        match topic {
+            Topic::Channel(channel) => pubsub.subscribe(channel).await?,
+            Topic::Pattern(pattern) => pubsub.psubscribe(pattern).await?,
+        }
+    }
+    Ok(pubsub)
+}
+
+fn decode_message<V>(msg: redis::Msg) -> Result<Message<V>, String>
+where
+    V: FromStr,
+    <V as FromStr>::Err: Display,
+{
+    let payload = match msg.get_payload::<String>() {
+        Ok(p) => p,
+        Err(e) => return Err(e.to_string()),
+    };
+    let payload = match V::from_str(&payload) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(format!(
+                "invalid payload on {}: {}",
+                msg.get_channel_name(),
+                e
+            ))
+        }
+    };
+    let pattern = if msg.from_pattern() {
+        msg.get_pattern::<String>().ok()
+    } else {
+        None
+    };
+    Ok(Message {
+        channel: msg.get_channel_name().to_string(),
+        pattern,
+        payload,
+    })
+}
+
+/// Queues `item` for the subscriber, returns false once it is gone.
+///
+/// When the subscriber is a full buffer behind, `item` is dropped and counted
+/// in `lagged`, which is reported as an error once there is room again.
+fn forward<V>(
+    tx: &mpsc::Sender<Result<Message<V>, String>>,
+    lagged: &mut u64,
+    item: Result<Message<V>, String>,
+) -> bool {
+    if *lagged > 0 {
+        let error = format!("subscriber lagged, {lagged} messages dropped");
+        match tx.try_send(Err(error)) {
+            Ok(()) => *lagged = 0,
+            Err(TrySendError::Full(_)) => {
+                *lagged += 1;
+                return true;
+            }
+            Err(TrySendError::Closed(_)) => return false,
+        }
+    }
+    match tx.try_send(item) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            *lagged += 1;
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+/// Pumps messages into `tx` until the receiver is dropped, resubscribing
+/// to `topics` whenever the connection is lost
+async fn forward_messages<V>(
+    cache: RedisCache,
+    topics: Vec<Topic>,
+    pubsub: PubSub,
+    tx: mpsc::Sender<Result<Message<V>, String>>,
+) where
+    V: FromStr + Send + 'static,
+    <V as FromStr>::Err: Display,
+{
+    let mut pubsub = Some(pubsub);
+    let mut backoff = MIN_BACKOFF;
+    let mut lagged = 0;
+    loop {
+        let conn = match pubsub.take() {
+            Some(p) => p,
+            None => match cache.pubsub(&topics).await {
+                Ok(p) => {
+                    backoff = MIN_BACKOFF;
+                    p
+                }
+                Err(e) => {
+                    if !forward(&tx, &mut lagged, Err(e.to_string())) {
+                        return;
+                    }
+                    tokio::select! {
+                        _ = sleep(backoff) => (),
+                        _ = tx.closed() => return,
+                    }
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            },
+        };
+
+        let messages = conn.into_on_message();
+        tokio::pin!(messages);
+        loop {
+            tokio::select! {
+                msg = messages.next() => match msg {
+                    Some(msg) => {
+                        if !forward(&tx, &mut lagged, decode_message(msg)) {
+                            return;
+                        }
+                    }
+                    // connection dropped, resubscribe to a freshly resolved master
+                    None => {
+                        cache.reset();
+                        break;
+                    }
+                },
+                _ = tx.closed() => return,
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
-
     use super::*;
     use testcontainers::{
         core::{IntoContainerPort, WaitFor},
         runners::AsyncRunner,
-        ContainerAsync, GenericImage,
+        ContainerAsync, GenericImage, ImageExt,
     };
-    use tokio::time::sleep;
 
     async fn new_server_and_client() -> (ContainerAsync<GenericImage>, super::RedisCache) {
         let server = GenericImage::new("redis", "7.2.4")
@@ -248,18 +815,204 @@ mod tests {
     async fn test_subscribe() {
         let (_server, cache) = new_server_and_client().await;
 
-        let cache_clone = cache.clone();
-        let handler = std::thread::spawn(move || {
-            cache.subscribe::<String, _>(
+        let mut messages = cache
+            .subscribe::<String>(vec![Topic::Channel("channel1".to_string())])
+            .await
+            .unwrap();
+        cache
+            .publish(
                 "channel1".to_string(),
-                |msg| -> crate::ControlFlow<String> { crate::ControlFlow::Break(msg) },
+                "Hey! check out what I sent ya way :)",
             )
+            .unwrap();
+        let msg = messages.next().await.unwrap().unwrap();
+        assert_eq!(msg.channel, "channel1");
+        assert_eq!(msg.payload, "Hey! check out what I sent ya way :)");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_pattern_and_decode_error() {
+        let (_server, cache) = new_server_and_client().await;
+
+        let mut messages = cache
+            .subscribe::<u32>(vec![Topic::Pattern("orders.*".to_string())])
+            .await
+            .unwrap();
+        cache
+            .publish("orders.created".to_string(), "not a number")
+            .unwrap();
+        cache.publish("orders.paid".to_string(), 42).unwrap();
+
+        assert!(messages.next().await.unwrap().is_err());
+        let msg = messages.next().await.unwrap().unwrap();
+        assert_eq!(msg.channel, "orders.paid");
+        assert_eq!(msg.pattern.as_deref(), Some("orders.*"));
+        assert_eq!(msg.payload, 42);
+    }
+
+    #[test]
+    fn test_slow_subscriber_lags() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let mut lagged = 0;
+        let message = |payload| {
+            Ok(Message {
+                channel: "orders".to_string(),
+                pattern: None,
+                payload,
+            })
+        };
+        for i in 0..5 {
+            assert!(forward(&tx, &mut lagged, message(i)));
+        }
+        assert_eq!(lagged, 3);
+        assert_eq!(rx.try_recv().unwrap().unwrap().payload, 0);
+        assert_eq!(rx.try_recv().unwrap().unwrap().payload, 1);
+
+        // the drop is reported before the next message
+        assert!(forward(&tx, &mut lagged, message(5)));
+        assert_eq!(
+            rx.try_recv().unwrap().unwrap_err(),
+            "subscriber lagged, 3 messages dropped"
+        );
+        assert_eq!(rx.try_recv().unwrap().unwrap().payload, 5);
+
+        drop(rx);
+        assert!(!forward(&tx, &mut lagged, message(6)));
+    }
+
+    #[tokio::test]
+    async fn test_tagged_storage() {
+        let (_server, cache) = new_server_and_client().await;
+        let storage = crate::CacheStorage::new(cache.clone()).with_prefix("test");
+
+        storage
+            .set_tagged("product:1", "a", Duration::from_secs(60), &["seller:1"])
+            .unwrap();
+        assert_eq!(
+            cache.get::<String>("test:product:1".to_string()).unwrap(),
+            Some("a".to_string())
+        );
+        let ttl: i64 = cache
+            .connection()
+            .unwrap()
+            .pttl("test:tag:seller:1")
+            .unwrap();
+        assert!(ttl > 59_000);
+        assert_eq!(storage.invalidate_tag("seller:1").unwrap(), 1);
+        assert_eq!(storage.get::<String>("product:1").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_reused() {
+        let (_server, cache) = new_server_and_client().await;
+        cache.set("key.name".to_string(), 1).unwrap();
+        assert_eq!(cache.get::<u8>("key.name".to_string()).unwrap(), Some(1));
+        assert_eq!(cache.idle.lock().unwrap().len(), 1);
+
+        // a dead connection is dropped, and the next command reconnects
+        {
+            let mut conn = cache.connection().unwrap();
+            let _ = conn.check(Err::<(), _>((ErrorKind::IoError, "reset").into()));
+        }
+        assert!(cache.idle.lock().unwrap().is_empty());
+        assert_eq!(cache.get::<u8>("key.name".to_string()).unwrap(), Some(1));
+
+        // an idle connection closed by the server fails once, without panicking
+        let mut admin = cache.connect().unwrap();
+        redis::cmd("CLIENT")
+            .arg("KILL")
+            .arg("TYPE")
+            .arg("normal")
+            .arg("SKIPME")
+            .arg("yes")
+            .query::<()>(&mut admin)
+            .unwrap();
+        assert!(cache.get::<u8>("key.name".to_string()).is_err());
+        assert!(cache.idle.lock().unwrap().is_empty());
+        assert_eq!(cache.get::<u8>("key.name".to_string()).unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_failed_subscribe_resets_master() {
+        // nothing listens on port 1, for the sentinel nor the cached master
+        let cache = RedisCache::from(Topology::Sentinel {
+            sentinel: Arc::new(Mutex::new(
+                Sentinel::build(vec!["redis://127.0.0.1:1"]).unwrap(),
+            )),
+            service_name: "mymaster".to_string(),
+            master: Arc::new(Mutex::new(Some(
+                Client::open("redis://127.0.0.1:1").unwrap(),
+            ))),
         });
-        sleep(Duration::from_secs(2)).await;
-        let _ = cache_clone.publish(
-            "channel1".to_string(),
-            "Hey! check out what I sent ya way :)",
+        let topics = vec![Topic::Channel("orders".to_string())];
+        assert!(cache.subscribe::<String>(topics).await.is_err());
+        match &cache.inner {
+            Topology::Sentinel { master, .. } => assert!(master.lock().unwrap().is_none()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_try_new_invalid_address() {
+        assert!(RedisCache::try_new("not-a-url".to_string()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cluster() {
+        let server = GenericImage::new("grokzen/redis-cluster", "7.0.10")
+            .with_wait_for(WaitFor::message_on_stdout("Cluster state changed: ok"))
+            .start()
+            .await
+            .unwrap();
+        // nodes announce their container address in MOVED replies, so talk to them directly
+        let ip = server.get_bridge_ip_address().await.unwrap();
+        let cache =
+            RedisCache::cluster((7000..7003).map(|p| format!("redis://{ip}:{p}")).collect())
+                .await
+                .unwrap();
+
+        for i in 0..20 {
+            cache.set(format!("key.{i}"), i).unwrap();
+        }
+        for i in 0..20 {
+            assert_eq!(cache.get::<u32>(format!("key.{i}")).unwrap(), Some(i));
+        }
+        let keys = (0..20).map(|i| format!("key.{i}")).collect();
+        assert_eq!(cache.delete(keys).unwrap(), 20);
+
+        let mut messages = cache
+            .subscribe::<String>(vec![Topic::Channel("channel1".to_string())])
+            .await
+            .unwrap();
+        cache.publish("channel1".to_string(), "hello").unwrap();
+        assert_eq!(messages.next().await.unwrap().unwrap().payload, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_sentinel() {
+        let (master, _) = new_server_and_client().await;
+        let master_ip = master.get_bridge_ip_address().await.unwrap();
+        let sentinel = GenericImage::new("bitnami/redis-sentinel", "7.2")
+            .with_exposed_port(26379.tcp())
+            .with_wait_for(WaitFor::message_on_stdout("+monitor master"))
+            .with_env_var("REDIS_MASTER_HOST", master_ip.to_string())
+            .with_env_var("REDIS_MASTER_SET", "mymaster")
+            .with_env_var("REDIS_SENTINEL_QUORUM", "1")
+            .start()
+            .await
+            .unwrap();
+        let ip = sentinel.get_bridge_ip_address().await.unwrap();
+
+        let cache = RedisCache::sentinel(vec![format!("redis://{ip}:26379")], "mymaster".into())
+            .await
+            .unwrap();
+        cache.set("key.name".to_string(), "value").unwrap();
+        assert_eq!(
+            cache.get::<String>("key.name".to_string()).unwrap(),
+            Some("value".to_string())
         );
-        let _ = handler.join().unwrap();
+
+        let unknown = RedisCache::sentinel(vec![format!("redis://{ip}:26379")], "other".into());
+        assert!(unknown.await.is_err());
     }
 }
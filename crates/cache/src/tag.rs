@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use crate::{Cache, CacheStorage, Key};
+
+/// Namespace of the sets that track which keys carry a tag
+const TAG_NAMESPACE: &str = "tag";
+
+impl<C: Cache> CacheStorage<C> {
+    fn tag_key(&self, tag: &str) -> String {
+        self.key(Key::new(TAG_NAMESPACE).push(tag))
+    }
+
+    /// Groups `key` under each of `tags`, so that it is removed by `invalidate_tag`.
+    ///
+    /// `ttl` is the expiry of `key`, `None` when it has none: each tag is kept
+    /// at least as long as its longest lived key, and dropped after.
+    pub fn tag(
+        &self,
+        key: impl Into<Key>,
+        ttl: Option<Duration>,
+        tags: &[&str],
+    ) -> Result<(), C::Err> {
+        let key = self.key(key);
+        for tag in tags {
+            self.inner
+                .set_add(self.tag_key(tag), vec![key.clone()], ttl)?;
+        }
+        Ok(())
+    }
+
+    /// Sets `key` to `value` with an expiry and tags it in one call
+    pub fn set_tagged<V>(
+        &self,
+        key: impl Into<Key>,
+        value: V,
+        ttl: Duration,
+        tags: &[&str],
+    ) -> Result<(), C::Err>
+    where
+        V: ToString,
+    {
+        let key = key.into();
+        self.set_ex(key.clone(), value, ttl)?;
+        self.tag(key, Some(ttl), tags)
+    }
+
+    /// Deletes every key tagged with `tag`, returns how many keys were removed.
+    ///
+    /// Members are removed from the tag individually rather than dropping the
+    /// whole set, so keys tagged while this runs are kept for the next call.
+    pub fn invalidate_tag(&self, tag: &str) -> Result<usize, C::Err> {
+        let tag_key = self.tag_key(tag);
+        let members = self.inner.set_members(tag_key.clone())?;
+        if members.is_empty() {
+            return Ok(0);
+        }
+        let removed = self.inner.delete(members.clone())?;
+        self.inner.set_remove(tag_key, members)?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemoryCache;
+
+    #[test]
+    fn test_invalidate_tag() {
+        let cache = CacheStorage::new(MemoryCache::default()).with_prefix("test");
+        let ttl = Duration::from_secs(60);
+        let seller = Key::new("seller").push(1);
+        cache
+            .set_tagged(Key::new("product").push(10), "a", ttl, &[seller.as_str()])
+            .unwrap();
+        cache
+            .set_tagged(Key::new("product").push(11), "b", ttl, &[seller.as_str()])
+            .unwrap();
+        cache.set("product:12", "c").unwrap();
+
+        assert_eq!(cache.invalidate_tag(seller.as_str()).unwrap(), 2);
+        assert_eq!(cache.get::<String>("product:10").unwrap(), None);
+        assert_eq!(cache.get::<String>("product:11").unwrap(), None);
+        assert_eq!(cache.get::<String>("product:12").unwrap(), Some("c".into()));
+        assert_eq!(cache.invalidate_tag(seller.as_str()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_tag_expires_with_its_keys() {
+        let cache = CacheStorage::new(MemoryCache::default());
+        let short = Duration::from_millis(20);
+        cache.set_tagged("a", "a", short, &["t"]).unwrap();
+        cache
+            .set_tagged("b", "b", Duration::from_secs(60), &["t"])
+            .unwrap();
+        cache.set_tagged("c", "c", short, &["t"]).unwrap();
+        std::thread::sleep(short * 2);
+        // kept by its longest lived key
+        assert_eq!(
+            cache.inner.set_members(cache.tag_key("t")).unwrap().len(),
+            3
+        );
+
+        cache.set_tagged("d", "d", short, &["u"]).unwrap();
+        std::thread::sleep(short * 2);
+        assert!(cache
+            .inner
+            .set_members(cache.tag_key("u"))
+            .unwrap()
+            .is_empty());
+
+        // keys without expiry keep their tag forever
+        cache.set_tagged("e", "e", short, &["v"]).unwrap();
+        cache.set("f", "f").unwrap();
+        cache.tag("f", None, &["v"]).unwrap();
+        std::thread::sleep(short * 2);
+        assert_eq!(
+            cache.inner.set_members(cache.tag_key("v")).unwrap().len(),
+            2
+        );
+    }
+}
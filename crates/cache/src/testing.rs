@@ -0,0 +1,178 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Debug, Display},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    pubsub::{Subscription, Topic},
+    Cache,
+};
+
+/// Members and expiry of a set
+type Set = (HashSet<String>, Option<Instant>);
+
+/// In-process `Cache` used by unit tests that don't need a redis server
+#[derive(Default)]
+pub(crate) struct MemoryCache {
+    entries: Mutex<HashMap<String, (String, Option<Instant>)>>,
+    sets: Mutex<HashMap<String, Set>>,
+}
+
+impl MemoryCache {
+    fn take(&self, key: &str, remove: bool) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((_, Some(exp))) if *exp <= Instant::now() => {
+                entries.remove(key);
+                None
+            }
+            Some((v, _)) => {
+                let v = v.clone();
+                if remove {
+                    entries.remove(key);
+                }
+                Some(v)
+            }
+            None => None,
+        }
+    }
+}
+
+impl Cache for MemoryCache {
+    type Err = String;
+
+    fn set<V: ToString>(&self, key: String, value: V) -> Result<(), Self::Err> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key, (value.to_string(), None));
+        Ok(())
+    }
+
+    fn set_ex<V: ToString>(&self, key: String, value: V, ttl: Duration) -> Result<(), Self::Err> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key, (value.to_string(), Some(Instant::now() + ttl)));
+        Ok(())
+    }
+
+    fn set_nx<V: ToString>(&self, key: String, value: V, ttl: Duration) -> Result<bool, Self::Err> {
+        if self.take(&key, false).is_some() {
+            return Ok(false);
+        }
+        self.set_ex(key, value, ttl)?;
+        Ok(true)
+    }
+
+    fn get<V>(&self, key: String) -> Result<Option<V>, Self::Err>
+    where
+        V: FromStr + Debug,
+        <V as FromStr>::Err: std::fmt::Display,
+    {
+        match self.take(&key, false) {
+            Some(v) => V::from_str(&v).map(Some).map_err(|e| e.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    fn forget<V>(&self, key: String) -> Result<Option<V>, Self::Err>
+    where
+        V: FromStr,
+        <V as FromStr>::Err: std::fmt::Display,
+    {
+        match self.take(&key, true) {
+            Some(v) => V::from_str(&v).map(Some).map_err(|e| e.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    fn compare_and_delete<V: ToString>(&self, key: String, value: V) -> Result<bool, Self::Err> {
+        if self.take(&key, false) == Some(value.to_string()) {
+            self.entries.lock().unwrap().remove(&key);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn compare_and_expire<V: ToString>(
+        &self,
+        key: String,
+        value: V,
+        ttl: Duration,
+    ) -> Result<bool, Self::Err> {
+        if self.take(&key, false) == Some(value.to_string()) {
+            self.set_ex(key, value, ttl)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn set_add(
+        &self,
+        key: String,
+        members: Vec<String>,
+        ttl: Option<Duration>,
+    ) -> Result<(), Self::Err> {
+        let now = Instant::now();
+        let mut sets = self.sets.lock().unwrap();
+        if sets
+            .get(&key)
+            .is_some_and(|(_, exp)| exp.is_some_and(|e| e <= now))
+        {
+            sets.remove(&key);
+        }
+        let (set, exp) = sets
+            .entry(key)
+            .or_insert_with(|| (HashSet::new(), ttl.map(|t| now + t)));
+        set.extend(members);
+        *exp = match (*exp, ttl) {
+            (Some(e), Some(t)) => Some(e.max(now + t)),
+            _ => None,
+        };
+        Ok(())
+    }
+
+    fn set_members(&self, key: String) -> Result<Vec<String>, Self::Err> {
+        let mut sets = self.sets.lock().unwrap();
+        match sets.get(&key) {
+            Some((_, Some(exp))) if *exp <= Instant::now() => {
+                sets.remove(&key);
+                Ok(vec![])
+            }
+            Some((set, _)) => Ok(set.iter().cloned().collect()),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn set_remove(&self, key: String, members: Vec<String>) -> Result<(), Self::Err> {
+        if let Some((set, _)) = self.sets.lock().unwrap().get_mut(&key) {
+            for m in members {
+                set.remove(&m);
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&self, keys: Vec<String>) -> Result<usize, Self::Err> {
+        Ok(keys.iter().filter(|k| self.take(k, true).is_some()).count())
+    }
+
+    async fn subscribe<V>(
+        &self,
+        _topics: Vec<Topic>,
+    ) -> Result<Subscription<V, Self::Err>, Self::Err>
+    where
+        V: FromStr + Send + 'static,
+        <V as FromStr>::Err: Display,
+    {
+        Err("subscribe is not supported by MemoryCache".to_string())
+    }
+
+    fn publish<V: ToString>(&self, _topic: String, _v: V) -> Result<(), Self::Err> {
+        Err("publish is not supported by MemoryCache".to_string())
+    }
+}
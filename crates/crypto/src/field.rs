@@ -0,0 +1,203 @@
+use std::{
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+};
+
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+
+use crate::{crypto_aes::Error, keyring};
+
+/// Value that is encrypted at rest.
+///
+/// Stored as a base64 string holding the JSON encoding of the inner value,
+/// sealed by the process-wide keyring with the `context` of where it is stored
+/// as associated data, so that a ciphertext copied to another field or
+/// document fails to open. `Debug` never prints the value.
+#[derive(Clone, Default, PartialEq)]
+pub struct Encrypted<T>(T);
+
+impl<T> Encrypted<T> {
+    pub fn new(value: T) -> Self {
+        Encrypted(value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Encrypted<T> {
+    fn from(value: T) -> Self {
+        Encrypted(value)
+    }
+}
+
+impl<T> Deref for Encrypted<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Encrypted<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> Debug for Encrypted<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Encrypted(..)")
+    }
+}
+
+impl<T: Serialize> Encrypted<T> {
+    /// Encrypts the value for `context`, see `context`
+    pub fn seal(&self, context: &str) -> Result<String, Error> {
+        let plaintext = match serde_json::to_vec(&self.0) {
+            Ok(p) => p,
+            Err(e) => return Err(Error::Encoding(e.to_string())),
+        };
+        let ciphertext = keyring::global()?.encrypt(&plaintext, context.as_bytes())?;
+        Ok(crate::base64::encode(ciphertext))
+    }
+}
+
+impl<T: DeserializeOwned> Encrypted<T> {
+    /// Decrypts a value sealed for `context`
+    pub fn open(encoded: &str, context: &str) -> Result<Self, Error> {
+        let ciphertext = match crate::base64::decode(encoded) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::Encoding(e.to_string())),
+        };
+        let plaintext = keyring::global()?.decrypt(&ciphertext, context.as_bytes())?;
+        match serde_json::from_slice(&plaintext) {
+            Ok(v) => Ok(Encrypted(v)),
+            Err(e) => Err(Error::Encoding(e.to_string())),
+        }
+    }
+}
+
+/// Context of `field` in the document `id` of `collection`, which is also the
+/// associated data `datastore::reencrypt::ReencryptJob` uses
+pub fn context(collection: &str, field: &str, id: &str) -> String {
+    format!("{collection}.{field}:{id}")
+}
+
+/// Deterministic HMAC-SHA256 of a value, stored next to an encrypted field so
+/// that it can still be matched exactly.
+///
+/// Use a key distinct from the encryption keys. The field name is mixed in, so
+/// equal values in different fields do not share an index.
+pub struct BlindIndex {
+    key: Vec<u8>,
+}
+
+impl BlindIndex {
+    pub fn new(key: &[u8]) -> Self {
+        BlindIndex { key: key.to_vec() }
+    }
+
+    /// Reads the key from `BLIND_INDEX_KEY`
+    pub fn from_env() -> Result<Self, Error> {
+        match env::get("BLIND_INDEX_KEY") {
+            Some(key) if !key.is_empty() => Ok(Self::new(key.as_bytes())),
+            _ => Err(Error::MissingKey),
+        }
+    }
+
+    pub fn compute(&self, field: &str, value: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key size");
+        mac.update(field.as_bytes());
+        mac.update(&[0]);
+        mac.update(value);
+        crate::base64::encode(mac.finalize().into_bytes())
+    }
+}
+
+/// Blind index of `value` for `field` with the key from `BLIND_INDEX_KEY`
+pub fn blind_index(field: &str, value: &[u8]) -> Result<String, Error> {
+    Ok(BlindIndex::from_env()?.compute(field, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::keyring::Keyring;
+
+    const KEY: &[u8] = b"Z44JJuldrAXxYpg0Z44JJuldrAXxYpg0";
+
+    #[test]
+    fn test_encrypted_round_trip() {
+        keyring::install(Keyring::new(1, KEY).unwrap());
+        let totp = Encrypted::new(HashMap::from([(
+            "secret".to_string(),
+            "JBSWY3DP".to_string(),
+        )]));
+        let stored = totp.seal(&context("accounts", "totp", "1")).unwrap();
+        assert!(!stored.contains("JBSWY3DP"));
+        let ciphertext = crate::base64::decode(&stored).unwrap();
+        assert_eq!(keyring::key_id(&ciphertext), Ok(1));
+
+        let opened: Encrypted<HashMap<String, String>> =
+            Encrypted::open(&stored, &context("accounts", "totp", "1")).unwrap();
+        assert_eq!(opened["secret"], "JBSWY3DP");
+        assert!(!format!("{opened:?}").contains("JBSWY3DP"));
+    }
+
+    #[test]
+    fn test_encrypted_rejects_tampering() {
+        keyring::install(Keyring::new(1, KEY).unwrap());
+        let stored = Encrypted::new("+15550100")
+            .seal("accounts.phone:1")
+            .unwrap();
+        let mut ciphertext = crate::base64::decode(&stored).unwrap();
+        let last = ciphertext.len() - 1;
+        ciphertext[last] ^= 1;
+        let tampered = crate::base64::encode(ciphertext);
+        assert!(Encrypted::<String>::open(&tampered, "accounts.phone:1").is_err());
+    }
+
+    #[test]
+    fn test_encrypted_rejects_other_context() {
+        keyring::install(Keyring::new(1, KEY).unwrap());
+        let stored = Encrypted::new("hash")
+            .seal(&context("users", "credentials", "1"))
+            .unwrap();
+        // moved to another user, or to another field of the same user
+        for other in [
+            context("users", "credentials", "2"),
+            context("users", "meta", "1"),
+        ] {
+            assert_eq!(
+                Encrypted::<String>::open(&stored, &other).unwrap_err(),
+                Error::Decrypt
+            );
+        }
+    }
+    #[test]
+    fn test_blind_index() {
+        let index = BlindIndex::new(b"index-key");
+        assert_eq!(
+            index.compute("phone", b"+15550100"),
+            index.compute("phone", b"+15550100")
+        );
+        assert_ne!(
+            index.compute("phone", b"+15550100"),
+            index.compute("phone", b"+15550101")
+        );
+        assert_ne!(
+            index.compute("phone", b"+15550100"),
+            index.compute("email", b"+15550100")
+        );
+        assert_ne!(
+            index.compute("phone", b"+15550100"),
+            BlindIndex::new(b"other-key").compute("phone", b"+15550100")
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use lazy_static::lazy_static;
+
+use crate::crypto_aes::{Cipher, Error, VERSION as SINGLE_KEY_VERSION};
+
+/// Version byte of ciphertexts produced by `Keyring::encrypt`
+pub const VERSION: u8 = 2;
+/// Key id used for `AES_KEY`, which also decrypts ciphertexts without a key id
+pub const LEGACY_KEY_ID: u32 = 0;
+
+/// Set of AES-256-GCM keys, one of which is used for new ciphertexts.
+///
+/// Ciphertexts are laid out as `version | key id (u32, big endian) | nonce | ciphertext+tag`,
+/// so data sealed with a retired key stays readable until it is re-encrypted.
+pub struct Keyring {
+    keys: BTreeMap<u32, Cipher>,
+    active: u32,
+}
+
+impl Keyring {
+    /// Creates a keyring whose only and active key is `key`
+    pub fn new(id: u32, key: &[u8]) -> Result<Self, Error> {
+        let mut keys = BTreeMap::new();
+        keys.insert(id, Cipher::new(key)?);
+        Ok(Keyring { keys, active: id })
+    }
+
+    /// Adds a key that can still decrypt, without making it active
+    pub fn with_key(mut self, id: u32, key: &[u8]) -> Result<Self, Error> {
+        self.keys.insert(id, Cipher::new(key)?);
+        Ok(self)
+    }
+
+    /// Makes `id` the key used for new ciphertexts
+    pub fn activate(mut self, id: u32) -> Result<Self, Error> {
+        if !self.keys.contains_key(&id) {
+            return Err(Error::UnknownKey(id));
+        }
+        self.active = id;
+        Ok(self)
+    }
+
+    /// Loads keys from `AES_KEYS` as `id:key,id:key`, plus `AES_KEY` as key `0`.
+    ///
+    /// `AES_ACTIVE_KEY` selects the active key and defaults to the highest id.
+    pub fn from_env() -> Result<Self, Error> {
+        let mut keys = BTreeMap::new();
+        if let Some(key) = env::get("AES_KEY") {
+            keys.insert(LEGACY_KEY_ID, Cipher::new(key.as_bytes())?);
+        }
+        if let Some(list) = env::get("AES_KEYS") {
+            for (id, key) in parse_key_list(&list)? {
+                keys.insert(id, Cipher::new(key.as_bytes())?);
+            }
+        }
+        let active = active_key_id(&keys, "AES_ACTIVE_KEY")?;
+        Ok(Keyring { keys, active })
+    }
+
+    pub fn active_id(&self) -> u32 {
+        self.active
+    }
+
+    /// Encrypts with the active key
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut header = [0u8; 5];
+        header[0] = VERSION;
+        header[1..].copy_from_slice(&self.active.to_be_bytes());
+        self.keys[&self.active].seal_framed(&header, plaintext, aad)
+    }
+
+    /// Decrypts with the key the ciphertext was sealed with
+    pub fn decrypt(&self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let id = key_id(ciphertext)?;
+        let cipher = match self.keys.get(&id) {
+            Some(c) => c,
+            None => return Err(Error::UnknownKey(id)),
+        };
+        match ciphertext[0] {
+            VERSION => cipher.open_framed(&ciphertext[..5], &ciphertext[5..], aad),
+            _ => cipher.open(ciphertext, aad),
+        }
+    }
+
+    /// Whether `ciphertext` was sealed with a key other than the active one
+    pub fn needs_reencrypt(&self, ciphertext: &[u8]) -> bool {
+        !matches!(key_id(ciphertext), Ok(id) if id == self.active && ciphertext[0] == VERSION)
+    }
+
+    /// Decrypts `ciphertext` and seals it again with the active key
+    pub fn reencrypt(&self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let plaintext = self.decrypt(ciphertext, aad)?;
+        self.encrypt(&plaintext, aad)
+    }
+}
+
+/// Parses a key list formatted as `id:key,id:key`
+pub(crate) fn parse_key_list(list: &str) -> Result<Vec<(u32, &str)>, Error> {
+    let mut keys = vec![];
+    for entry in list.split(',').filter(|e| !e.trim().is_empty()) {
+        let (id, key) = match entry.trim().split_once(':') {
+            Some(v) => v,
+            None => return Err(Error::InvalidKeyring(format!("missing id in {entry:?}"))),
+        };
+        let id = match id.parse::<u32>() {
+            Ok(id) => id,
+            Err(e) => return Err(Error::InvalidKeyring(format!("key id {id:?}: {e}"))),
+        };
+        keys.push((id, key));
+    }
+    Ok(keys)
+}
+
+/// Reads the active key id from `var`, defaulting to the highest id in `keys`
+pub(crate) fn active_key_id<V>(keys: &BTreeMap<u32, V>, var: &str) -> Result<u32, Error> {
+    let active = match env::get(var) {
+        Some(id) => match id.parse::<u32>() {
+            Ok(id) => id,
+            Err(e) => return Err(Error::InvalidKeyring(format!("{var}: {e}"))),
+        },
+        None => match keys.keys().next_back() {
+            Some(id) => *id,
+            None => return Err(Error::MissingKey),
+        },
+    };
+    if !keys.contains_key(&active) {
+        return Err(Error::UnknownKey(active));
+    }
+    Ok(active)
+}
+
+lazy_static! {
+    static ref GLOBAL: RwLock<Option<Arc<Keyring>>> = RwLock::new(None);
+}
+
+/// Sets the process-wide keyring, used by `field::Encrypted`
+pub fn install(keyring: Keyring) {
+    *GLOBAL.write().unwrap() = Some(Arc::new(keyring));
+}
+
+/// Returns the process-wide keyring, loading it with `Keyring::from_env` on first use
+pub fn global() -> Result<Arc<Keyring>, Error> {
+    if let Some(keyring) = GLOBAL.read().unwrap().as_ref() {
+        return Ok(keyring.clone());
+    }
+    let mut global = GLOBAL.write().unwrap();
+    if let Some(keyring) = global.as_ref() {
+        return Ok(keyring.clone());
+    }
+    let keyring = Arc::new(Keyring::from_env()?);
+    *global = Some(keyring.clone());
+    Ok(keyring)
+}
+
+/// Returns the id of the key `ciphertext` was sealed with
+pub fn key_id(ciphertext: &[u8]) -> Result<u32, Error> {
+    match ciphertext.first() {
+        Some(&VERSION) => match ciphertext.get(1..5) {
+            Some(id) => Ok(u32::from_be_bytes([id[0], id[1], id[2], id[3]])),
+            None => Err(Error::Malformed),
+        },
+        Some(&SINGLE_KEY_VERSION) => Ok(LEGACY_KEY_ID),
+        Some(v) => Err(Error::UnsupportedVersion(*v)),
+        None => Err(Error::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &[u8] = b"Z44JJuldrAXxYpg0Z44JJuldrAXxYpg0";
+    const NEW: &[u8] = b"9dQ2mXbKv7rT1sLp4aFh8nWc3eYu6GzJ";
+
+    #[test]
+    fn test_decrypt_with_retired_key() {
+        let old = Keyring::new(1, OLD).unwrap();
+        let ciphertext = old.encrypt(b"secret", b"").unwrap();
+        assert_eq!(key_id(&ciphertext), Ok(1));
+
+        let rotated = Keyring::new(2, NEW).unwrap().with_key(1, OLD).unwrap();
+        assert_eq!(rotated.decrypt(&ciphertext, b"").unwrap(), b"secret");
+        assert!(rotated.needs_reencrypt(&ciphertext));
+
+        let reencrypted = rotated.reencrypt(&ciphertext, b"").unwrap();
+        assert_eq!(key_id(&reencrypted), Ok(2));
+        assert!(!rotated.needs_reencrypt(&reencrypted));
+        assert_eq!(rotated.decrypt(&reencrypted, b"").unwrap(), b"secret");
+    }
+
+    #[test]
+    fn test_decrypt_unknown_key() {
+        let ciphertext = Keyring::new(7, OLD).unwrap().encrypt(b"x", b"").unwrap();
+        let keyring = Keyring::new(1, OLD).unwrap();
+        assert_eq!(keyring.decrypt(&ciphertext, b""), Err(Error::UnknownKey(7)));
+    }
+
+    #[test]
+    fn test_decrypt_single_key_ciphertext() {
+        let ciphertext = Cipher::new(OLD).unwrap().seal(b"legacy", b"").unwrap();
+        let keyring = Keyring::new(1, NEW)
+            .unwrap()
+            .with_key(LEGACY_KEY_ID, OLD)
+            .unwrap();
+        assert_eq!(keyring.decrypt(&ciphertext, b"").unwrap(), b"legacy");
+        assert!(keyring.needs_reencrypt(&ciphertext));
+    }
+
+    #[test]
+    fn test_key_id_is_authenticated() {
+        let keyring = Keyring::new(1, OLD).unwrap().with_key(2, OLD).unwrap();
+        let mut ciphertext = keyring.encrypt(b"secret", b"").unwrap();
+        ciphertext[4] = 2;
+        assert_eq!(keyring.decrypt(&ciphertext, b""), Err(Error::Decrypt));
+    }
+}
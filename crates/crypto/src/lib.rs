@@ -1,16 +1,118 @@
+pub mod field;
+pub mod keyring;
+pub mod token;
+
 // hash.rs
 pub mod hash {
-    use bcrypt::{hash, verify, BcryptError};
-    static COST: u32 = 10;
+    use argon2::{
+        password_hash::{rand_core::OsRng, SaltString},
+        Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
+    };
+    use std::fmt::Display;
+
+    /// Longest accepted password, Argon2 has no limit of its own
+    pub const MAX_PASSWORD_BYTES: usize = 1024;
+    /// bcrypt ignores everything past the first 72 bytes of its input
+    pub const BCRYPT_MAX_BYTES: usize = 72;
+
+    // OWASP recommended minimums for Argon2id
+    const DEFAULT_MEMORY_KIB: u32 = 19 * 1024;
+    const DEFAULT_ITERATIONS: u32 = 2;
+    const DEFAULT_PARALLELISM: u32 = 1;
+
+    #[derive(Debug, PartialEq)]
+    pub enum Error {
+        /// The password is longer than `MAX_PASSWORD_BYTES`
+        TooLong,
+        /// Invalid parameters or hashing failure
+        Hash(String),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::TooLong => write!(f, "password exceeds {MAX_PASSWORD_BYTES} bytes"),
+                Self::Hash(e) => write!(f, "hash error: {e}"),
+            }
+        }
+    }
+
+    /// Argon2id parameters from `HASH_MEMORY_KIB`, `HASH_ITERATIONS` and `HASH_PARALLELISM`
+    fn params() -> Result<Params, Error> {
+        let read = |key: &str, default: u32| -> Result<u32, Error> {
+            match env::get(key) {
+                Some(v) => v.parse().map_err(|e| Error::Hash(format!("{key}: {e}"))),
+                None => Ok(default),
+            }
+        };
+        Params::new(
+            read("HASH_MEMORY_KIB", DEFAULT_MEMORY_KIB)?,
+            read("HASH_ITERATIONS", DEFAULT_ITERATIONS)?,
+            read("HASH_PARALLELISM", DEFAULT_PARALLELISM)?,
+            None,
+        )
+        .map_err(|e| Error::Hash(e.to_string()))
+    }
 
-    /// Generate bcrypt hash from string
-    pub fn make(s: &str) -> Result<String, BcryptError> {
-        hash(s, COST)
+    fn is_bcrypt(h: &str) -> bool {
+        h.starts_with("$2a$") || h.starts_with("$2b$") || h.starts_with("$2y$")
     }
 
-    /// Verify bcrypt hash from string
+    /// Generate an Argon2id hash in PHC string format
+    pub fn make(s: &str) -> Result<String, Error> {
+        if s.len() > MAX_PASSWORD_BYTES {
+            return Err(Error::TooLong);
+        }
+        let salt = SaltString::generate(&mut OsRng);
+        match Argon2::new(Algorithm::Argon2id, Version::V0x13, params()?)
+            .hash_password(s.as_bytes(), &salt)
+        {
+            Ok(h) => Ok(h.to_string()),
+            Err(e) => Err(Error::Hash(e.to_string())),
+        }
+    }
+
+    /// Verify a password against an Argon2 PHC string or a legacy bcrypt hash.
+    ///
+    /// bcrypt hashes only cover the first 72 bytes, so longer inputs are compared
+    /// the way they were hashed and `needs_rehash` reports them for an upgrade.
     pub fn check(s: &str, h: &str) -> bool {
-        verify(s, h).unwrap_or(false)
+        if s.len() > MAX_PASSWORD_BYTES {
+            return false;
+        }
+        if is_bcrypt(h) {
+            let input = &s.as_bytes()[..s.len().min(BCRYPT_MAX_BYTES)];
+            return bcrypt::verify(input, h).unwrap_or(false);
+        }
+        match PasswordHash::new(h) {
+            Ok(parsed) => Argon2::default()
+                .verify_password(s.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether `h` should be replaced by a fresh `make` after a successful `check`,
+    /// because it is bcrypt or uses weaker parameters than the current ones
+    pub fn needs_rehash(h: &str) -> bool {
+        if is_bcrypt(h) {
+            return true;
+        }
+        let parsed = match PasswordHash::new(h) {
+            Ok(p) => p,
+            Err(_) => return true,
+        };
+        if parsed.algorithm != Algorithm::Argon2id.ident() {
+            return true;
+        }
+        match (Params::try_from(&parsed), params()) {
+            (Ok(stored), Ok(current)) => {
+                stored.m_cost() < current.m_cost()
+                    || stored.t_cost() < current.t_cost()
+                    || stored.p_cost() != current.p_cost()
+            }
+            _ => true,
+        }
     }
 
     #[cfg(test)]
@@ -21,8 +123,50 @@ pub mod hash {
         fn test_hash_and_check() {
             let password = "testpassword";
             let hashed = make(password).unwrap();
+            assert!(hashed.starts_with("$argon2id$"));
             assert!(check(password, &hashed));
             assert!(!check("wrongpassword", &hashed));
+            assert!(!needs_rehash(&hashed));
+        }
+
+        #[test]
+        fn test_check_legacy_bcrypt() {
+            let hashed = bcrypt::hash("testpassword", 4).unwrap();
+            assert!(check("testpassword", &hashed));
+            assert!(!check("wrongpassword", &hashed));
+            assert!(needs_rehash(&hashed));
+        }
+
+        #[test]
+        fn test_bcrypt_long_password() {
+            let long = "a".repeat(100);
+            let hashed = bcrypt::hash(&long, 4).unwrap();
+            assert!(check(&long, &hashed));
+
+            // Argon2 hashes the whole input
+            let hashed = make(&long).unwrap();
+            assert!(check(&long, &hashed));
+            assert!(!check(&"a".repeat(BCRYPT_MAX_BYTES), &hashed));
+        }
+
+        #[test]
+        fn test_password_too_long() {
+            let long = "a".repeat(MAX_PASSWORD_BYTES + 1);
+            assert_eq!(make(&long), Err(Error::TooLong));
+        }
+
+        #[test]
+        fn test_needs_rehash_weaker_params() {
+            let weak = Argon2::new(
+                Algorithm::Argon2id,
+                Version::V0x13,
+                Params::new(1024, 1, 1, None).unwrap(),
+            )
+            .hash_password(b"testpassword", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+            assert!(check("testpassword", &weak));
+            assert!(needs_rehash(&weak));
         }
     }
 }
@@ -31,11 +175,159 @@ pub mod hash {
 pub mod crypto_aes {
     use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
     use aes::Aes256;
-    use std::env;
+    use aes_gcm::{
+        aead::{Aead, OsRng, Payload},
+        AeadCore, Aes256Gcm, KeyInit, Nonce,
+    };
+    use std::fmt::Display;
 
     type Aes256CbcEnc = cbc::Encryptor<Aes256>;
     type Aes256CbcDec = cbc::Decryptor<Aes256>;
 
+    /// Version byte of ciphertexts produced by `seal`
+    pub const VERSION: u8 = 1;
+    const KEY_LEN: usize = 32;
+    const NONCE_LEN: usize = 12;
+
+    #[derive(Debug, PartialEq)]
+    pub enum Error {
+        /// `AES_KEY` is not set
+        MissingKey,
+        /// The key is not 32 bytes long
+        InvalidKeyLength(usize),
+        /// The ciphertext is too short to hold a header and tag
+        Malformed,
+        UnsupportedVersion(u8),
+        /// Wrong key, wrong associated data or tampered ciphertext
+        Decrypt,
+        /// The ciphertext was sealed with a key id missing from the keyring
+        UnknownKey(u32),
+        /// The keyring configuration could not be parsed
+        InvalidKeyring(String),
+        /// An encrypted field could not be encoded or decoded
+        Encoding(String),
+    }
+
+    impl Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::MissingKey => f.write_str("AES_KEY is not set"),
+                Self::InvalidKeyLength(n) => {
+                    write!(f, "AES key must be {KEY_LEN} bytes, got {n}")
+                }
+                Self::Malformed => f.write_str("malformed ciphertext"),
+                Self::UnsupportedVersion(v) => write!(f, "unsupported ciphertext version {v}"),
+                Self::Decrypt => f.write_str("decryption failed"),
+                Self::UnknownKey(id) => write!(f, "unknown key id {id}"),
+                Self::InvalidKeyring(e) => write!(f, "invalid keyring: {e}"),
+                Self::Encoding(e) => write!(f, "encoding error: {e}"),
+            }
+        }
+    }
+
+    /// AES-256-GCM with a random nonce per message.
+    ///
+    /// Ciphertexts are laid out as `version | nonce | ciphertext+tag`, and the
+    /// version byte is authenticated together with the associated data.
+    pub struct Cipher {
+        inner: Aes256Gcm,
+    }
+
+    impl Cipher {
+        pub fn new(key: &[u8]) -> Result<Self, Error> {
+            if key.len() != KEY_LEN {
+                return Err(Error::InvalidKeyLength(key.len()));
+            }
+            match Aes256Gcm::new_from_slice(key) {
+                Ok(inner) => Ok(Cipher { inner }),
+                Err(_) => Err(Error::InvalidKeyLength(key.len())),
+            }
+        }
+
+        /// Reads the key from `AES_KEY`
+        pub fn from_env() -> Result<Self, Error> {
+            match env::get("AES_KEY") {
+                Some(key) => Self::new(key.as_bytes()),
+                None => Err(Error::MissingKey),
+            }
+        }
+
+        /// Encrypts `plaintext`, binding it to `aad` which must be passed again to `open`
+        pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+            self.seal_framed(&[VERSION], plaintext, aad)
+        }
+
+        pub fn open(&self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+            match ciphertext.split_first() {
+                Some((&VERSION, rest)) => self.open_framed(&[VERSION], rest, aad),
+                Some((v, _)) => Err(Error::UnsupportedVersion(*v)),
+                None => Err(Error::Malformed),
+            }
+        }
+
+        /// Produces `header | nonce | ciphertext+tag`, authenticating `header` with `aad`
+        pub(crate) fn seal_framed(
+            &self,
+            header: &[u8],
+            plaintext: &[u8],
+            aad: &[u8],
+        ) -> Result<Vec<u8>, Error> {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let aad = [header, aad].concat();
+            let ciphertext = match self.inner.encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            ) {
+                Ok(c) => c,
+                Err(_) => return Err(Error::Decrypt),
+            };
+
+            let mut out = Vec::with_capacity(header.len() + NONCE_LEN + ciphertext.len());
+            out.extend_from_slice(header);
+            out.extend_from_slice(&nonce);
+            out.extend_from_slice(&ciphertext);
+            Ok(out)
+        }
+
+        /// Decrypts `nonce | ciphertext+tag` that was sealed with `header`
+        pub(crate) fn open_framed(
+            &self,
+            header: &[u8],
+            rest: &[u8],
+            aad: &[u8],
+        ) -> Result<Vec<u8>, Error> {
+            if rest.len() < NONCE_LEN {
+                return Err(Error::Malformed);
+            }
+            let (nonce, body) = rest.split_at(NONCE_LEN);
+            let aad = [header, aad].concat();
+            self.inner
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: body,
+                        aad: &aad,
+                    },
+                )
+                .map_err(|_| Error::Decrypt)
+        }
+    }
+
+    /// Encrypts with the key from `AES_KEY`, see `Cipher::seal`
+    pub fn seal(plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        Cipher::from_env()?.seal(plaintext, aad)
+    }
+
+    /// Decrypts with the key from `AES_KEY`, see `Cipher::open`
+    pub fn open(ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        Cipher::from_env()?.open(ciphertext, aad)
+    }
+
+    /// Legacy AES-256-CBC with the fixed `AES_IV`, kept to read existing data.
+    /// New data should use `seal`/`open`.
     struct Aes {
         encoder: Aes256CbcEnc,
         decoder: Aes256CbcDec,
@@ -43,13 +335,13 @@ pub mod crypto_aes {
 
     impl Aes {
         pub fn new() -> Self {
-            let secret: [u8; 32] = env::var("AES_KEY")
-                .unwrap_or_else(|_| panic!("Failed to retrieve AES_KEY"))
+            let secret: [u8; 32] = env::get("AES_KEY")
+                .unwrap_or_else(|| panic!("Failed to retrieve AES_KEY"))
                 .as_bytes()
                 .try_into()
                 .unwrap();
-            let iv: [u8; 16] = env::var("AES_IV")
-                .unwrap_or_else(|_| panic!("Failed to retrieve AES_IV"))
+            let iv: [u8; 16] = env::get("AES_IV")
+                .unwrap_or_else(|| panic!("Failed to retrieve AES_IV"))
                 .as_bytes()
                 .try_into()
                 .unwrap();
@@ -83,23 +375,79 @@ pub mod crypto_aes {
     mod tests {
         use super::*;
 
+        const LEGACY_ENV: [(&str, &str); 2] = [
+            ("AES_KEY", "Z44JJuldrAXxYpg0Z44JJuldrAXxYpg0"),
+            ("AES_IV", "Z44JJuldrAXxYpg0"),
+        ];
+
         #[test]
         fn test_encode_and_decode() {
-            std::env::set_var("AES_KEY", "Z44JJuldrAXxYpg0Z44JJuldrAXxYpg0");
-            std::env::set_var("AES_IV", "Z44JJuldrAXxYpg0");
-            let plaintext = b"teststring123";
-            let encrypted = encode(plaintext);
-            let decrypted = decode(&encrypted);
-            assert_eq!(plaintext.to_vec(), decrypted.unwrap());
+            env::with_overrides(LEGACY_ENV, || {
+                let plaintext = b"teststring123";
+                let encrypted = encode(plaintext);
+                let decrypted = decode(&encrypted);
+                assert_eq!(plaintext.to_vec(), decrypted.unwrap());
+            });
         }
 
         #[test]
         fn test_invalid_decryption() {
-            std::env::set_var("AES_KEY", "Z44JJuldrAXxYpg0Z44JJuldrAXxYpg0");
-            std::env::set_var("AES_IV", "Z44JJuldrAXxYpg0");
-            let invalid_data = b"invalid_data";
-            let decrypted = decode(invalid_data);
-            assert!(decrypted.is_err()); // Ensure it's empty or invalid
+            env::with_overrides(LEGACY_ENV, || {
+                let invalid_data = b"invalid_data";
+                let decrypted = decode(invalid_data);
+                assert!(decrypted.is_err()); // Ensure it's empty or invalid
+            });
+        }
+
+        #[test]
+        fn test_seal_with_env_key() {
+            let sealed = env::with_overrides([("AES_KEY", KEY_STR)], || seal(b"x", b"")).unwrap();
+            assert_eq!(Cipher::new(KEY).unwrap().open(&sealed, b"").unwrap(), b"x");
+        }
+
+        const KEY_STR: &str = "Z44JJuldrAXxYpg0Z44JJuldrAXxYpg0";
+        const KEY: &[u8] = KEY_STR.as_bytes();
+
+        #[test]
+        fn test_seal_and_open() {
+            let cipher = Cipher::new(KEY).unwrap();
+            let sealed = cipher.seal(b"teststring123", b"user:1").unwrap();
+            assert_eq!(sealed[0], VERSION);
+            assert_eq!(cipher.open(&sealed, b"user:1").unwrap(), b"teststring123");
+        }
+
+        #[test]
+        fn test_seal_uses_random_nonce() {
+            let cipher = Cipher::new(KEY).unwrap();
+            let a = cipher.seal(b"same", b"").unwrap();
+            let b = cipher.seal(b"same", b"").unwrap();
+            assert_ne!(a, b);
+        }
+
+        #[test]
+        fn test_open_rejects_tampering() {
+            let cipher = Cipher::new(KEY).unwrap();
+            let mut sealed = cipher.seal(b"teststring123", b"user:1").unwrap();
+            assert_eq!(cipher.open(&sealed, b"user:2"), Err(Error::Decrypt));
+
+            let last = sealed.len() - 1;
+            sealed[last] ^= 1;
+            assert_eq!(cipher.open(&sealed, b"user:1"), Err(Error::Decrypt));
+
+            sealed[0] = 9;
+            assert_eq!(
+                cipher.open(&sealed, b"user:1"),
+                Err(Error::UnsupportedVersion(9))
+            );
+            assert_eq!(cipher.open(&[VERSION, 1, 2], b""), Err(Error::Malformed));
+        }
+
+        #[test]
+        fn test_invalid_key_length() {
+            assert_eq!(
+                Cipher::new(b"short").err(),
+                Some(Error::InvalidKeyLength(5))
+            );
         }
     }
 }
@@ -0,0 +1,349 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    crypto_aes::Error,
+    keyring::{active_key_id, parse_key_list},
+};
+
+/// Shortest accepted signing key
+pub const MIN_KEY_LEN: usize = 32;
+
+// Domain separation between signed tokens and signed URLs
+const TOKEN_DOMAIN: &[u8] = b"token\0";
+const URL_DOMAIN: &[u8] = b"url\0";
+
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    /// The token or URL is not in the expected format
+    Malformed,
+    /// The token was signed with a key that is not in the signer
+    UnknownKey(u32),
+    /// The signature does not match
+    BadSignature,
+    /// The token was issued for another audience
+    WrongAudience,
+    Expired,
+    /// The payload could not be encoded or decoded
+    Payload(String),
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => f.write_str("malformed token"),
+            Self::UnknownKey(id) => write!(f, "unknown signing key {id}"),
+            Self::BadSignature => f.write_str("bad signature"),
+            Self::WrongAudience => f.write_str("wrong audience"),
+            Self::Expired => f.write_str("token expired"),
+            Self::Payload(e) => write!(f, "payload error: {e}"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims<A, T> {
+    aud: A,
+    exp: u64,
+    data: T,
+}
+
+/// HMAC-SHA256 signer for stateless tokens and URLs.
+///
+/// Tokens are laid out as `key id.claims.signature`, where claims are the
+/// base64url JSON of the audience, the expiry in unix seconds and the payload.
+/// Like `Keyring`, retired keys can still verify until they are removed.
+pub struct Signer {
+    keys: BTreeMap<u32, Vec<u8>>,
+    active: u32,
+}
+
+impl Signer {
+    pub fn new(id: u32, key: &[u8]) -> Result<Self, Error> {
+        let mut keys = BTreeMap::new();
+        keys.insert(id, checked_key(key)?);
+        Ok(Signer { keys, active: id })
+    }
+
+    /// Adds a key that can still verify, without making it active
+    pub fn with_key(mut self, id: u32, key: &[u8]) -> Result<Self, Error> {
+        self.keys.insert(id, checked_key(key)?);
+        Ok(self)
+    }
+
+    /// Loads keys from `TOKEN_KEYS` as `id:key,id:key`.
+    ///
+    /// `TOKEN_ACTIVE_KEY` selects the signing key and defaults to the highest id.
+    pub fn from_env() -> Result<Self, Error> {
+        let mut keys = BTreeMap::new();
+        if let Some(list) = env::get("TOKEN_KEYS") {
+            for (id, key) in parse_key_list(&list)? {
+                keys.insert(id, checked_key(key.as_bytes())?);
+            }
+        }
+        let active = active_key_id(&keys, "TOKEN_ACTIVE_KEY")?;
+        Ok(Signer { keys, active })
+    }
+
+    /// Signs `data` for `audience`, valid for `ttl`
+    pub fn sign<T: Serialize>(
+        &self,
+        audience: &str,
+        data: &T,
+        ttl: Duration,
+    ) -> Result<String, TokenError> {
+        self.sign_at(audience, data, now() + ttl.as_secs())
+    }
+
+    fn sign_at<T: Serialize>(
+        &self,
+        audience: &str,
+        data: &T,
+        exp: u64,
+    ) -> Result<String, TokenError> {
+        let claims = Claims {
+            aud: audience,
+            exp,
+            data,
+        };
+        let claims = match serde_json::to_vec(&claims) {
+            Ok(c) => BASE64_URL_SAFE_NO_PAD.encode(c),
+            Err(e) => return Err(TokenError::Payload(e.to_string())),
+        };
+        let signed = format!("{}.{claims}", self.active);
+        let signature = self.mac(self.active, TOKEN_DOMAIN, &signed)?.finalize();
+        Ok(format!(
+            "{signed}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(signature.into_bytes())
+        ))
+    }
+
+    /// Verifies the signature, audience and expiry of `token`, returns its payload
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        audience: &str,
+        token: &str,
+    ) -> Result<T, TokenError> {
+        let (signed, signature) = match token.rsplit_once('.') {
+            Some(v) => v,
+            None => return Err(TokenError::Malformed),
+        };
+        let (kid, claims) = match signed.split_once('.') {
+            Some(v) => v,
+            None => return Err(TokenError::Malformed),
+        };
+        let kid = match kid.parse::<u32>() {
+            Ok(kid) => kid,
+            Err(_) => return Err(TokenError::Malformed),
+        };
+        self.check(kid, TOKEN_DOMAIN, signed, signature)?;
+
+        let claims: Claims<String, T> = match BASE64_URL_SAFE_NO_PAD.decode(claims) {
+            Ok(c) => match serde_json::from_slice(&c) {
+                Ok(c) => c,
+                Err(e) => return Err(TokenError::Payload(e.to_string())),
+            },
+            Err(_) => return Err(TokenError::Malformed),
+        };
+        if claims.aud != audience {
+            return Err(TokenError::WrongAudience);
+        }
+        if claims.exp <= now() {
+            return Err(TokenError::Expired);
+        }
+        Ok(claims.data)
+    }
+
+    /// Appends `expires`, `kid` and `signature` query parameters to `url`.
+    ///
+    /// The signature covers the whole URL, so the path and every other
+    /// parameter are tamper-proof as well.
+    pub fn sign_url(&self, url: &str, ttl: Duration) -> Result<String, TokenError> {
+        self.sign_url_at(url, now() + ttl.as_secs())
+    }
+
+    fn sign_url_at(&self, url: &str, exp: u64) -> Result<String, TokenError> {
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let signed = format!("{url}{separator}expires={exp}&kid={}", self.active);
+        let signature = self.mac(self.active, URL_DOMAIN, &signed)?.finalize();
+        Ok(format!(
+            "{signed}&signature={}",
+            BASE64_URL_SAFE_NO_PAD.encode(signature.into_bytes())
+        ))
+    }
+
+    /// Verifies a URL produced by `sign_url`, `signature` must be the last parameter
+    pub fn verify_url(&self, url: &str) -> Result<(), TokenError> {
+        let (signed, signature) = match url.rsplit_once("&signature=") {
+            Some(v) => v,
+            None => return Err(TokenError::Malformed),
+        };
+        let query = match signed.split_once('?') {
+            Some((_, query)) => query,
+            None => return Err(TokenError::Malformed),
+        };
+        let param = |name: &str| -> Result<u64, TokenError> {
+            query
+                .split('&')
+                .filter_map(|p| p.split_once('='))
+                .rfind(|(k, _)| *k == name)
+                .and_then(|(_, v)| v.parse().ok())
+                .ok_or(TokenError::Malformed)
+        };
+        let kid = match u32::try_from(param("kid")?) {
+            Ok(kid) => kid,
+            Err(_) => return Err(TokenError::Malformed),
+        };
+        self.check(kid, URL_DOMAIN, signed, signature)?;
+        if param("expires")? <= now() {
+            return Err(TokenError::Expired);
+        }
+        Ok(())
+    }
+
+    fn mac(&self, kid: u32, domain: &[u8], signed: &str) -> Result<Hmac<Sha256>, TokenError> {
+        let key = match self.keys.get(&kid) {
+            Some(k) => k,
+            None => return Err(TokenError::UnknownKey(kid)),
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key size");
+        mac.update(domain);
+        mac.update(signed.as_bytes());
+        Ok(mac)
+    }
+
+    /// Compares the signature in constant time
+    fn check(
+        &self,
+        kid: u32,
+        domain: &[u8],
+        signed: &str,
+        signature: &str,
+    ) -> Result<(), TokenError> {
+        let signature = match BASE64_URL_SAFE_NO_PAD.decode(signature) {
+            Ok(s) => s,
+            Err(_) => return Err(TokenError::Malformed),
+        };
+        match self.mac(kid, domain, signed)?.verify_slice(&signature) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(TokenError::BadSignature),
+        }
+    }
+}
+
+fn checked_key(key: &[u8]) -> Result<Vec<u8>, Error> {
+    if key.len() < MIN_KEY_LEN {
+        return Err(Error::InvalidKeyLength(key.len()));
+    }
+    Ok(key.to_vec())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &[u8] = b"Z44JJuldrAXxYpg0Z44JJuldrAXxYpg0";
+    const NEW: &[u8] = b"9dQ2mXbKv7rT1sLp4aFh8nWc3eYu6GzJ";
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Cursor {
+        after: String,
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = Signer::new(1, OLD).unwrap();
+        let cursor = Cursor {
+            after: "66f0c2".into(),
+        };
+        let token = signer.sign("cursor", &cursor, HOUR).unwrap();
+        assert_eq!(signer.verify::<Cursor>("cursor", &token), Ok(cursor));
+        assert_eq!(
+            signer.verify::<Cursor>("email", &token),
+            Err(TokenError::WrongAudience)
+        );
+    }
+
+    #[test]
+    fn test_verify_rejects_tampering() {
+        let signer = Signer::new(1, OLD).unwrap();
+        let token = signer.sign("email", &"acme@gmail.com", HOUR).unwrap();
+        let (kid, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged = BASE64_URL_SAFE_NO_PAD
+            .encode(r#"{"aud":"email","exp":99999999999,"data":"evil@gmail.com"}"#);
+        assert_eq!(
+            signer.verify::<String>("email", &format!("{kid}.{forged}.{signature}")),
+            Err(TokenError::BadSignature)
+        );
+        assert_eq!(
+            signer.verify::<String>("email", "garbage"),
+            Err(TokenError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_verify_expired() {
+        let signer = Signer::new(1, OLD).unwrap();
+        let token = signer.sign_at("email", &1, now() - 1).unwrap();
+        assert_eq!(
+            signer.verify::<u32>("email", &token),
+            Err(TokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_verify_with_retired_key() {
+        let token = Signer::new(1, OLD)
+            .unwrap()
+            .sign("email", &1, HOUR)
+            .unwrap();
+        let rotated = Signer::new(2, NEW).unwrap().with_key(1, OLD).unwrap();
+        assert_eq!(rotated.verify::<u32>("email", &token), Ok(1));
+        assert_eq!(
+            Signer::new(2, NEW).unwrap().verify::<u32>("email", &token),
+            Err(TokenError::UnknownKey(1))
+        );
+    }
+
+    #[test]
+    fn test_signed_url() {
+        let signer = Signer::new(1, OLD).unwrap();
+        let url = signer
+            .sign_url("https://cdn.snapshop.dev/files/42?size=large", HOUR)
+            .unwrap();
+        assert!(signer.verify_url(&url).is_ok());
+
+        let tampered = url.replace("size=large", "size=original");
+        assert_eq!(signer.verify_url(&tampered), Err(TokenError::BadSignature));
+
+        let expired = signer
+            .sign_url_at("https://cdn.snapshop.dev/files/42", now() - 1)
+            .unwrap();
+        assert_eq!(signer.verify_url(&expired), Err(TokenError::Expired));
+    }
+
+    #[test]
+    fn test_short_key() {
+        assert_eq!(
+            Signer::new(1, b"short").err(),
+            Some(Error::InvalidKeyLength(5))
+        );
+    }
+}
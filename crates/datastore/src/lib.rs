@@ -1,10 +1,15 @@
+use std::time::{Duration, Instant};
+
 use mongodb::{
-    bson::{oid::ObjectId, Document},
-    error::Error,
-    Client,
+    bson::{doc, oid::ObjectId, Document},
+    error::{Error, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+    Client, ClientSession,
 };
 use serde::{Deserialize, Serialize};
 
+pub mod outbox;
+pub mod reencrypt;
+
 pub trait Model: Serialize + for<'a> Deserialize<'a> {
     fn find_one(
         client: &Client,
@@ -25,6 +30,24 @@ pub trait Model: Serialize + for<'a> Deserialize<'a> {
     ) -> impl std::future::Future<Output = Result<ObjectId, Error>> + Send
     where
         Self: Sized;
+
+    /// Like `insert_one`, within the transaction of `session`
+    fn insert_one_with_session(
+        client: &Client,
+        session: &mut ClientSession,
+        data: &mut Self,
+    ) -> impl std::future::Future<Output = Result<ObjectId, Error>> + Send
+    where
+        Self: Sized;
+
+    /// Applies `update` to the first document matching `query`, returns the modified count
+    fn update_one(
+        client: &Client,
+        query: Document,
+        update: Document,
+    ) -> impl std::future::Future<Output = Result<u64, Error>> + Send
+    where
+        Self: Sized;
 }
 
 pub trait ModelExt<'a> {
@@ -73,6 +96,69 @@ impl Datastore {
     pub async fn insert_one<M: Model>(&self, data: &mut M) -> Result<ObjectId, Error> {
         M::insert_one(&self.client, data).await
     }
+
+    pub async fn insert_one_with_session<M: Model>(
+        &self,
+        session: &mut ClientSession,
+        data: &mut M,
+    ) -> Result<ObjectId, Error> {
+        M::insert_one_with_session(&self.client, session, data).await
+    }
+
+    pub async fn update_one<M: Model>(
+        &self,
+        query: Document,
+        update: Document,
+    ) -> Result<u64, Error> {
+        M::update_one(&self.client, query, update).await
+    }
+}
+
+// transactions, which need a replica set
+impl Datastore {
+    /// Session with a started transaction, see `commit`.
+    ///
+    /// The transaction is aborted if the session is dropped before committing.
+    pub async fn start_transaction(&self) -> Result<ClientSession, Error> {
+        let mut session = self.client.start_session().await?;
+        session.start_transaction().await?;
+        Ok(session)
+    }
+
+    /// Whether the server is a replica set member or a mongos, the
+    /// deployments that support transactions
+    pub async fn supports_transactions(&self) -> Result<bool, Error> {
+        let hello = self
+            .client
+            .database("admin")
+            .run_command(doc! {"hello": 1})
+            .await?;
+        Ok(hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid"))
+    }
+}
+
+/// How long `commit` retries a commit whose outcome is unknown, as the
+/// driver specification advises
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Pause between two attempts of `commit`
+const COMMIT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Commits the transaction of `session`, retrying while its outcome is
+/// unknown for at most `COMMIT_TIMEOUT`
+pub async fn commit(session: &mut ClientSession) -> Result<(), Error> {
+    let deadline = Instant::now() + COMMIT_TIMEOUT;
+    loop {
+        match session.commit_transaction().await {
+            Err(err)
+                if err.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)
+                    && Instant::now() < deadline =>
+            {
+                tokio::time::sleep(COMMIT_RETRY_DELAY).await
+            }
+            result => return result,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -118,6 +204,28 @@ mod tests {
         {
             todo!()
         }
+
+        async fn insert_one_with_session(
+            _client: &Client,
+            _session: &mut mongodb::ClientSession,
+            _data: &mut Self,
+        ) -> Result<ObjectId, Error>
+        where
+            Self: Sized,
+        {
+            todo!()
+        }
+
+        async fn update_one(
+            _client: &Client,
+            _query: mongodb::bson::Document,
+            _update: mongodb::bson::Document,
+        ) -> Result<u64, Error>
+        where
+            Self: Sized,
+        {
+            todo!()
+        }
     }
 
     struct UserExt<'a> {
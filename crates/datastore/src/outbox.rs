@@ -0,0 +1,375 @@
+use std::{collections::HashMap, future::Future, time::Duration};
+
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    error::Error,
+    options::{IndexOptions, ReturnDocument},
+    ClientSession, Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+
+/// Longest wait before a failed message is published again
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    /// Work for a single consumer
+    Job,
+    /// Something that happened, for whoever listens
+    Event,
+}
+
+/// A job or event written with a business change, published by a `Relay`.
+///
+/// Messages are published at least once: consumers deduplicate them on `id`,
+/// which stays the same across publications.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutboxMessage {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub kind: MessageKind,
+    /// Job name or event topic
+    pub name: String,
+    /// JSON encoded body
+    pub payload: String,
+    /// Metadata for the sink, such as the queue of a job
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub created_at: DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published_at: Option<DateTime>,
+    /// Publications tried so far
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Until when a relay holds the message, or waits before trying again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locked_until: Option<DateTime>,
+}
+
+impl OutboxMessage {
+    pub fn job(name: &str, payload: String) -> Self {
+        Self::new(MessageKind::Job, name, payload)
+    }
+
+    pub fn event(name: &str, payload: String) -> Self {
+        Self::new(MessageKind::Event, name, payload)
+    }
+
+    fn new(kind: MessageKind, name: &str, payload: String) -> Self {
+        OutboxMessage {
+            id: ObjectId::new(),
+            kind,
+            name: name.to_string(),
+            payload,
+            headers: HashMap::new(),
+            created_at: DateTime::now(),
+            published_at: None,
+            attempts: 0,
+            last_error: None,
+            locked_until: None,
+        }
+    }
+
+    pub fn header(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.headers.insert(key.to_string(), value.into());
+        self
+    }
+}
+
+/// Where a `Relay` publishes messages, such as a job queue
+#[async_trait]
+pub trait OutboxSink: Send + Sync {
+    /// Publishes `message`, succeeding when it was already published before
+    async fn publish(&self, message: &OutboxMessage) -> Result<(), String>;
+}
+
+/// Collection of messages waiting to be published.
+///
+/// Messages are written in the transaction of the change they come from, so
+/// they exist if and only if the change was committed:
+///
+/// ```ignore
+/// let mut session = store.start_transaction().await?;
+/// store.insert_one_with_session(&mut session, &mut user).await?;
+/// outbox.write(&mut session, &OutboxMessage::job("send_welcome_email", payload)).await?;
+/// datastore::commit(&mut session).await?;
+/// ```
+#[derive(Clone)]
+pub struct Outbox {
+    messages: Collection<OutboxMessage>,
+    retention: Duration,
+}
+
+impl Outbox {
+    pub fn new(client: &mongodb::Client, database: &str, collection: &str) -> Self {
+        Outbox {
+            messages: client.database(database).collection(collection),
+            retention: Duration::from_secs(7 * 24 * 3600),
+        }
+    }
+
+    /// How long published messages are kept, see `ensure_indexes`
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Creates the indexes used by relays and the expiry of published messages
+    pub async fn ensure_indexes(&self) -> Result<(), Error> {
+        let pending = IndexModel::builder()
+            .keys(doc! {"published_at": 1, "locked_until": 1, "_id": 1})
+            .build();
+        let expiry = IndexModel::builder()
+            .keys(doc! {"published_at": 1})
+            .options(
+                IndexOptions::builder()
+                    .name("published_at_ttl".to_string())
+                    .expire_after(self.retention)
+                    .build(),
+            )
+            .build();
+        self.messages.create_indexes([pending, expiry]).await?;
+        Ok(())
+    }
+
+    /// Writes `message` within the transaction of `session`
+    pub async fn write(
+        &self,
+        session: &mut ClientSession,
+        message: &OutboxMessage,
+    ) -> Result<(), Error> {
+        self.messages.insert_one(message).session(session).await?;
+        Ok(())
+    }
+
+    /// Holds the oldest message ready to publish for `lease`
+    async fn claim(&self, lease: Duration) -> Result<Option<OutboxMessage>, Error> {
+        let now = DateTime::now();
+        let until = DateTime::from_millis(now.timestamp_millis() + lease.as_millis() as i64);
+        self.messages
+            .find_one_and_update(
+                doc! {
+                    "published_at": null,
+                    "$or": [{"locked_until": null}, {"locked_until": {"$lte": now}}],
+                },
+                doc! {"$set": {"locked_until": until}, "$inc": {"attempts": 1}},
+            )
+            .sort(doc! {"_id": 1})
+            .return_document(ReturnDocument::After)
+            .await
+    }
+
+    async fn published(&self, message: &OutboxMessage) -> Result<(), Error> {
+        self.messages
+            .update_one(
+                doc! {"_id": message.id},
+                doc! {
+                    "$set": {"published_at": DateTime::now()},
+                    "$unset": {"locked_until": "", "last_error": ""},
+                },
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn failed(&self, message: &OutboxMessage, error: &str) -> Result<(), Error> {
+        let retry_at =
+            DateTime::now().timestamp_millis() + backoff(message.attempts).as_millis() as i64;
+        self.messages
+            .update_one(
+                doc! {"_id": message.id},
+                doc! {"$set": {
+                    "last_error": error,
+                    "locked_until": DateTime::from_millis(retry_at),
+                }},
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Messages not published yet, oldest first
+    pub async fn pending(&self, limit: i64) -> Result<Vec<OutboxMessage>, Error> {
+        let mut cursor = self
+            .messages
+            .find(doc! {"published_at": null})
+            .sort(doc! {"_id": 1})
+            .limit(limit)
+            .await?;
+        let mut messages = vec![];
+        while cursor.advance().await? {
+            messages.push(cursor.deserialize_current()?);
+        }
+        Ok(messages)
+    }
+}
+
+/// Wait before publishing again a message that failed `attempts` times
+fn backoff(attempts: u32) -> Duration {
+    Duration::from_secs(1 << attempts.min(16)).min(MAX_BACKOFF)
+}
+
+/// Publishes the messages of an `Outbox` to a sink, at least once.
+///
+/// A message is held while it is published, so several relays can run
+/// side by side. A relay dying between publishing a message and marking it
+/// published leaves it to be published again once the lease expires.
+pub struct Relay<S> {
+    outbox: Outbox,
+    sink: S,
+    batch_size: usize,
+    poll_interval: Duration,
+    lease: Duration,
+}
+
+impl<S: OutboxSink> Relay<S> {
+    pub fn new(outbox: Outbox, sink: S) -> Self {
+        Relay {
+            outbox,
+            sink,
+            batch_size: 100,
+            poll_interval: Duration::from_secs(1),
+            lease: Duration::from_secs(30),
+        }
+    }
+
+    /// Most messages published between two waits, at least 1
+    pub fn batch_size(mut self, n: usize) -> Self {
+        self.batch_size = n.max(1);
+        self
+    }
+
+    /// Wait between polls when the outbox is empty
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Publishes up to a batch of messages, returns how many were handled
+    pub async fn run_once(&self) -> Result<usize, Error> {
+        let mut handled = 0;
+        while handled < self.batch_size {
+            let message = match self.outbox.claim(self.lease).await? {
+                Some(message) => message,
+                None => break,
+            };
+            match self.sink.publish(&message).await {
+                Ok(()) => self.outbox.published(&message).await?,
+                Err(err) => {
+                    tracing::warn!(
+                        "outbox: could not publish {} on attempt {}: {err}",
+                        message.id,
+                        message.attempts
+                    );
+                    self.outbox.failed(&message, &err).await?
+                }
+            }
+            handled += 1;
+        }
+        Ok(handled)
+    }
+
+    /// Publishes messages until `shutdown` completes
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) {
+        tokio::pin!(shutdown);
+        loop {
+            let wait = match self.run_once().await {
+                Ok(n) if n == self.batch_size => Duration::ZERO,
+                Ok(_) => self.poll_interval,
+                Err(err) => {
+                    tracing::error!("outbox: relay failed: {err}");
+                    self.poll_interval
+                }
+            };
+            tokio::select! {
+                _ = &mut shutdown => return,
+                _ = tokio::time::sleep(wait) => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use mongodb::Client;
+    use testcontainers::runners::AsyncRunner;
+    use testcontainers_modules::mongo::Mongo;
+
+    use super::*;
+
+    /// Fails the first publication of each message
+    #[derive(Default)]
+    struct Flaky {
+        seen: Mutex<Vec<ObjectId>>,
+    }
+
+    #[async_trait]
+    impl OutboxSink for Flaky {
+        async fn publish(&self, message: &OutboxMessage) -> Result<(), String> {
+            let mut seen = self.seen.lock().unwrap();
+            let first = !seen.contains(&message.id);
+            seen.push(message.id);
+            match first {
+                true => Err("unavailable".to_string()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(3), Duration::from_secs(8));
+        assert_eq!(backoff(40), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_relay_publishes_committed_messages() {
+        let server = Mongo::repl_set().start().await.unwrap();
+        let host = server.get_host().await.unwrap();
+        let port = server.get_host_port_ipv4(27017).await.unwrap();
+        let client =
+            Client::with_uri_str(format!("mongodb://{host}:{port}/?directConnection=true"))
+                .await
+                .unwrap();
+        let store = crate::Datastore::from(client.clone());
+        let outbox = Outbox::new(&client, "test", "outbox");
+        outbox.ensure_indexes().await.unwrap();
+
+        // aborted transactions leave nothing to publish
+        let mut session = store.start_transaction().await.unwrap();
+        let aborted = OutboxMessage::job("welcome", "{}".to_string());
+        outbox.write(&mut session, &aborted).await.unwrap();
+        session.abort_transaction().await.unwrap();
+
+        let mut session = store.start_transaction().await.unwrap();
+        let committed = OutboxMessage::event("user_registered", "{}".to_string());
+        outbox.write(&mut session, &committed).await.unwrap();
+        crate::commit(&mut session).await.unwrap();
+        assert_eq!(outbox.pending(10).await.unwrap().len(), 1);
+
+        let relay = Relay::new(outbox.clone(), Flaky::default());
+        assert_eq!(relay.run_once().await.unwrap(), 1);
+        let pending = outbox.pending(10).await.unwrap();
+        assert_eq!(pending[0].last_error.as_deref(), Some("unavailable"));
+
+        // failed messages wait for their backoff
+        assert_eq!(relay.run_once().await.unwrap(), 0);
+        outbox
+            .messages
+            .update_one(doc! {}, doc! {"$unset": {"locked_until": ""}})
+            .await
+            .unwrap();
+        assert_eq!(relay.run_once().await.unwrap(), 1);
+        assert!(outbox.pending(10).await.unwrap().is_empty());
+        assert_eq!(
+            *relay.sink.seen.lock().unwrap(),
+            [committed.id, committed.id]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use crypto::keyring::Keyring;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::Error,
+    Client,
+};
+
+/// Fields handled by a `ReencryptJob`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Reencrypted {
+    /// Fields moved to the active key
+    pub updated: usize,
+    /// Fields that could not be decrypted, left as they were
+    pub skipped: usize,
+}
+
+/// Background job that moves encrypted fields of a collection to the active key.
+///
+/// Fields hold base64 ciphertexts produced by `crypto::field::Encrypted`,
+/// whose associated data is the `crypto::field::context` of the field, the
+/// document `_id` being its hex string for object ids. Documents are walked in `_id` order, one batch at a time,
+/// and every update is conditional on the old ciphertext so that concurrent
+/// writes are never overwritten. Fields that cannot be decrypted are logged
+/// and skipped, so one bad value does not stop the rotation.
+#[derive(Clone, Debug)]
+pub struct ReencryptJob {
+    database: String,
+    collection: String,
+    fields: Vec<String>,
+    batch_size: i64,
+}
+
+impl ReencryptJob {
+    pub fn new(database: &str, collection: &str, fields: &[&str]) -> Self {
+        ReencryptJob {
+            database: database.to_string(),
+            collection: collection.to_string(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            batch_size: 100,
+        }
+    }
+
+    /// Documents read per batch, at least 1
+    pub fn batch_size(mut self, n: i64) -> Self {
+        self.batch_size = n.max(1);
+        self
+    }
+
+    /// Re-encrypts the whole collection
+    pub async fn run(&self, client: &Client, keyring: &Keyring) -> Result<Reencrypted, Error> {
+        let mut total = Reencrypted::default();
+        let mut after = None;
+        loop {
+            let (batch, last) = self.run_batch(client, keyring, after).await?;
+            total.updated += batch.updated;
+            total.skipped += batch.skipped;
+            match last {
+                Some(id) => after = Some(id),
+                None => return Ok(total),
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Processes documents with an `_id` greater than `after`.
+    ///
+    /// Returns the fields handled and the last `_id` seen, which is `None` once
+    /// the collection is exhausted.
+    pub async fn run_batch(
+        &self,
+        client: &Client,
+        keyring: &Keyring,
+        after: Option<Bson>,
+    ) -> Result<(Reencrypted, Option<Bson>), Error> {
+        let collection = client
+            .database(&self.database)
+            .collection::<Document>(&self.collection);
+        let filter = match after {
+            Some(id) => doc! {"_id": {"$gt": id}},
+            None => doc! {},
+        };
+        let mut cursor = collection
+            .find(filter)
+            .sort(doc! {"_id": 1})
+            .limit(self.batch_size)
+            .await?;
+
+        let mut handled = Reencrypted::default();
+        let mut last = None;
+        while cursor.advance().await? {
+            let document = cursor.deserialize_current()?;
+            let id = match document.get("_id") {
+                Some(id) => id.clone(),
+                None => continue,
+            };
+            for field in &self.fields {
+                let old = match document.get_str(field) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                let context = crypto::field::context(&self.collection, field, &id_string(&id));
+                let new = match reencrypt_field(keyring, old, &context) {
+                    Ok(Some(v)) => v,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        tracing::warn!(
+                            "reencrypt: skipped {field} of {id} in {}: {e}",
+                            self.collection
+                        );
+                        handled.skipped += 1;
+                        continue;
+                    }
+                };
+                let r = collection
+                    .update_one(
+                        doc! {"_id": id.clone(), field.as_str(): old},
+                        doc! {"$set": {field.as_str(): new}},
+                    )
+                    .await?;
+                handled.updated += r.modified_count as usize;
+            }
+            last = Some(id);
+        }
+        Ok((handled, last))
+    }
+}
+
+/// `_id` as used in the context of encrypted fields
+fn id_string(id: &Bson) -> String {
+    match id {
+        Bson::ObjectId(id) => id.to_hex(),
+        Bson::String(id) => id.clone(),
+        id => id.to_string(),
+    }
+}
+
+/// Returns the re-encrypted value, or `None` if it already uses the active key
+fn reencrypt_field(keyring: &Keyring, value: &str, context: &str) -> Result<Option<String>, Error> {
+    let ciphertext = match crypto::base64::decode(value) {
+        Ok(c) => c,
+        Err(e) => return Err(Error::custom(e.to_string())),
+    };
+    if !keyring.needs_reencrypt(&ciphertext) {
+        return Ok(None);
+    }
+    match keyring.reencrypt(&ciphertext, context.as_bytes()) {
+        Ok(c) => Ok(Some(crypto::base64::encode(c))),
+        Err(e) => Err(Error::custom(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &[u8] = b"Z44JJuldrAXxYpg0Z44JJuldrAXxYpg0";
+    const NEW: &[u8] = b"9dQ2mXbKv7rT1sLp4aFh8nWc3eYu6GzJ";
+
+    #[test]
+    fn test_reencrypt_field() {
+        let context = crypto::field::context("users", "credentials", "1");
+        let old = Keyring::new(1, OLD).unwrap();
+        let value = crypto::base64::encode(old.encrypt(b"secret", context.as_bytes()).unwrap());
+
+        let keyring = Keyring::new(2, NEW).unwrap().with_key(1, OLD).unwrap();
+        let new = reencrypt_field(&keyring, &value, &context)
+            .unwrap()
+            .unwrap();
+        let ciphertext = crypto::base64::decode(&new).unwrap();
+        assert_eq!(
+            keyring.decrypt(&ciphertext, context.as_bytes()).unwrap(),
+            b"secret"
+        );
+        assert_eq!(reencrypt_field(&keyring, &new, &context).unwrap(), None);
+
+        // a key no longer in the keyring, or a value moved from another document
+        let lost = Keyring::new(3, OLD).unwrap();
+        let value = crypto::base64::encode(lost.encrypt(b"secret", context.as_bytes()).unwrap());
+        assert!(reencrypt_field(&keyring, &value, &context).is_err());
+        let value = crypto::base64::encode(old.encrypt(b"secret", b"users.credentials:2").unwrap());
+        assert!(reencrypt_field(&keyring, &value, &context).is_err());
+    }
+
+    #[test]
+    fn test_id_string() {
+        let id = mongodb::bson::oid::ObjectId::new();
+        assert_eq!(id_string(&Bson::ObjectId(id)), id.to_hex());
+        assert_eq!(id_string(&Bson::String("a".to_string())), "a");
+    }
+}
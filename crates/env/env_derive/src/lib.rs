@@ -0,0 +1,103 @@
+use proc_macro::TokenStream;
+use quote::{quote, quote_spanned};
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Fields, GenericArgument, LitStr,
+    PathArguments, Type,
+};
+
+#[proc_macro_derive(Config, attributes(env))]
+pub fn config_derive(input: TokenStream) -> TokenStream {
+    let ast: DeriveInput = parse_macro_input!(input);
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let fields = match &ast.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => panic!("Config can only be derived for structs with named fields"),
+        },
+        _ => panic!("Config can only be derived for structs"),
+    };
+
+    let mut loads = vec![];
+    let mut inits = vec![];
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+
+        // #[env(name = "VAR", default = "value")]
+        let mut var = ident.to_string().to_uppercase();
+        let mut default = None;
+        for attr in &field.attrs {
+            if !attr.path().is_ident("env") {
+                continue;
+            }
+            let r = attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    var = meta.value()?.parse::<LitStr>()?.value();
+                    Ok(())
+                } else if meta.path.is_ident("default") {
+                    default = Some(meta.value()?.parse::<LitStr>()?.value());
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `name` or `default`"))
+                }
+            });
+            if let Err(err) = r {
+                return err.to_compile_error().into();
+            }
+        }
+
+        let default = match default {
+            Some(default) => quote! { Some(#default) },
+            None => quote! { None },
+        };
+        let ty = &field.ty;
+        let load = match option_inner(ty) {
+            Some(inner) => quote_spanned! { field.span() =>
+                let #ident = errors.optional::<#inner>(source, #var, #default);
+            },
+            None => quote_spanned! { field.span() =>
+                let #ident = errors.required::<#ty>(source, #var, #default);
+            },
+        };
+        loads.push(load);
+        inits.push(match option_inner(ty) {
+            Some(_) => quote! { #ident },
+            None => quote! { #ident: #ident.unwrap() },
+        });
+    }
+
+    let expanded = quote! {
+        impl #impl_generics ::env::Config for #name #ty_generics #where_clause {
+            fn from_source(source: &dyn ::env::Source) -> Result<Self, ::env::ConfigError> {
+                let mut errors = ::env::ConfigError::default();
+                #(#loads)*
+                if !errors.is_empty() {
+                    return Err(errors);
+                }
+                Ok(Self { #(#inits),* })
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
+
+/// Returns `T` for fields declared as `Option<T>`
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let path = match ty {
+        Type::Path(p) if p.qself.is_none() => &p.path,
+        _ => return None,
+    };
+    let segment = path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first() {
+            Some(GenericArgument::Type(inner)) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
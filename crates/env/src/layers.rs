@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Suffix of variables that hold the path of a file containing the value
+pub const FILE_SUFFIX: &str = "_FILE";
+
+/// Modification time and length of a file, `None` when it does not exist
+type Stamp = Option<(SystemTime, u64)>;
+
+/// Values from the dotenv file and `config/{profile}.toml`.
+///
+/// Config file keys are upper-cased and nested tables are joined with `_`, so
+/// `[redis] url = ".."` is read as `REDIS_URL`. Arrays become comma separated
+/// lists, matching how `FromEnv` parses `Vec`.
+pub(crate) struct Layers {
+    pub profile: String,
+    dotenv_path: Option<PathBuf>,
+    dir: PathBuf,
+    dotenv: HashMap<String, String>,
+    values: HashMap<String, String>,
+    stamps: Vec<(PathBuf, Stamp)>,
+}
+
+impl Layers {
+    /// Reads the dotenv file, then `{dir}/{profile}.toml`. Missing files are empty layers.
+    ///
+    /// Without `profile`, the profile is read from `APP_ENV` in the dotenv file.
+    pub fn load(
+        dotenv_path: Option<&Path>,
+        dir: &Path,
+        profile: Option<String>,
+    ) -> Result<Self, String> {
+        let mut stamps = vec![];
+        let mut dotenv = HashMap::new();
+        if let Some(path) = dotenv_path {
+            stamps.push((path.to_path_buf(), stamp(path)));
+            // the only dotenv API that reads a file without exporting it to the process
+            #[allow(deprecated)]
+            let iter = dotenv::from_path_iter(path);
+            match iter {
+                Ok(iter) => {
+                    for item in iter {
+                        match item {
+                            Ok((k, v)) => dotenv.insert(k, v),
+                            Err(e) => return Err(format!("{}: {e}", path.display())),
+                        };
+                    }
+                }
+                Err(dotenv::Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(format!("{}: {e}", path.display())),
+            }
+        }
+
+        let profile = profile
+            .or_else(|| dotenv.get("APP_ENV").cloned())
+            .unwrap_or(crate::DEFAULT_PROFILE.to_string());
+        let path = dir.join(format!("{profile}.toml"));
+        stamps.push((path.clone(), stamp(&path)));
+        let mut values = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(content) => match content.parse::<toml::Table>() {
+                Ok(table) => flatten(&table, "", &mut values),
+                Err(e) => return Err(format!("{}: {e}", path.display())),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("{}: {e}", path.display())),
+        }
+
+        Ok(Layers {
+            profile,
+            dotenv_path: dotenv_path.map(Path::to_path_buf),
+            dir: dir.to_path_buf(),
+            dotenv,
+            values,
+            stamps,
+        })
+    }
+
+    /// Reads the same files again
+    pub fn reload(&self, profile: Option<String>) -> Result<Self, String> {
+        Self::load(self.dotenv_path.as_deref(), &self.dir, profile)
+    }
+
+    /// Whether a file was modified, created or removed since it was read
+    pub fn changed(&self) -> bool {
+        self.stamps.iter().any(|(path, old)| stamp(path) != *old)
+    }
+
+    /// Resolves `key` with `process` as the process environment, in decreasing priority:
+    /// `{key}_FILE`, the process environment, the dotenv file, then the config file
+    pub fn resolve(
+        &self,
+        key: &str,
+        process: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<String>, String> {
+        let layered = |k: &str| {
+            process(k)
+                .or_else(|| self.dotenv.get(k).cloned())
+                .or_else(|| self.values.get(k).cloned())
+        };
+        let file_key = format!("{key}{FILE_SUFFIX}");
+        if let Some(path) = layered(&file_key) {
+            return match fs::read_to_string(&path) {
+                Ok(value) => Ok(Some(value.trim_end_matches(['\r', '\n']).to_string())),
+                Err(e) => Err(format!("cannot read {file_key} {path:?}: {e}")),
+            };
+        }
+        Ok(layered(key))
+    }
+}
+
+/// Looks for `.env` in the current directory and its parents
+pub(crate) fn find_dotenv() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(".env"))
+        .find(|path| path.is_file())
+}
+
+fn stamp(path: &Path) -> Stamp {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+fn flatten(table: &toml::Table, prefix: &str, out: &mut HashMap<String, String>) {
+    for (key, value) in table {
+        let key = format!("{prefix}{}", key.to_uppercase());
+        match value {
+            toml::Value::Table(table) => flatten(table, &format!("{key}_"), out),
+            value => {
+                out.insert(key, scalar(value));
+            }
+        }
+    }
+}
+
+fn scalar(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Array(items) => items.iter().map(scalar).collect::<Vec<_>>().join(","),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("env-layers-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_profile_file() {
+        let dir = temp_dir("profile");
+        fs::write(
+            dir.join("test.toml"),
+            "http_addr = \"0.0.0.0:80\"\nworkers = 4\n[redis]\nnodes = [\"a:6379\", \"b:6379\"]\n",
+        )
+        .unwrap();
+        let layers = Layers::load(None, &dir, Some("test".into())).unwrap();
+        let none = |_: &str| None;
+        assert_eq!(
+            layers.resolve("HTTP_ADDR", none),
+            Ok(Some("0.0.0.0:80".into()))
+        );
+        assert_eq!(layers.resolve("WORKERS", none), Ok(Some("4".into())));
+        assert_eq!(
+            layers.resolve("REDIS_NODES", none),
+            Ok(Some("a:6379,b:6379".into()))
+        );
+
+        // the process environment wins over the file
+        let process = |k: &str| (k == "WORKERS").then(|| "8".to_string());
+        assert_eq!(layers.resolve("WORKERS", process), Ok(Some("8".into())));
+
+        // a missing profile is an empty layer
+        let layers = Layers::load(None, &dir, Some("prod".into())).unwrap();
+        assert_eq!(layers.resolve("HTTP_ADDR", none), Ok(None));
+    }
+
+    #[test]
+    fn test_dotenv_layer() {
+        let dir = temp_dir("dotenv");
+        let dotenv = dir.join(".env");
+        fs::write(&dotenv, "APP_ENV=staging\nWORKERS=2\n").unwrap();
+        fs::write(
+            dir.join("staging.toml"),
+            "workers = 1\nlog_level = \"warn\"\n",
+        )
+        .unwrap();
+        let layers = Layers::load(Some(&dotenv), &dir, None).unwrap();
+        let none = |_: &str| None;
+        assert_eq!(layers.profile, "staging");
+        assert_eq!(layers.resolve("WORKERS", none), Ok(Some("2".into())));
+        assert_eq!(layers.resolve("LOG_LEVEL", none), Ok(Some("warn".into())));
+        assert!(!layers.changed());
+
+        fs::write(&dotenv, "APP_ENV=staging\nWORKERS=16\n").unwrap();
+        assert!(layers.changed());
+        let layers = layers.reload(None).unwrap();
+        assert_eq!(layers.resolve("WORKERS", none), Ok(Some("16".into())));
+    }
+
+    #[test]
+    fn test_file_indirection() {
+        let dir = temp_dir("secret");
+        let secret = dir.join("db_password");
+        fs::write(&secret, "hunter2\n").unwrap();
+        let layers = Layers::load(None, &dir, Some("none".into())).unwrap();
+        let path = secret.to_str().unwrap().to_string();
+        let process = move |k: &str| match k {
+            "DB_PASSWORD_FILE" => Some(path.clone()),
+            "DB_PASSWORD" => Some("ignored".to_string()),
+            "API_KEY_FILE" => Some("/nonexistent/api_key".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            layers.resolve("DB_PASSWORD", &process),
+            Ok(Some("hunter2".into()))
+        );
+        assert!(layers.resolve("API_KEY", &process).is_err());
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let dir = temp_dir("invalid");
+        fs::write(dir.join("dev.toml"), "http_addr = ").unwrap();
+        assert!(Layers::load(None, &dir, Some("dev".into())).is_err());
+    }
+}
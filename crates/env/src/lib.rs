@@ -1,40 +1,176 @@
 use lazy_static::lazy_static;
-use std::env;
-use std::sync::RwLock;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+// lets `#[derive(Config)]` refer to `::env` inside this crate too
+extern crate self as env;
+
+mod config;
+mod layers;
+mod watch;
+
+pub use config::{Config, ConfigError, FromEnv, ProcessEnv, Secret, Source, VarError};
+#[cfg(feature = "derive")]
+pub use env_derive::*;
+pub use layers::FILE_SUFFIX;
+pub use watch::{watch, DEFAULT_WATCH_INTERVAL};
+
+use layers::Layers;
+
+/// Profile used when `APP_ENV` is not set
+pub const DEFAULT_PROFILE: &str = "dev";
 
 pub struct Env;
 
 lazy_static! {
-    static ref INITIALIZED: RwLock<bool> = RwLock::new(false);
+    static ref LAYERS: RwLock<Option<Arc<Layers>>> = RwLock::new(None);
+    static ref SET: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+}
+
+thread_local! {
+    static OVERRIDES: RefCell<Vec<HashMap<String, String>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Loads the dotenv file at `path` and the profile config file.
+///
+/// Settings are resolved in decreasing priority from `with_overrides`, `set`,
+/// `{KEY}_FILE`, the process environment, the dotenv file, then
+/// `config/{APP_ENV}.toml`; `Config` fields fall back to their defaults last.
+/// The config directory can be moved with `CONFIG_DIR`. A missing dotenv or
+/// profile file is not an error. Without `init`, `.env` is looked up in the
+/// current directory and its parents.
+pub fn init(path: impl AsRef<Path>) {
+    load_layers(Some(path.as_ref()));
 }
 
-pub fn init(path: &'static str) {
-    dotenv::from_path(path).unwrap();
-    let mut init = INITIALIZED.write().unwrap();
-    *init = true;
+/// Active profile, from `APP_ENV`
+pub fn profile() -> String {
+    layers().profile.clone()
 }
 
 pub fn get(key: &str) -> Option<String> {
-    ensure_initialzed();
-    match env::var(key) {
-        Ok(v) => Some(v),
-        Err(_) => None,
+    try_get(key).ok().flatten()
+}
+
+/// Like `get`, but reports an unreadable `{KEY}_FILE`
+pub fn try_get(key: &str) -> Result<Option<String>, String> {
+    resolve(&layers(), key)
+}
+
+fn resolve(layers: &Layers, key: &str) -> Result<Option<String>, String> {
+    if let Some(value) = overridden(key) {
+        return Ok(Some(value));
     }
+    layers.resolve(key, |k| overridden(k).or_else(|| std::env::var(k).ok()))
 }
 
+/// Sets `key` for the whole process, above every other source but `with_overrides`.
+///
+/// The real process environment is left untouched.
 pub fn set(key: &str, value: String) {
-    ensure_initialzed();
-    unsafe {
-        env::set_var(key, value);
+    SET.write().unwrap().insert(key.to_string(), value);
+}
+
+/// Runs `f` with `vars` taking precedence over every other source, on the
+/// current thread only, so concurrent tests cannot observe each other's values.
+///
+/// Scopes nest and are removed when `f` returns or panics.
+pub fn with_overrides<I, K, V, F, R>(vars: I, f: F) -> R
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: Into<String>,
+    V: Into<String>,
+    F: FnOnce() -> R,
+{
+    struct Scope;
+
+    impl Drop for Scope {
+        fn drop(&mut self) {
+            OVERRIDES.with(|scopes| scopes.borrow_mut().pop());
+        }
     }
+
+    let vars = vars
+        .into_iter()
+        .map(|(k, v)| (k.into(), v.into()))
+        .collect();
+    OVERRIDES.with(|scopes| scopes.borrow_mut().push(vars));
+    let _scope = Scope;
+    f()
+}
+
+fn overridden(key: &str) -> Option<String> {
+    let scoped = OVERRIDES.with(|scopes| {
+        scopes
+            .borrow()
+            .iter()
+            .rev()
+            .find_map(|vars| vars.get(key).cloned())
+    });
+    scoped.or_else(|| SET.read().unwrap().get(key).cloned())
 }
 
-fn ensure_initialzed() {
-    let init = INITIALIZED.read().unwrap();
-    if !*init {
-        dotenv::dotenv().expect("crate:env could not be initialzed");
+fn layers() -> Arc<Layers> {
+    if let Some(layers) = LAYERS.read().unwrap().as_ref() {
+        return layers.clone();
     }
+    load_layers(layers::find_dotenv().as_deref())
+}
+
+fn load_layers(dotenv: Option<&Path>) -> Arc<Layers> {
+    let dir = std::env::var("CONFIG_DIR").unwrap_or("config".to_string());
+    let layers = match Layers::load(dotenv, Path::new(&dir), std::env::var("APP_ENV").ok()) {
+        Ok(layers) => layers,
+        Err(e) => panic!("crate:env could not load the config file {e}"),
+    };
+    install_layers(layers)
+}
+
+fn install_layers(layers: Layers) -> Arc<Layers> {
+    let layers = Arc::new(layers);
+    *LAYERS.write().unwrap() = Some(layers.clone());
+    layers
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_overrides() {
+        assert_eq!(get("ENV_TEST_OVERRIDE"), None);
+        with_overrides([("ENV_TEST_OVERRIDE", "outer")], || {
+            assert_eq!(get("ENV_TEST_OVERRIDE"), Some("outer".into()));
+            with_overrides([("ENV_TEST_OVERRIDE", "inner")], || {
+                assert_eq!(get("ENV_TEST_OVERRIDE"), Some("inner".into()));
+            });
+            assert_eq!(get("ENV_TEST_OVERRIDE"), Some("outer".into()));
+
+            // other threads do not see the override
+            let other = std::thread::spawn(|| get("ENV_TEST_OVERRIDE"));
+            assert_eq!(other.join().unwrap(), None);
+        });
+        assert_eq!(get("ENV_TEST_OVERRIDE"), None);
+    }
+
+    #[test]
+    fn test_override_removed_on_panic() {
+        let r = std::panic::catch_unwind(|| {
+            with_overrides([("ENV_TEST_PANIC", "1")], || panic!("boom"));
+        });
+        assert!(r.is_err());
+        assert_eq!(get("ENV_TEST_PANIC"), None);
+    }
+
+    #[test]
+    fn test_set() {
+        set("ENV_TEST_SET", "value".into());
+        assert_eq!(get("ENV_TEST_SET"), Some("value".into()));
+        assert!(std::env::var("ENV_TEST_SET").is_err());
+        with_overrides([("ENV_TEST_SET", "scoped")], || {
+            assert_eq!(get("ENV_TEST_SET"), Some("scoped".into()));
+        });
+    }
+}
@@ -0,0 +1,137 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    sync::watch::{self, Receiver},
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::{layers::Layers, Config, FromEnv, Source};
+
+/// Interval between checks of the config files, see `CONFIG_WATCH_INTERVAL`
+pub const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Source reading from layers that are not installed yet
+struct Candidate<'a>(&'a Layers);
+
+impl Source for Candidate<'_> {
+    fn get(&self, key: &str) -> Result<Option<String>, String> {
+        crate::resolve(self.0, key)
+    }
+}
+
+/// Loads `C` and publishes a new snapshot whenever the dotenv or profile file changes.
+///
+/// Files are polled every `CONFIG_WATCH_INTERVAL` on a background task. An update
+/// is applied only when it yields a valid `C`, otherwise it is logged and the last
+/// good config stays in place, for `get` as well. Must be called within a tokio
+/// runtime; the task stops once every receiver is dropped.
+pub fn watch<C>() -> Result<Receiver<Arc<C>>, crate::ConfigError>
+where
+    C: Config + Send + Sync + 'static,
+{
+    let config = C::load()?;
+    let period = crate::get("CONFIG_WATCH_INTERVAL")
+        .and_then(|raw| Duration::from_env(&raw).ok())
+        .unwrap_or(DEFAULT_WATCH_INTERVAL);
+    let (tx, rx) = watch::channel(Arc::new(config));
+
+    tokio::spawn(async move {
+        let mut seen = crate::layers();
+        let mut rejected = None;
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                _ = tx.closed() => return,
+                _ = ticker.tick() => (),
+            }
+
+            let current = crate::layers();
+            let update = if current.changed() {
+                match current.reload(std::env::var("APP_ENV").ok()) {
+                    Ok(candidate) => match C::from_source(&Candidate(&candidate)) {
+                        Ok(config) => {
+                            seen = crate::install_layers(candidate);
+                            Ok(config)
+                        }
+                        Err(e) => Err(e.to_string()),
+                    },
+                    Err(e) => Err(e),
+                }
+            } else if !Arc::ptr_eq(&seen, &current) {
+                // reloaded by another watcher
+                seen = current;
+                C::load().map_err(|e| e.to_string())
+            } else {
+                continue;
+            };
+
+            match update {
+                Ok(config) => {
+                    rejected = None;
+                    tx.send_replace(Arc::new(config));
+                }
+                Err(e) => {
+                    if rejected.as_ref() != Some(&e) {
+                        tracing::warn!("config update rejected, keeping the last good config: {e}");
+                        rejected = Some(e);
+                    }
+                }
+            }
+        }
+    });
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+
+    #[derive(crate::Config, Debug)]
+    struct WatchConfig {
+        #[env(name = "WATCH_TEST_LEVEL")]
+        level: String,
+        #[env(name = "WATCH_TEST_WORKERS", default = "1")]
+        workers: u32,
+    }
+
+    #[tokio::test]
+    async fn test_watch_keeps_last_good_config() {
+        let dir = std::env::temp_dir().join(format!("env-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dotenv = dir.join(".env");
+        fs::write(&dotenv, "WATCH_TEST_LEVEL=info\n").unwrap();
+        crate::init(&dotenv);
+
+        let mut rx = crate::with_overrides([("CONFIG_WATCH_INTERVAL", "20ms")], || {
+            watch::<WatchConfig>().unwrap()
+        });
+        assert_eq!(rx.borrow_and_update().level, "info");
+
+        fs::write(&dotenv, "WATCH_TEST_LEVEL=debug\n").unwrap();
+        timeout(Duration::from_secs(2), rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rx.borrow_and_update().level, "debug");
+
+        // invalid update is rejected
+        fs::write(&dotenv, "WATCH_TEST_LEVEL=trace\nWATCH_TEST_WORKERS=many\n").unwrap();
+        sleep(Duration::from_millis(200)).await;
+        assert!(!rx.has_changed().unwrap());
+        assert_eq!(rx.borrow().level, "debug");
+        assert_eq!(crate::get("WATCH_TEST_LEVEL"), Some("debug".into()));
+
+        fs::write(&dotenv, "WATCH_TEST_LEVEL=trace\nWATCH_TEST_WORKERS=4\n").unwrap();
+        timeout(Duration::from_secs(2), rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rx.borrow().level, "trace");
+        assert_eq!(rx.borrow().workers, 4);
+    }
+}
@@ -0,0 +1,728 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use async_trait::async_trait;
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::{now_ms, JobFailure, JobId, JobRecord, JobStatus, QueueError};
+
+/// Exclusive claim on a job until `expires_at`, Unix time in milliseconds
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lease {
+    pub job_id: JobId,
+    pub token: String,
+    pub expires_at: i64,
+}
+
+impl Lease {
+    pub(crate) fn new(job_id: JobId, token: String, visibility: Duration) -> Self {
+        Lease {
+            job_id,
+            token,
+            expires_at: now_ms() + visibility.as_millis() as i64,
+        }
+    }
+}
+
+/// Random lease token
+pub(crate) fn token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Selects jobs for `Backend::list`, every field set must match
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobFilter {
+    pub status: Option<JobStatus>,
+    pub queue: Option<String>,
+    pub name: Option<String>,
+    pub limit: usize,
+}
+
+impl Default for JobFilter {
+    fn default() -> Self {
+        JobFilter {
+            status: None,
+            queue: None,
+            name: None,
+            limit: 100,
+        }
+    }
+}
+
+impl JobFilter {
+    fn matches(&self, job: &JobRecord) -> bool {
+        self.status.is_none_or(|status| job.status == status)
+            && self.queue.as_ref().is_none_or(|queue| &job.queue == queue)
+            && self.name.as_ref().is_none_or(|name| &job.name == name)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Claimed {
+    pub job: JobRecord,
+    pub lease: Lease,
+}
+
+/// Durable storage for queued jobs.
+///
+/// A claimed job is hidden from other workers until its lease expires, after
+/// which it is delivered again, so every job runs at least once even if its
+/// worker crashes. Operations on a claimed job do nothing and return `false`
+/// once the lease was lost to another worker.
+///
+/// Every change of status or progress updates `JobRecord::updated_at`. Dead
+/// jobs, which ran out of attempts or failed permanently, are kept with their
+/// error history until they are retried or purged. How long succeeded jobs are
+/// kept is up to the backend.
+#[async_trait]
+pub trait Backend: Send + Sync + 'static {
+    /// Stores a job, claimable from `job.run_at`.
+    ///
+    /// Fails with `QueueError::Duplicate` when a job with the same id is stored,
+    /// and with `QueueError::DuplicateKey` when another job holds `job.unique_key`.
+    async fn push(&self, job: JobRecord) -> Result<(), QueueError>;
+
+    /// Job holding the uniqueness key `key`, whether or not the key expired
+    async fn holder(&self, key: &str) -> Result<Option<JobRecord>, QueueError>;
+
+    /// Frees the uniqueness key `key` if the job `id` still holds it
+    async fn unhold(&self, id: &JobId, key: &str) -> Result<bool, QueueError>;
+
+    /// Gives the job `id` the payload, priority, run time and key expiry of
+    /// `job`, if it is still waiting to be claimed
+    async fn merge(&self, id: &JobId, job: &JobRecord) -> Result<bool, QueueError>;
+
+    /// Atomically claims a claimable job of `queue` for `visibility`, the one
+    /// with the highest priority, then the earliest `run_at`, and marks it running
+    async fn claim(&self, queue: &str, visibility: Duration)
+        -> Result<Option<Claimed>, QueueError>;
+
+    /// Pushes the lease expiry to `visibility` from now
+    async fn extend(&self, lease: &Lease, visibility: Duration) -> Result<bool, QueueError>;
+
+    /// Records the progress of a running job, in percent
+    async fn progress(&self, lease: &Lease, percent: u8) -> Result<bool, QueueError>;
+
+    /// Marks a job succeeded, with its JSON encoded result
+    async fn complete(&self, lease: &Lease, result: Option<String>) -> Result<bool, QueueError>;
+
+    /// Queues the job again, claimable from `run_at`, without counting the attempt
+    async fn release(&self, lease: &Lease, run_at: i64) -> Result<bool, QueueError>;
+
+    /// Records a failed run and marks the job retrying, claimable again from `run_at`
+    async fn retry(
+        &self,
+        lease: &Lease,
+        run_at: i64,
+        failure: JobFailure,
+    ) -> Result<bool, QueueError>;
+
+    /// Records the last failed run and moves the job to the dead letters with
+    /// `status`, `Failed` or `Dead`
+    async fn bury(
+        &self,
+        lease: &Lease,
+        failure: JobFailure,
+        status: JobStatus,
+    ) -> Result<bool, QueueError>;
+
+    /// Dead jobs, most recently buried first
+    async fn dead(&self, limit: usize) -> Result<Vec<JobRecord>, QueueError>;
+
+    /// Makes a dead job claimable now with a fresh set of attempts, keeping its errors
+    async fn revive(&self, id: &JobId) -> Result<bool, QueueError>;
+
+    /// Deletes the dead job `id`, or every dead job, returns the number deleted
+    async fn purge(&self, id: Option<&JobId>) -> Result<u64, QueueError>;
+
+    async fn get(&self, id: &JobId) -> Result<Option<JobRecord>, QueueError>;
+
+    /// Jobs matching `filter`, most recently updated first
+    async fn list(&self, filter: &JobFilter) -> Result<Vec<JobRecord>, QueueError>;
+
+    /// Jobs updated at or after `since`, least recently updated first
+    async fn changed(&self, since: i64, limit: usize) -> Result<Vec<JobRecord>, QueueError>;
+
+    /// Takes the lock `name` for `owner`, or extends it if `owner` already holds
+    /// it, until `ttl` from now. Returns whether `owner` holds the lock.
+    async fn lock(&self, name: &str, owner: &str, ttl: Duration) -> Result<bool, QueueError>;
+
+    /// Time of the last run of a recurring schedule, Unix time in milliseconds
+    async fn last_fired(&self, schedule: &str) -> Result<Option<i64>, QueueError>;
+
+    async fn set_last_fired(&self, schedule: &str, at: i64) -> Result<(), QueueError>;
+}
+
+/// Backend keeping jobs in process memory, for tests and local development
+#[derive(Default)]
+pub struct MemoryBackend {
+    jobs: Mutex<Vec<(JobRecord, Option<Lease>)>>,
+    locks: Mutex<HashMap<String, (String, i64)>>,
+    schedules: Mutex<HashMap<String, i64>>,
+}
+
+impl MemoryBackend {
+    /// Number of stored jobs in any status, succeeded jobs are kept until dropped
+    pub fn len(&self) -> usize {
+        self.jobs.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn with_lease<R>(
+        &self,
+        lease: &Lease,
+        f: impl FnOnce(&mut Vec<(JobRecord, Option<Lease>)>, usize) -> R,
+    ) -> Option<R> {
+        let now = now_ms();
+        let mut jobs = self.jobs.lock().unwrap();
+        let i = jobs.iter().position(|(job, held)| {
+            job.id == lease.job_id
+                && held
+                    .as_ref()
+                    .is_some_and(|held| held.token == lease.token && held.expires_at > now)
+        })?;
+        Some(f(&mut jobs, i))
+    }
+}
+
+#[async_trait]
+impl Backend for MemoryBackend {
+    async fn push(&self, job: JobRecord) -> Result<(), QueueError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.iter().any(|(stored, _)| stored.id == job.id) {
+            return Err(QueueError::Duplicate(job.id));
+        }
+        if let Some(key) = &job.unique_key {
+            if jobs
+                .iter()
+                .any(|(stored, _)| stored.unique_key.as_ref() == Some(key))
+            {
+                return Err(QueueError::DuplicateKey(key.clone()));
+            }
+        }
+        jobs.push((job, None));
+        Ok(())
+    }
+
+    async fn holder(&self, key: &str) -> Result<Option<JobRecord>, QueueError> {
+        let jobs = self.jobs.lock().unwrap();
+        Ok(jobs
+            .iter()
+            .find(|(job, _)| job.unique_key.as_deref() == Some(key))
+            .map(|(job, _)| job.clone()))
+    }
+
+    async fn unhold(&self, id: &JobId, key: &str) -> Result<bool, QueueError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let holder = jobs
+            .iter_mut()
+            .find(|(job, _)| &job.id == id && job.unique_key.as_deref() == Some(key));
+        match holder {
+            Some((job, _)) => {
+                job.unique_key = None;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn merge(&self, id: &JobId, job: &JobRecord) -> Result<bool, QueueError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let waiting = jobs.iter_mut().find(|(stored, _)| {
+            &stored.id == id && matches!(stored.status, JobStatus::Queued | JobStatus::Retrying)
+        });
+        match waiting {
+            Some((stored, _)) => {
+                stored.payload = job.payload.clone();
+                stored.priority = job.priority;
+                stored.run_at = job.run_at;
+                stored.unique_until = job.unique_until;
+                stored.updated_at = now_ms();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn claim(
+        &self,
+        queue: &str,
+        visibility: Duration,
+    ) -> Result<Option<Claimed>, QueueError> {
+        let now = now_ms();
+        let mut jobs = self.jobs.lock().unwrap();
+        let next = jobs
+            .iter_mut()
+            .filter(|(job, lease)| {
+                job.queue == queue
+                    && match job.status {
+                        JobStatus::Queued | JobStatus::Retrying => job.run_at <= now,
+                        JobStatus::Running => lease.as_ref().is_some_and(|l| l.expires_at <= now),
+                        _ => false,
+                    }
+            })
+            .min_by_key(|(job, _)| (std::cmp::Reverse(job.priority), job.run_at));
+        let (job, held) = match next {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        let lease = Lease::new(job.id.clone(), token(), visibility);
+        job.attempts += 1;
+        job.status = JobStatus::Running;
+        job.started_at = Some(now);
+        job.updated_at = now;
+        job.progress = None;
+        *held = Some(lease.clone());
+        Ok(Some(Claimed {
+            job: job.clone(),
+            lease,
+        }))
+    }
+
+    async fn extend(&self, lease: &Lease, visibility: Duration) -> Result<bool, QueueError> {
+        let extended = self.with_lease(lease, |jobs, i| {
+            if let Some(held) = jobs[i].1.as_mut() {
+                held.expires_at = now_ms() + visibility.as_millis() as i64;
+            }
+        });
+        Ok(extended.is_some())
+    }
+
+    async fn progress(&self, lease: &Lease, percent: u8) -> Result<bool, QueueError> {
+        let updated = self.with_lease(lease, |jobs, i| {
+            jobs[i].0.progress = Some(percent);
+            jobs[i].0.updated_at = now_ms();
+        });
+        Ok(updated.is_some())
+    }
+
+    async fn complete(&self, lease: &Lease, result: Option<String>) -> Result<bool, QueueError> {
+        let completed = self.with_lease(lease, |jobs, i| {
+            let now = now_ms();
+            let job = &mut jobs[i].0;
+            job.status = JobStatus::Succeeded;
+            job.finished_at = Some(now);
+            job.updated_at = now;
+            job.result = result;
+            jobs[i].1 = None;
+        });
+        Ok(completed.is_some())
+    }
+
+    async fn release(&self, lease: &Lease, run_at: i64) -> Result<bool, QueueError> {
+        let released = self.with_lease(lease, |jobs, i| {
+            let job = &mut jobs[i].0;
+            job.status = JobStatus::Queued;
+            job.run_at = run_at;
+            job.attempts -= 1;
+            job.updated_at = now_ms();
+            jobs[i].1 = None;
+        });
+        Ok(released.is_some())
+    }
+
+    async fn retry(
+        &self,
+        lease: &Lease,
+        run_at: i64,
+        failure: JobFailure,
+    ) -> Result<bool, QueueError> {
+        let retried = self.with_lease(lease, |jobs, i| {
+            let job = &mut jobs[i].0;
+            job.status = JobStatus::Retrying;
+            job.run_at = run_at;
+            job.errors.push(failure);
+            job.updated_at = now_ms();
+            jobs[i].1 = None;
+        });
+        Ok(retried.is_some())
+    }
+
+    async fn bury(
+        &self,
+        lease: &Lease,
+        failure: JobFailure,
+        status: JobStatus,
+    ) -> Result<bool, QueueError> {
+        let buried = self.with_lease(lease, |jobs, i| {
+            let now = now_ms();
+            let job = &mut jobs[i].0;
+            job.status = status;
+            job.dead_at = Some(now);
+            job.errors.push(failure);
+            job.updated_at = now;
+            jobs[i].1 = None;
+        });
+        Ok(buried.is_some())
+    }
+
+    async fn dead(&self, limit: usize) -> Result<Vec<JobRecord>, QueueError> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut dead: Vec<_> = jobs
+            .iter()
+            .filter(|(job, _)| job.dead_at.is_some())
+            .map(|(job, _)| job.clone())
+            .collect();
+        dead.sort_by_key(|job| std::cmp::Reverse(job.dead_at));
+        dead.truncate(limit);
+        Ok(dead)
+    }
+
+    async fn revive(&self, id: &JobId) -> Result<bool, QueueError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let dead = jobs
+            .iter_mut()
+            .find(|(job, _)| &job.id == id && job.dead_at.is_some());
+        match dead {
+            Some((job, _)) => {
+                let now = now_ms();
+                job.status = JobStatus::Queued;
+                job.dead_at = None;
+                job.attempts = 0;
+                job.run_at = now;
+                job.progress = None;
+                job.updated_at = now;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn purge(&self, id: Option<&JobId>) -> Result<u64, QueueError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+        jobs.retain(|(job, _)| job.dead_at.is_none() || id.is_some_and(|id| &job.id != id));
+        Ok((before - jobs.len()) as u64)
+    }
+
+    async fn get(&self, id: &JobId) -> Result<Option<JobRecord>, QueueError> {
+        let jobs = self.jobs.lock().unwrap();
+        Ok(jobs
+            .iter()
+            .find(|(job, _)| &job.id == id)
+            .map(|(job, _)| job.clone()))
+    }
+
+    async fn list(&self, filter: &JobFilter) -> Result<Vec<JobRecord>, QueueError> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut found: Vec<_> = jobs
+            .iter()
+            .filter(|(job, _)| filter.matches(job))
+            .map(|(job, _)| job.clone())
+            .collect();
+        found.sort_by_key(|job| std::cmp::Reverse(job.updated_at));
+        found.truncate(filter.limit);
+        Ok(found)
+    }
+
+    async fn changed(&self, since: i64, limit: usize) -> Result<Vec<JobRecord>, QueueError> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut changed: Vec<_> = jobs
+            .iter()
+            .filter(|(job, _)| job.updated_at >= since)
+            .map(|(job, _)| job.clone())
+            .collect();
+        changed.sort_by_key(|job| job.updated_at);
+        changed.truncate(limit);
+        Ok(changed)
+    }
+
+    async fn lock(&self, name: &str, owner: &str, ttl: Duration) -> Result<bool, QueueError> {
+        let now = now_ms();
+        let mut locks = self.locks.lock().unwrap();
+        match locks.get(name) {
+            Some((holder, expires_at)) if holder != owner && *expires_at > now => Ok(false),
+            _ => {
+                let expires_at = now + ttl.as_millis() as i64;
+                locks.insert(name.to_string(), (owner.to_string(), expires_at));
+                Ok(true)
+            }
+        }
+    }
+
+    async fn last_fired(&self, schedule: &str) -> Result<Option<i64>, QueueError> {
+        Ok(self.schedules.lock().unwrap().get(schedule).copied())
+    }
+
+    async fn set_last_fired(&self, schedule: &str, at: i64) -> Result<(), QueueError> {
+        self.schedules
+            .lock()
+            .unwrap()
+            .insert(schedule.to_string(), at);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Priority, DEFAULT_QUEUE};
+
+    fn record(name: &str, run_at: i64) -> JobRecord {
+        JobRecord {
+            id: JobId::generate(),
+            name: name.to_string(),
+            queue: DEFAULT_QUEUE.to_string(),
+            priority: Priority::Normal,
+            payload: "null".to_string(),
+            attempts: 0,
+            run_at,
+            errors: vec![],
+            dead_at: None,
+            status: JobStatus::Queued,
+            created_at: run_at,
+            updated_at: run_at,
+            started_at: None,
+            finished_at: None,
+            progress: None,
+            result: None,
+            unique_key: None,
+            unique_until: None,
+            correlation_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_claim_hides_job_until_lease_expires() {
+        let backend = MemoryBackend::default();
+        backend.push(record("a", now_ms())).await.unwrap();
+
+        let visibility = Duration::from_millis(50);
+        let first = backend
+            .claim(DEFAULT_QUEUE, visibility)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.job.attempts, 1);
+        assert!(backend
+            .claim(DEFAULT_QUEUE, visibility)
+            .await
+            .unwrap()
+            .is_none());
+
+        // the worker crashed, the job is delivered again
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let second = backend
+            .claim(DEFAULT_QUEUE, visibility)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.job.id, first.job.id);
+        assert_eq!(second.job.attempts, 2);
+
+        // the first lease is lost
+        assert!(!backend.complete(&first.lease, None).await.unwrap());
+        assert!(backend.complete(&second.lease, None).await.unwrap());
+        let job = backend.get(&first.job.id).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert!(backend
+            .claim(DEFAULT_QUEUE, visibility)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_claim_order_and_release() {
+        let backend = MemoryBackend::default();
+        let now = now_ms();
+        backend.push(record("later", now + 60_000)).await.unwrap();
+        backend.push(record("second", now - 10)).await.unwrap();
+        backend.push(record("first", now - 20)).await.unwrap();
+
+        let visibility = Duration::from_secs(30);
+        let claimed = backend
+            .claim(DEFAULT_QUEUE, visibility)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.job.name, "first");
+        assert!(backend.extend(&claimed.lease, visibility).await.unwrap());
+        assert!(backend
+            .release(&claimed.lease, now + 120_000)
+            .await
+            .unwrap());
+
+        let claimed = backend
+            .claim(DEFAULT_QUEUE, visibility)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.job.name, "second");
+        assert!(backend
+            .claim(DEFAULT_QUEUE, visibility)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters() {
+        let backend = MemoryBackend::default();
+        backend.push(record("a", now_ms())).await.unwrap();
+        backend.push(record("b", now_ms())).await.unwrap();
+
+        let visibility = Duration::from_secs(30);
+        for _ in 0..2 {
+            let claimed = backend
+                .claim(DEFAULT_QUEUE, visibility)
+                .await
+                .unwrap()
+                .unwrap();
+            let failure = JobFailure {
+                attempt: claimed.job.attempts,
+                error: format!("{} failed", claimed.job.name),
+                at: now_ms(),
+            };
+            assert!(backend
+                .bury(&claimed.lease, failure, JobStatus::Dead)
+                .await
+                .unwrap());
+        }
+        assert!(backend
+            .claim(DEFAULT_QUEUE, visibility)
+            .await
+            .unwrap()
+            .is_none());
+
+        let dead = backend.dead(10).await.unwrap();
+        assert_eq!(dead.len(), 2);
+        assert!(dead.iter().all(|job| job.errors.len() == 1));
+        assert!(dead.iter().all(|job| job.status == JobStatus::Dead));
+
+        assert!(backend.revive(&dead[0].id).await.unwrap());
+        assert!(!backend.revive(&dead[0].id).await.unwrap());
+        let claimed = backend
+            .claim(DEFAULT_QUEUE, visibility)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.job.id, dead[0].id);
+        assert_eq!(claimed.job.attempts, 1);
+        assert_eq!(claimed.job.errors.len(), 1);
+
+        // only dead jobs are purged
+        assert_eq!(backend.purge(None).await.unwrap(), 1);
+        assert_eq!(backend.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_lock() {
+        let backend = MemoryBackend::default();
+        let ttl = Duration::from_millis(50);
+        assert!(backend.lock("leader", "a", ttl).await.unwrap());
+        assert!(!backend.lock("leader", "b", ttl).await.unwrap());
+        assert!(backend.lock("leader", "a", ttl).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(backend.lock("leader", "b", ttl).await.unwrap());
+        assert!(!backend.lock("leader", "a", ttl).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_claim_by_queue_and_priority() {
+        let backend = MemoryBackend::default();
+        let now = now_ms();
+        backend.push(record("normal", now - 20)).await.unwrap();
+        let mut urgent = record("urgent", now - 10);
+        urgent.priority = Priority::High;
+        backend.push(urgent).await.unwrap();
+        let mut email = record("email", now - 30);
+        email.queue = "emails".to_string();
+        backend.push(email).await.unwrap();
+
+        let visibility = Duration::from_secs(30);
+        let mut names = vec![];
+        for queue in [DEFAULT_QUEUE, DEFAULT_QUEUE, "emails", "emails"] {
+            let claimed = backend.claim(queue, visibility).await.unwrap();
+            names.push(claimed.map(|claimed| claimed.job.name));
+        }
+        assert_eq!(
+            names,
+            [
+                Some("urgent".to_string()),
+                Some("normal".to_string()),
+                Some("email".to_string()),
+                None
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_status_and_changes() {
+        let backend = MemoryBackend::default();
+        let start = now_ms();
+        let job = record("report", start);
+        let id = job.id.clone();
+        backend.push(job).await.unwrap();
+        let mut other = record("email", start - 1_000);
+        other.queue = "emails".to_string();
+        backend.push(other).await.unwrap();
+
+        let visibility = Duration::from_secs(30);
+        let claimed = backend
+            .claim(DEFAULT_QUEUE, visibility)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.job.status, JobStatus::Running);
+        assert!(claimed.job.started_at.is_some());
+        assert!(backend.progress(&claimed.lease, 40).await.unwrap());
+        let running = backend.get(&id).await.unwrap().unwrap();
+        assert_eq!(running.progress, Some(40));
+
+        let failure = JobFailure {
+            attempt: 1,
+            error: "timeout".to_string(),
+            at: now_ms(),
+        };
+        assert!(backend
+            .retry(&claimed.lease, now_ms(), failure)
+            .await
+            .unwrap());
+        let filter = JobFilter {
+            status: Some(JobStatus::Retrying),
+            ..JobFilter::default()
+        };
+        let retrying = backend.list(&filter).await.unwrap();
+        assert_eq!(retrying.len(), 1);
+        assert_eq!(retrying[0].id, id);
+
+        let claimed = backend
+            .claim(DEFAULT_QUEUE, visibility)
+            .await
+            .unwrap()
+            .unwrap();
+        let result = Some(r#"{"rows":3}"#.to_string());
+        assert!(backend
+            .complete(&claimed.lease, result.clone())
+            .await
+            .unwrap());
+        let done = backend.get(&id).await.unwrap().unwrap();
+        assert_eq!(done.status, JobStatus::Succeeded);
+        assert_eq!(done.result, result);
+        assert!(done.finished_at.is_some());
+
+        let filter = JobFilter {
+            queue: Some("emails".to_string()),
+            ..JobFilter::default()
+        };
+        let emails = backend.list(&filter).await.unwrap();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].status, JobStatus::Queued);
+
+        let changed = backend.changed(start, 10).await.unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].id, id);
+        assert!(backend
+            .changed(done.updated_at + 1, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicI64, AtomicU8, AtomicUsize, Ordering},
+    Arc,
+};
+
+use serde::Serialize;
+
+use crate::now_ms;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerState {
+    /// `run` was not called yet
+    Idle,
+    Running,
+    /// Shutdown requested, waiting for running jobs
+    Stopping,
+    Stopped,
+}
+
+impl WorkerState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => Self::Idle,
+            1 => Self::Running,
+            2 => Self::Stopping,
+            _ => Self::Stopped,
+        }
+    }
+}
+
+/// Snapshot of a worker for health endpoints
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HealthReport {
+    pub state: WorkerState,
+    pub live: bool,
+    pub ready: bool,
+    /// Jobs running on this worker
+    pub running: usize,
+    /// Whether the last claim reached the backend
+    pub backend_ok: bool,
+    /// Unix time in milliseconds of the last claim that reached the backend
+    pub last_claim_at: Option<i64>,
+}
+
+/// Liveness and readiness of a worker, cheap to clone.
+///
+/// A worker is live while every queue loop it started is still running, and
+/// ready while it runs and its last claim reached the backend.
+#[derive(Clone, Default)]
+pub struct WorkerHealth {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: AtomicU8,
+    queues: AtomicUsize,
+    loops: AtomicUsize,
+    running: AtomicUsize,
+    backend_ok: AtomicBool,
+    last_claim_at: AtomicI64,
+}
+
+impl WorkerHealth {
+    pub fn state(&self) -> WorkerState {
+        WorkerState::from_u8(self.inner.state.load(Ordering::SeqCst))
+    }
+
+    pub fn is_live(&self) -> bool {
+        match self.state() {
+            WorkerState::Idle | WorkerState::Stopping => true,
+            WorkerState::Running => {
+                self.inner.loops.load(Ordering::SeqCst) == self.inner.queues.load(Ordering::SeqCst)
+            }
+            WorkerState::Stopped => false,
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.state() == WorkerState::Running
+            && self.is_live()
+            && self.inner.backend_ok.load(Ordering::SeqCst)
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let last_claim_at = match self.inner.last_claim_at.load(Ordering::SeqCst) {
+            0 => None,
+            at => Some(at),
+        };
+        HealthReport {
+            state: self.state(),
+            live: self.is_live(),
+            ready: self.is_ready(),
+            running: self.inner.running.load(Ordering::SeqCst),
+            backend_ok: self.inner.backend_ok.load(Ordering::SeqCst),
+            last_claim_at,
+        }
+    }
+
+    pub(crate) fn set_state(&self, state: WorkerState) {
+        self.inner.state.store(state as u8, Ordering::SeqCst);
+    }
+
+    pub(crate) fn set_queues(&self, n: usize) {
+        self.inner.queues.store(n, Ordering::SeqCst);
+    }
+
+    /// Counts a queue loop until the guard is dropped, including by a panic
+    pub(crate) fn queue_loop(&self) -> Guard {
+        self.inner.loops.fetch_add(1, Ordering::SeqCst);
+        Guard {
+            counter: self.inner.clone(),
+            loops: true,
+        }
+    }
+
+    /// Counts a running job until the guard is dropped
+    pub(crate) fn job(&self) -> Guard {
+        self.inner.running.fetch_add(1, Ordering::SeqCst);
+        Guard {
+            counter: self.inner.clone(),
+            loops: false,
+        }
+    }
+
+    pub(crate) fn claimed(&self, ok: bool) {
+        self.inner.backend_ok.store(ok, Ordering::SeqCst);
+        if ok {
+            self.inner.last_claim_at.store(now_ms(), Ordering::SeqCst);
+        }
+    }
+}
+
+pub(crate) struct Guard {
+    counter: Arc<Inner>,
+    loops: bool,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let counter = match self.loops {
+            true => &self.counter.loops,
+            false => &self.counter.running,
+        };
+        counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
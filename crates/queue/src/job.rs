@@ -0,0 +1,304 @@
+use std::{
+    fmt::Display,
+    future::Future,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use mongodb::bson::oid::ObjectId;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{Backend, JobError, Lease, QueueError, RetryPolicy};
+
+/// Work that can be stored in the queue and run by any worker.
+///
+/// The value itself is the payload: it is serialized to JSON on dispatch and
+/// deserialized by the worker that claims it. `NAME` selects the handler, so it
+/// must stay stable while jobs of that type may still be queued.
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize)]
+/// struct SendWelcomeEmail {
+///     user_id: String,
+/// }
+///
+/// impl queue::Job for SendWelcomeEmail {
+///     const NAME: &'static str = "send_welcome_email";
+///
+///     async fn handle(self, ctx: queue::JobContext) -> Result<(), queue::JobError> {
+///         ...
+///         ctx.progress(50).await?;
+///         ...
+///     }
+/// }
+/// ```
+pub trait Job: Serialize + DeserializeOwned + Send + 'static {
+    const NAME: &'static str;
+
+    /// Named queue the job is pushed to, workers subscribe to queues
+    const QUEUE: &'static str = DEFAULT_QUEUE;
+
+    /// Runs the job. Jobs are delivered at least once, so handlers should be idempotent
+    fn handle(self, ctx: JobContext) -> impl Future<Output = Result<(), JobError>> + Send;
+
+    /// Retries of failed runs, 5 attempts with exponential backoff by default
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Jobs of a queue are claimed by decreasing priority, then by `run_at`
+    fn priority(&self) -> Priority {
+        Priority::Normal
+    }
+}
+
+/// Queue of jobs that do not name one
+pub const DEFAULT_QUEUE: &str = "default";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Critical,
+}
+
+impl Priority {
+    /// Stored value, higher runs first
+    pub fn level(self) -> i32 {
+        match self {
+            Self::Low => -1,
+            Self::Normal => 0,
+            Self::High => 1,
+            Self::Critical => 2,
+        }
+    }
+
+    pub fn from_level(level: i32) -> Self {
+        match level {
+            i32::MIN..=-1 => Self::Low,
+            0 => Self::Normal,
+            1 => Self::High,
+            2.. => Self::Critical,
+        }
+    }
+}
+
+/// Where a job is in its lifecycle
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Waiting for `run_at`, or for a free worker
+    Queued,
+    /// Claimed by a worker
+    Running,
+    Succeeded,
+    /// Failed with a permanent error, kept in the dead letters
+    Failed,
+    /// Failed and waiting for its next attempt
+    Retrying,
+    /// Ran out of attempts, kept in the dead letters
+    Dead,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+            Self::Retrying => "retrying",
+            Self::Dead => "dead",
+        }
+    }
+
+    /// Whether the job is in the dead letters
+    pub fn is_dead(self) -> bool {
+        matches!(self, Self::Failed | Self::Dead)
+    }
+}
+
+impl Display for JobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queued" => Ok(Self::Queued),
+            "running" => Ok(Self::Running),
+            "succeeded" => Ok(Self::Succeeded),
+            "failed" => Ok(Self::Failed),
+            "retrying" => Ok(Self::Retrying),
+            "dead" => Ok(Self::Dead),
+            _ => Err(format!("unknown job status {s:?}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct JobId(String);
+
+impl JobId {
+    /// New id, ordered by creation time
+    pub fn generate() -> Self {
+        JobId(ObjectId::new().to_hex())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for JobId {
+    fn from(id: String) -> Self {
+        JobId(id)
+    }
+}
+
+impl Display for JobId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A job as stored by a backend. Times are Unix time in milliseconds.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct JobRecord {
+    pub id: JobId,
+    /// `Job::NAME`
+    pub name: String,
+    /// `Job::QUEUE`
+    pub queue: String,
+    pub priority: Priority,
+    /// JSON encoded job
+    pub payload: String,
+    /// Number of times the job was claimed, including the current claim
+    pub attempts: u32,
+    /// Unix time in milliseconds from which the job may be claimed
+    pub run_at: i64,
+    /// Failed runs, oldest first
+    pub errors: Vec<JobFailure>,
+    /// When the job was moved to the dead letters
+    pub dead_at: Option<i64>,
+    pub status: JobStatus,
+    pub created_at: i64,
+    /// Last status or progress change
+    pub updated_at: i64,
+    /// Start of the latest attempt
+    pub started_at: Option<i64>,
+    /// When the job succeeded
+    pub finished_at: Option<i64>,
+    /// Percentage reported by the running job
+    pub progress: Option<u8>,
+    /// JSON encoded value set with `JobContext::set_result`
+    pub result: Option<String>,
+    /// Uniqueness key held by the job, see `Unique`
+    pub unique_key: Option<String>,
+    /// When the uniqueness key expires
+    pub unique_until: Option<i64>,
+    /// Id of the request or job that dispatched this one, see `with_correlation_id`
+    pub correlation_id: Option<String>,
+}
+
+/// A failed run of a job
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct JobFailure {
+    pub attempt: u32,
+    pub error: String,
+    /// Unix time in milliseconds
+    pub at: i64,
+}
+
+impl JobRecord {
+    /// Record for `job`, ready to run now
+    pub fn new<J: Job>(job: &J) -> Result<Self, QueueError> {
+        let payload = match serde_json::to_string(job) {
+            Ok(p) => p,
+            Err(e) => return Err(QueueError::Payload(e.to_string())),
+        };
+        let now = crate::now_ms();
+        Ok(JobRecord {
+            id: JobId::generate(),
+            name: J::NAME.to_string(),
+            queue: J::QUEUE.to_string(),
+            priority: job.priority(),
+            payload,
+            attempts: 0,
+            run_at: now,
+            errors: vec![],
+            dead_at: None,
+            status: JobStatus::Queued,
+            created_at: now,
+            updated_at: now,
+            started_at: None,
+            finished_at: None,
+            progress: None,
+            result: None,
+            unique_key: None,
+            unique_until: None,
+            correlation_id: crate::correlation_id(),
+        })
+    }
+}
+
+/// Information about the current delivery of a job, and a way to report on it
+#[derive(Clone)]
+pub struct JobContext {
+    id: JobId,
+    attempt: u32,
+    backend: Arc<dyn Backend>,
+    lease: Lease,
+    result: Arc<Mutex<Option<String>>>,
+}
+
+impl JobContext {
+    pub(crate) fn new(job: &JobRecord, lease: Lease, backend: Arc<dyn Backend>) -> Self {
+        JobContext {
+            id: job.id.clone(),
+            attempt: job.attempts,
+            backend,
+            lease,
+            result: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn id(&self) -> &JobId {
+        &self.id
+    }
+
+    /// 1 on the first delivery
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Records how far the job got, in percent
+    pub async fn progress(&self, percent: u8) -> Result<(), QueueError> {
+        self.backend
+            .progress(&self.lease, percent.min(100))
+            .await
+            .map(|_| ())
+    }
+
+    /// Sets the value stored with the job once it succeeds, replacing any previous one
+    pub fn set_result<T: Serialize>(&self, result: &T) -> Result<(), QueueError> {
+        let result = match serde_json::to_string(result) {
+            Ok(r) => r,
+            Err(e) => return Err(QueueError::Payload(e.to_string())),
+        };
+        *self.result.lock().unwrap() = Some(result);
+        Ok(())
+    }
+
+    pub(crate) fn take_result(&self) -> Option<String> {
+        self.result.lock().unwrap().take()
+    }
+}
@@ -1,130 +1,447 @@
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Display,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use log::{debug, error};
+use futures_util::{stream, Stream};
+use lazy_static::lazy_static;
 
-pub static QUEUE: once_cell::sync::Lazy<Queue> = once_cell::sync::Lazy::new(|| Queue::new());
+mod backend;
+mod health;
+mod job;
+mod middleware;
+mod mongo;
+mod outbox;
+mod retry;
+mod schedule;
+mod unique;
+mod worker;
 
-pub struct Job {
-    pub title: String,
-    pub handler: Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'static>>,
+pub use backend::{Backend, Claimed, JobFilter, Lease, MemoryBackend};
+pub use health::{HealthReport, WorkerHealth, WorkerState};
+pub use job::{Job, JobContext, JobFailure, JobId, JobRecord, JobStatus, Priority, DEFAULT_QUEUE};
+pub use middleware::{
+    correlation_id, with_correlation_id, CatchPanic, JobMetrics, JobMiddleware, Metrics, Next,
+    Timeout, Trace,
+};
+pub use mongo::MongoBackend;
+pub use outbox::outbox_message;
+pub use retry::{JobError, RetryPolicy};
+pub use schedule::{CatchUp, Scheduler};
+pub use tokio_util::sync::CancellationToken;
+pub use unique::Unique;
+pub use worker::{QueueOptions, Worker};
+
+lazy_static! {
+    static ref GLOBAL: RwLock<Option<Queue>> = RwLock::new(None);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QueueError {
+    /// The storage backend failed
+    Backend(String),
+    /// The job could not be serialized
+    Payload(String),
+    /// `dispatch` was called before `install`
+    NotInstalled,
+    /// A job with this id is already stored
+    Duplicate(JobId),
+    /// Another job holds this uniqueness key
+    DuplicateKey(String),
+    /// The cron expression could not be parsed
+    InvalidSchedule(String),
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Backend(e) => write!(f, "queue backend error: {e}"),
+            Self::Payload(e) => write!(f, "invalid job payload: {e}"),
+            Self::NotInstalled => f.write_str("no queue is installed"),
+            Self::Duplicate(id) => write!(f, "job {id} already exists"),
+            Self::DuplicateKey(key) => write!(f, "a job already holds the key {key:?}"),
+            Self::InvalidSchedule(e) => write!(f, "invalid schedule: {e}"),
+        }
+    }
 }
 
+impl std::error::Error for QueueError {}
+
+/// Handle to push jobs to a backend, cheap to clone
+#[derive(Clone)]
 pub struct Queue {
-    sender: Sender<Option<Job>>,
-    receiver: Mutex<Receiver<Option<Job>>>,
+    backend: Arc<dyn Backend>,
 }
 
 impl Queue {
-    pub fn new() -> Self {
-        let (sender, receiver) = mpsc::channel();
+    pub fn new(backend: impl Backend) -> Self {
         Queue {
-            sender,
-            receiver: Mutex::new(receiver),
+            backend: Arc::new(backend),
         }
     }
 
-    pub fn push(&self, job: Job) {
-        self.sender.send(Some(job)).unwrap();
+    /// Stores `job` to run as soon as a worker is free
+    pub async fn dispatch<J: Job>(&self, job: &J) -> Result<JobId, QueueError> {
+        self.push(JobRecord::new(job)?).await
+    }
+
+    /// Stores `job` to run once `at` has passed
+    pub async fn dispatch_at<J: Job>(&self, job: &J, at: SystemTime) -> Result<JobId, QueueError> {
+        let mut record = JobRecord::new(job)?;
+        record.run_at = unix_ms(at);
+        self.push(record).await
+    }
+
+    /// Stores `job` to run after `delay`
+    pub async fn dispatch_in<J: Job>(&self, job: &J, delay: Duration) -> Result<JobId, QueueError> {
+        self.dispatch_at(job, SystemTime::now() + delay).await
+    }
+
+    /// Stores `job` unless an equal job is already pending, see `Unique`.
+    ///
+    /// Returns the id of the job that will do the work, which is the pending
+    /// one when the dispatch was dropped or merged.
+    pub async fn dispatch_unique<J: Job>(
+        &self,
+        job: &J,
+        unique: Unique,
+    ) -> Result<JobId, QueueError> {
+        unique::push(self.backend.as_ref(), JobRecord::new(job)?, &unique).await
+    }
+
+    async fn push(&self, record: JobRecord) -> Result<JobId, QueueError> {
+        let id = record.id.clone();
+        self.backend.push(record).await?;
+        Ok(id)
+    }
+
+    /// Jobs that ran out of attempts or failed permanently, most recent first
+    pub async fn dead_jobs(&self, limit: usize) -> Result<Vec<JobRecord>, QueueError> {
+        self.backend.dead(limit).await
+    }
+
+    /// Runs a dead job again with a fresh set of attempts, returns `false` if it is not dead
+    pub async fn retry_dead(&self, id: &JobId) -> Result<bool, QueueError> {
+        self.backend.revive(id).await
+    }
+
+    /// Deletes the dead job `id`, or every dead job, returns the number deleted
+    pub async fn purge_dead(&self, id: Option<&JobId>) -> Result<u64, QueueError> {
+        self.backend.purge(id).await
+    }
+
+    /// Status, progress and result of the job `id`
+    pub async fn job(&self, id: &JobId) -> Result<Option<JobRecord>, QueueError> {
+        self.backend.get(id).await
     }
 
-    pub fn pop(&self) -> Option<Job> {
-        self.receiver.lock().unwrap().recv().unwrap()
+    /// Jobs matching `filter`, most recently updated first
+    pub async fn jobs(&self, filter: &JobFilter) -> Result<Vec<JobRecord>, QueueError> {
+        self.backend.list(filter).await
     }
 
-    pub fn end(&self) {
-        self.sender.send(None).unwrap();
+    /// Jobs as they change from now on, polling the backend every `poll`.
+    ///
+    /// Each item is the job after a change of status or progress. Changes made
+    /// between two polls are coalesced, and the stream ends on the first error.
+    pub fn changes(&self, poll: Duration) -> impl Stream<Item = Result<JobRecord, QueueError>> {
+        const BATCH: usize = 100;
+        struct State {
+            backend: Arc<dyn Backend>,
+            since: i64,
+            // ids already sent with `updated_at == since`
+            sent: HashSet<JobId>,
+            pending: VecDeque<JobRecord>,
+            failed: bool,
+        }
+        let state = State {
+            backend: self.backend.clone(),
+            since: now_ms(),
+            sent: HashSet::new(),
+            pending: VecDeque::new(),
+            failed: false,
+        };
+        stream::unfold(state, move |mut state| async move {
+            if state.failed {
+                return None;
+            }
+            loop {
+                if let Some(job) = state.pending.pop_front() {
+                    if job.updated_at > state.since {
+                        state.since = job.updated_at;
+                        state.sent.clear();
+                    }
+                    state.sent.insert(job.id.clone());
+                    return Some((Ok(job), state));
+                }
+                match state.backend.changed(state.since, BATCH).await {
+                    Ok(jobs) => state.pending.extend(jobs.into_iter().filter(|job| {
+                        job.updated_at > state.since || !state.sent.contains(&job.id)
+                    })),
+                    Err(e) => {
+                        state.failed = true;
+                        return Some((Err(e), state));
+                    }
+                }
+                if state.pending.is_empty() {
+                    tokio::time::sleep(poll).await;
+                }
+            }
+        })
     }
 }
 
-pub fn dispatch(job: Job) {
-    tokio::spawn(async {
-        QUEUE.push(job);
-    });
+/// Sets the queue used by `dispatch`
+pub fn install(queue: Queue) {
+    *GLOBAL.write().unwrap() = Some(queue);
 }
 
-pub async fn run_block(n: Option<u64>) {
-    let n = Arc::new(Mutex::new(n));
-    if let Some(ref n) = *n.lock().unwrap() {
-        debug!("Queue: max allowed executions :{n}");
+/// Queue set by `install`
+pub fn global() -> Result<Queue, QueueError> {
+    match GLOBAL.read().unwrap().as_ref() {
+        Some(queue) => Ok(queue.clone()),
+        None => Err(QueueError::NotInstalled),
     }
+}
 
-    loop {
-        if let Some(job) = QUEUE.pop() {
-            let n_clone = Arc::clone(&n);
-            tokio::spawn(async move {
-                match job.handler.await {
-                    Ok(_) => debug!("Queue: job: {} completed", job.title),
-                    Err(e) => error!("Queue: job: {} returned error: {}", job.title, e),
+/// Dispatches `job` on the installed queue
+pub async fn dispatch<J: Job>(job: &J) -> Result<JobId, QueueError> {
+    global()?.dispatch(job).await
+}
+
+/// Dispatches `job` on the installed queue to run once `at` has passed
+pub async fn dispatch_at<J: Job>(job: &J, at: SystemTime) -> Result<JobId, QueueError> {
+    global()?.dispatch_at(job, at).await
+}
+
+/// Dispatches `job` on the installed queue to run after `delay`
+pub async fn dispatch_in<J: Job>(job: &J, delay: Duration) -> Result<JobId, QueueError> {
+    global()?.dispatch_in(job, delay).await
+}
+
+/// Dispatches `job` on the installed queue unless an equal job is already pending
+pub async fn dispatch_unique<J: Job>(job: &J, unique: Unique) -> Result<JobId, QueueError> {
+    global()?.dispatch_unique(job, unique).await
+}
+
+/// Token cancelled when the process receives SIGTERM or Ctrl-C, for `Worker::run_until`
+pub fn shutdown_signal() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let terminate = async {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::terminate()) {
+                Ok(mut s) => {
+                    s.recv().await;
                 }
-                let mut data = n_clone.lock().unwrap();
-                if let Some(ref mut value) = *data {
-                    *value -= 1;
-                    if *value == 0 {
-                        debug!("task limit reached, signaling to terminate queue");
-                        shutdown();
-                    }
-                    debug!("task completed, remaining: {}", value);
+                Err(e) => {
+                    tracing::warn!("Queue: cannot listen for SIGTERM: {e}");
+                    std::future::pending::<()>().await
                 }
-            });
-        } else {
-            break;
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate => (),
         }
-    }
+        tracing::info!("Queue: shutdown requested");
+        cancel.cancel();
+    });
+    token
 }
 
-pub fn shutdown() {
-    debug!("Queue: graceful shutdown...");
-    QUEUE.end();
+/// Unix time in milliseconds
+pub(crate) fn now_ms() -> i64 {
+    unix_ms(SystemTime::now())
 }
 
-#[cfg(feature = "test")]
-pub fn start_queue(n: Option<u64>) -> std::thread::JoinHandle<()> {
-    std::thread::spawn(move || {
-        tokio::runtime::Runtime::new()
-            .unwrap()
-            .block_on(async { run_block(n).await });
-    })
+pub(crate) fn unix_ms(at: SystemTime) -> i64 {
+    match at.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use log::info;
-    use tokio::time::sleep;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use serde::{Deserialize, Serialize};
 
     use super::*;
-    use std::time::Duration;
+
+    static COUNTED: AtomicU32 = AtomicU32::new(0);
+
+    #[derive(Serialize, Deserialize)]
+    struct Count {
+        n: u32,
+    }
+
+    impl Job for Count {
+        const NAME: &'static str = "count";
+
+        async fn handle(self, _ctx: JobContext) -> Result<(), JobError> {
+            COUNTED.fetch_add(self.n, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Fail {
+        permanent: bool,
+    }
+
+    impl Job for Fail {
+        const NAME: &'static str = "fail";
+
+        async fn handle(self, _ctx: JobContext) -> Result<(), JobError> {
+            if self.permanent {
+                return Err(JobError::Permanent("bad input".to_string()));
+            }
+            Err("boom".into())
+        }
+
+        fn retry_policy() -> RetryPolicy {
+            RetryPolicy::default()
+                .max_attempts(2)
+                .backoff(Duration::ZERO, Duration::ZERO)
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Export {
+        rows: u32,
+    }
+
+    impl Job for Export {
+        const NAME: &'static str = "export";
+
+        async fn handle(self, ctx: JobContext) -> Result<(), JobError> {
+            ctx.progress(50).await?;
+            ctx.set_result(&self.rows)?;
+            Ok(())
+        }
+    }
 
     #[tokio::test]
     async fn test_queue() {
-        env_logger::builder()
-            .filter_level(log::LevelFilter::Debug)
-            .init();
-        let j = 100_u64;
-        let job = std::thread::spawn(move || {
-            tokio::runtime::Runtime::new()
-                .unwrap()
-                .block_on(async { run_block(Some(j)).await });
-        });
-
-        sleep(Duration::from_secs(2)).await;
-        let n = Arc::new(Mutex::new(0));
-        for i in 0..j {
-            let n_clone = Arc::clone(&n);
-            dispatch(Job {
-                title: "Test".to_string(),
-                handler: Box::pin(async move {
-                    *n_clone.lock().unwrap() += 1;
-                    debug!("Completed task {i}");
-                    Ok(())
-                }),
-            });
+        install(Queue::new(MemoryBackend::default()));
+        for _ in 0..100 {
+            dispatch(&Count { n: 1 }).await.unwrap();
         }
 
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-        let _ = job.join();
-        info!("Tasks .... {:?}", n.lock().unwrap());
+        let worker = Worker::new(&global().unwrap())
+            .register::<Count>()
+            .poll_interval(Duration::from_millis(10));
+        let running = tokio::spawn(worker.run());
+        for _ in 0..100 {
+            if COUNTED.load(Ordering::SeqCst) == 100 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        running.abort();
+        assert_eq!(COUNTED.load(Ordering::SeqCst), 100);
+    }
+
+    #[tokio::test]
+    async fn test_failed_job_is_retried_then_dead() {
+        let queue = Queue::new(MemoryBackend::default());
+        let id = queue.dispatch(&Fail { permanent: false }).await.unwrap();
+
+        let worker = Worker::new(&queue).register::<Fail>();
+        assert_eq!(worker.run_once().await, Ok(true));
+        assert_eq!(worker.run_once().await, Ok(true));
+        assert_eq!(worker.run_once().await, Ok(false));
+
+        let dead = queue.dead_jobs(10).await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].id, id);
+        let errors: Vec<_> = dead[0].errors.iter().map(|e| e.attempt).collect();
+        assert_eq!(errors, [1, 2]);
+
+        assert!(queue.retry_dead(&id).await.unwrap());
+        assert_eq!(worker.run_once().await, Ok(true));
+        assert_eq!(queue.dead_jobs(10).await.unwrap().len(), 0);
+        assert_eq!(worker.run_once().await, Ok(true));
+        assert_eq!(queue.purge_dead(Some(&id)).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_error_is_not_retried() {
+        let queue = Queue::new(MemoryBackend::default());
+        queue.dispatch(&Fail { permanent: true }).await.unwrap();
+
+        let worker = Worker::new(&queue).register::<Fail>();
+        assert_eq!(worker.run_once().await, Ok(true));
+        let dead = queue.dead_jobs(10).await.unwrap();
+        assert_eq!(dead[0].attempts, 1);
+        assert_eq!(dead[0].status, JobStatus::Failed);
+        assert_eq!(dead[0].errors[0].error, "bad input (permanent)");
+    }
+
+    #[tokio::test]
+    async fn test_job_status() {
+        let queue = Queue::new(MemoryBackend::default());
+        let id = queue.dispatch(&Export { rows: 12 }).await.unwrap();
+        let job = queue.job(&id).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Queued);
+
+        let worker = Worker::new(&queue).register::<Export>();
+        assert_eq!(worker.run_once().await, Ok(true));
+        let job = queue.job(&id).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert_eq!(job.progress, Some(50));
+        assert_eq!(job.result.as_deref(), Some("12"));
+        assert!(job.started_at.is_some() && job.finished_at.is_some());
+
+        let filter = JobFilter {
+            status: Some(JobStatus::Succeeded),
+            name: Some("export".to_string()),
+            ..JobFilter::default()
+        };
+        assert_eq!(queue.jobs(&filter).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_changes() {
+        use futures_util::StreamExt;
+
+        let queue = Queue::new(MemoryBackend::default());
+        let changes = queue.changes(Duration::from_millis(5));
+        tokio::pin!(changes);
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let id = queue.dispatch(&Export { rows: 1 }).await.unwrap();
+        let job = changes.next().await.unwrap().unwrap();
+        assert_eq!((job.id, job.status), (id.clone(), JobStatus::Queued));
+
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        Worker::new(&queue)
+            .register::<Export>()
+            .run_once()
+            .await
+            .unwrap();
+        let job = changes.next().await.unwrap().unwrap();
+        assert_eq!((job.id, job.status), (id, JobStatus::Succeeded));
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_in() {
+        let queue = Queue::new(MemoryBackend::default());
+        queue
+            .dispatch_in(&Count { n: 0 }, Duration::from_millis(50))
+            .await
+            .unwrap();
+        let worker = Worker::new(&queue).register::<Count>();
+        assert_eq!(worker.run_once().await, Ok(false));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(worker.run_once().await, Ok(true));
     }
 }
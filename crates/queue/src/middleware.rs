@@ -0,0 +1,350 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures_util::FutureExt;
+use serde::Serialize;
+use tracing::Instrument;
+
+use crate::{worker::Handler, Job, JobContext, JobError, JobRecord};
+
+tokio::task_local! {
+    static CORRELATION_ID: String;
+}
+
+/// Runs `f` with `id` as the correlation id of the jobs it dispatches.
+///
+/// Workers run every job within the correlation id of its own dispatch, so it
+/// follows chains of jobs.
+pub async fn with_correlation_id<F: Future>(id: String, f: F) -> F::Output {
+    CORRELATION_ID.scope(id, f).await
+}
+
+/// Correlation id set by `with_correlation_id` on the current task
+pub fn correlation_id() -> Option<String> {
+    CORRELATION_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Behavior wrapped around every job run by a worker, see `Worker::hoop`.
+///
+/// Middlewares run in the order they were added, each one deciding whether
+/// and how to call the rest of the chain:
+///
+/// ```ignore
+/// struct Audit;
+///
+/// #[async_trait]
+/// impl JobMiddleware for Audit {
+///     async fn handle(&self, job: &JobRecord, ctx: JobContext, next: Next<'_>) -> Result<(), JobError> {
+///         let result = next.run(ctx).await;
+///         audit::record(&job.name, result.is_ok());
+///         result
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait JobMiddleware: Send + Sync + 'static {
+    async fn handle(
+        &self,
+        job: &JobRecord,
+        ctx: JobContext,
+        next: Next<'_>,
+    ) -> Result<(), JobError>;
+}
+
+/// Rest of the middleware chain, ending with the job handler
+pub struct Next<'a> {
+    job: &'a JobRecord,
+    chain: &'a [Arc<dyn JobMiddleware>],
+    handler: &'a Handler,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        job: &'a JobRecord,
+        chain: &'a [Arc<dyn JobMiddleware>],
+        handler: &'a Handler,
+    ) -> Self {
+        Next {
+            job,
+            chain,
+            handler,
+        }
+    }
+
+    pub async fn run(self, ctx: JobContext) -> Result<(), JobError> {
+        match self.chain.split_first() {
+            Some((first, chain)) => {
+                let next = Next { chain, ..self };
+                first.handle(self.job, ctx, next).await
+            }
+            None => (self.handler)(&self.job.payload, ctx).await,
+        }
+    }
+}
+
+/// Runs each job in a `job` span with its name, id, queue, attempt and correlation id
+pub struct Trace;
+
+#[async_trait]
+impl JobMiddleware for Trace {
+    async fn handle(
+        &self,
+        job: &JobRecord,
+        ctx: JobContext,
+        next: Next<'_>,
+    ) -> Result<(), JobError> {
+        let span = tracing::info_span!(
+            "job",
+            name = %job.name,
+            id = %job.id,
+            queue = %job.queue,
+            attempt = job.attempts,
+            correlation_id = job.correlation_id.as_deref().unwrap_or_default(),
+        );
+        next.run(ctx).instrument(span).await
+    }
+}
+
+/// Fails jobs running longer than their timeout with a transient error, so
+/// that they are retried
+pub struct Timeout {
+    default: Duration,
+    jobs: HashMap<&'static str, Duration>,
+}
+
+impl Timeout {
+    pub fn new(default: Duration) -> Self {
+        Timeout {
+            default,
+            jobs: HashMap::new(),
+        }
+    }
+
+    /// Timeout of jobs of type `J`
+    pub fn job<J: Job>(mut self, timeout: Duration) -> Self {
+        self.jobs.insert(J::NAME, timeout);
+        self
+    }
+}
+
+#[async_trait]
+impl JobMiddleware for Timeout {
+    async fn handle(
+        &self,
+        job: &JobRecord,
+        ctx: JobContext,
+        next: Next<'_>,
+    ) -> Result<(), JobError> {
+        let timeout = self
+            .jobs
+            .get(job.name.as_str())
+            .copied()
+            .unwrap_or(self.default);
+        match tokio::time::timeout(timeout, next.run(ctx)).await {
+            Ok(result) => result,
+            Err(_) => Err(JobError::Transient(format!("timed out after {timeout:?}"))),
+        }
+    }
+}
+
+/// Turns a panicking job into a permanent failure instead of leaving it
+/// claimed until its lease expires
+pub struct CatchPanic;
+
+#[async_trait]
+impl JobMiddleware for CatchPanic {
+    async fn handle(
+        &self,
+        _job: &JobRecord,
+        ctx: JobContext,
+        next: Next<'_>,
+    ) -> Result<(), JobError> {
+        match AssertUnwindSafe(next.run(ctx)).catch_unwind().await {
+            Ok(result) => result,
+            Err(panic) => Err(JobError::Permanent(format!(
+                "panicked: {}",
+                panic_message(panic.as_ref())
+            ))),
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message,
+            None => "unknown panic",
+        },
+    }
+}
+
+/// Counters of the runs of one job type
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct JobMetrics {
+    pub succeeded: u64,
+    pub failed: u64,
+    /// Sum of the run durations, in milliseconds
+    pub total_ms: u64,
+    /// Longest run, in milliseconds
+    pub max_ms: u64,
+}
+
+/// Counts runs, failures and durations by job name, cheap to clone
+#[derive(Clone, Default)]
+pub struct Metrics {
+    jobs: Arc<Mutex<HashMap<String, JobMetrics>>>,
+}
+
+impl Metrics {
+    /// Counters so far, by job name
+    pub fn snapshot(&self) -> HashMap<String, JobMetrics> {
+        self.jobs.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl JobMiddleware for Metrics {
+    async fn handle(
+        &self,
+        job: &JobRecord,
+        ctx: JobContext,
+        next: Next<'_>,
+    ) -> Result<(), JobError> {
+        let started = Instant::now();
+        let result = next.run(ctx).await;
+        let elapsed = started.elapsed().as_millis() as u64;
+
+        let mut jobs = self.jobs.lock().unwrap();
+        let metrics = jobs.entry(job.name.clone()).or_default();
+        match result {
+            Ok(()) => metrics.succeeded += 1,
+            Err(_) => metrics.failed += 1,
+        }
+        metrics.total_ms += elapsed;
+        metrics.max_ms = metrics.max_ms.max(elapsed);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{JobStatus, MemoryBackend, Queue, Worker};
+
+    #[derive(Serialize, Deserialize)]
+    struct Flaky {
+        panic: bool,
+        millis: u64,
+    }
+
+    impl Job for Flaky {
+        const NAME: &'static str = "flaky";
+
+        async fn handle(self, _ctx: JobContext) -> Result<(), JobError> {
+            tokio::time::sleep(Duration::from_millis(self.millis)).await;
+            if self.panic {
+                panic!("flaky panicked");
+            }
+            Ok(())
+        }
+
+        fn retry_policy() -> crate::RetryPolicy {
+            crate::RetryPolicy::none()
+        }
+    }
+
+    /// Records the order middlewares run in
+    struct Tag(&'static str, Arc<Mutex<Vec<&'static str>>>);
+
+    #[async_trait]
+    impl JobMiddleware for Tag {
+        async fn handle(
+            &self,
+            _job: &JobRecord,
+            ctx: JobContext,
+            next: Next<'_>,
+        ) -> Result<(), JobError> {
+            self.1.lock().unwrap().push(self.0);
+            next.run(ctx).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chain_order_and_metrics() {
+        let queue = Queue::new(MemoryBackend::default());
+        let order = Arc::new(Mutex::new(vec![]));
+        let metrics = Metrics::default();
+        let worker = Worker::new(&queue)
+            .register::<Flaky>()
+            .hoop(Tag("outer", order.clone()))
+            .hoop(metrics.clone())
+            .hoop(Tag("inner", order.clone()));
+
+        let flaky = Flaky {
+            panic: false,
+            millis: 0,
+        };
+        queue.dispatch(&flaky).await.unwrap();
+        assert_eq!(worker.run_once().await, Ok(true));
+        assert_eq!(*order.lock().unwrap(), ["outer", "inner"]);
+        assert_eq!(metrics.snapshot()["flaky"].succeeded, 1);
+    }
+
+    #[tokio::test]
+    async fn test_panic_and_timeout_fail_the_job() {
+        let queue = Queue::new(MemoryBackend::default());
+        let worker = Worker::new(&queue)
+            .register::<Flaky>()
+            .hoop(CatchPanic)
+            .hoop(Timeout::new(Duration::from_secs(60)).job::<Flaky>(Duration::from_millis(20)));
+
+        let panics = queue
+            .dispatch(&Flaky {
+                panic: true,
+                millis: 0,
+            })
+            .await
+            .unwrap();
+        assert_eq!(worker.run_once().await, Ok(true));
+        let job = queue.job(&panics).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.errors[0].error, "panicked: flaky panicked (permanent)");
+
+        let slow = queue
+            .dispatch(&Flaky {
+                panic: false,
+                millis: 1_000,
+            })
+            .await
+            .unwrap();
+        assert_eq!(worker.run_once().await, Ok(true));
+        let job = queue.job(&slow).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Dead);
+        assert_eq!(job.errors[0].error, "timed out after 20ms");
+    }
+
+    #[tokio::test]
+    async fn test_correlation_id_is_carried() {
+        let queue = Queue::new(MemoryBackend::default());
+        let flaky = Flaky {
+            panic: false,
+            millis: 0,
+        };
+        let id = with_correlation_id("req-1".to_string(), queue.dispatch(&flaky))
+            .await
+            .unwrap();
+        let job = queue.job(&id).await.unwrap().unwrap();
+        assert_eq!(job.correlation_id.as_deref(), Some("req-1"));
+        assert_eq!(correlation_id(), None);
+    }
+}
@@ -0,0 +1,677 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    error::{ErrorKind, WriteFailure},
+    options::{IndexOptions, ReturnDocument},
+    Client, Collection, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    now_ms, Backend, Claimed, JobFailure, JobFilter, JobId, JobRecord, JobStatus, Lease, Priority,
+    QueueError,
+};
+
+/// Job document. The lease fields are only set while the job is running,
+/// `dead_at` is set once it moved to the dead letters
+#[derive(Serialize, Deserialize)]
+struct StoredJob {
+    #[serde(rename = "_id")]
+    id: String,
+    name: String,
+    queue: String,
+    priority: i32,
+    payload: String,
+    attempts: i64,
+    run_at: DateTime,
+    lease_token: Option<String>,
+    lease_expires_at: Option<DateTime>,
+    #[serde(default)]
+    errors: Vec<StoredFailure>,
+    dead_at: Option<DateTime>,
+    status: JobStatus,
+    created_at: DateTime,
+    updated_at: DateTime,
+    started_at: Option<DateTime>,
+    finished_at: Option<DateTime>,
+    progress: Option<i32>,
+    result: Option<String>,
+    unique_key: Option<String>,
+    unique_until: Option<DateTime>,
+    correlation_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredFailure {
+    attempt: i64,
+    error: String,
+    at: DateTime,
+}
+
+impl From<StoredJob> for JobRecord {
+    fn from(job: StoredJob) -> Self {
+        JobRecord {
+            id: job.id.into(),
+            name: job.name,
+            queue: job.queue,
+            priority: Priority::from_level(job.priority),
+            payload: job.payload,
+            attempts: job.attempts as u32,
+            run_at: job.run_at.timestamp_millis(),
+            errors: job
+                .errors
+                .into_iter()
+                .map(|f| JobFailure {
+                    attempt: f.attempt as u32,
+                    error: f.error,
+                    at: f.at.timestamp_millis(),
+                })
+                .collect(),
+            dead_at: job.dead_at.map(|at| at.timestamp_millis()),
+            status: job.status,
+            created_at: job.created_at.timestamp_millis(),
+            updated_at: job.updated_at.timestamp_millis(),
+            started_at: job.started_at.map(|at| at.timestamp_millis()),
+            finished_at: job.finished_at.map(|at| at.timestamp_millis()),
+            progress: job.progress.map(|p| p.clamp(0, 100) as u8),
+            result: job.result,
+            unique_key: job.unique_key,
+            unique_until: job.unique_until.map(|at| at.timestamp_millis()),
+            correlation_id: job.correlation_id,
+        }
+    }
+}
+
+fn failure_doc(failure: JobFailure) -> Document {
+    doc! {
+        "attempt": i64::from(failure.attempt),
+        "error": failure.error,
+        "at": DateTime::from_millis(failure.at),
+    }
+}
+
+/// Filter matching the job held by `lease`
+fn leased(lease: &Lease) -> Document {
+    doc! {
+        "_id": lease.job_id.as_str(),
+        "lease_token": &lease.token,
+        "lease_expires_at": {"$gt": DateTime::from_millis(now_ms())},
+    }
+}
+
+/// Update marking a job that leaves the running status
+fn settled(status: JobStatus) -> Document {
+    doc! {
+        "status": status.as_str(),
+        "lease_token": null,
+        "lease_expires_at": null,
+        "updated_at": DateTime::from_millis(now_ms()),
+    }
+}
+
+async fn collect(
+    cursor: mongodb::error::Result<mongodb::Cursor<StoredJob>>,
+) -> Result<Vec<JobRecord>, QueueError> {
+    let jobs: Vec<StoredJob> = match cursor {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(jobs) => jobs,
+            Err(e) => return Err(backend_error(e)),
+        },
+        Err(e) => return Err(backend_error(e)),
+    };
+    Ok(jobs.into_iter().map(JobRecord::from).collect())
+}
+
+fn backend_error(e: mongodb::error::Error) -> QueueError {
+    QueueError::Backend(e.to_string())
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == 11000
+    )
+}
+
+/// Whether a duplicate key error comes from the `unique_key` index rather than `_id`
+fn is_duplicate_unique_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e))
+            if e.code == 11000 && e.message.contains("unique_key")
+    )
+}
+
+/// Backend storing jobs in a MongoDB collection.
+///
+/// Claims are a single `findOneAndUpdate` that sets a random lease token, so
+/// two workers can never hold the same job, and every later operation on the
+/// job matches on that token. Dead jobs stay in the same collection so that
+/// burying a job is a single atomic update as well. Succeeded jobs are removed
+/// by a TTL index after the retention period. Locks and schedule state live in
+/// the `{collection}_locks` and `{collection}_schedules` collections.
+#[derive(Clone)]
+pub struct MongoBackend {
+    jobs: Collection<StoredJob>,
+    locks: Collection<Document>,
+    schedules: Collection<Document>,
+    retention: Duration,
+}
+
+impl MongoBackend {
+    pub fn new(client: &Client, database: &str, collection: &str) -> Self {
+        let database = client.database(database);
+        MongoBackend {
+            jobs: database.collection(collection),
+            locks: database.collection(&format!("{collection}_locks")),
+            schedules: database.collection(&format!("{collection}_schedules")),
+            retention: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+
+    /// How long succeeded jobs are kept, 7 days by default.
+    ///
+    /// Applied by `ensure_indexes` when it creates the TTL index, changing it
+    /// afterwards requires dropping the `finished_at` index.
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Creates the indexes used by `claim`, `dead`, `list` and `changed`, the
+    /// unique index on uniqueness keys and the TTL index removing succeeded jobs
+    pub async fn ensure_indexes(&self) -> Result<(), QueueError> {
+        let mut indexes: Vec<_> = [
+            doc! {"queue": 1, "status": 1, "priority": -1, "run_at": 1},
+            doc! {"lease_expires_at": 1},
+            doc! {"dead_at": -1},
+            doc! {"status": 1, "updated_at": -1},
+            doc! {"updated_at": 1},
+        ]
+        .into_iter()
+        .map(|keys| IndexModel::builder().keys(keys).build())
+        .collect();
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! {"finished_at": 1})
+                .options(IndexOptions::builder().expire_after(self.retention).build())
+                .build(),
+        );
+        indexes.push(
+            IndexModel::builder()
+                .keys(doc! {"unique_key": 1})
+                .options(
+                    IndexOptions::builder()
+                        .unique(true)
+                        .partial_filter_expression(doc! {"unique_key": {"$type": "string"}})
+                        .build(),
+                )
+                .build(),
+        );
+        match self.jobs.create_indexes(indexes).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for MongoBackend {
+    async fn push(&self, job: JobRecord) -> Result<(), QueueError> {
+        let id = job.id.clone();
+        let key = job.unique_key.clone();
+        let job = StoredJob {
+            id: job.id.to_string(),
+            name: job.name,
+            queue: job.queue,
+            priority: job.priority.level(),
+            payload: job.payload,
+            attempts: i64::from(job.attempts),
+            run_at: DateTime::from_millis(job.run_at),
+            lease_token: None,
+            lease_expires_at: None,
+            errors: job
+                .errors
+                .into_iter()
+                .map(|f| StoredFailure {
+                    attempt: i64::from(f.attempt),
+                    error: f.error,
+                    at: DateTime::from_millis(f.at),
+                })
+                .collect(),
+            dead_at: job.dead_at.map(DateTime::from_millis),
+            status: job.status,
+            created_at: DateTime::from_millis(job.created_at),
+            updated_at: DateTime::from_millis(job.updated_at),
+            started_at: job.started_at.map(DateTime::from_millis),
+            finished_at: job.finished_at.map(DateTime::from_millis),
+            progress: job.progress.map(i32::from),
+            result: job.result,
+            unique_key: job.unique_key,
+            unique_until: job.unique_until.map(DateTime::from_millis),
+            correlation_id: job.correlation_id,
+        };
+        match self.jobs.insert_one(job).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_duplicate_unique_key(&e) => {
+                Err(QueueError::DuplicateKey(key.unwrap_or_default()))
+            }
+            Err(e) if is_duplicate_key(&e) => Err(QueueError::Duplicate(id)),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn holder(&self, key: &str) -> Result<Option<JobRecord>, QueueError> {
+        match self.jobs.find_one(doc! {"unique_key": key}).await {
+            Ok(job) => Ok(job.map(JobRecord::from)),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn unhold(&self, id: &JobId, key: &str) -> Result<bool, QueueError> {
+        let r = self
+            .jobs
+            .update_one(
+                doc! {"_id": id.as_str(), "unique_key": key},
+                doc! {"$set": {"unique_key": null}},
+            )
+            .await;
+        match r {
+            Ok(r) => Ok(r.matched_count == 1),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn merge(&self, id: &JobId, job: &JobRecord) -> Result<bool, QueueError> {
+        let r = self
+            .jobs
+            .update_one(
+                doc! {
+                    "_id": id.as_str(),
+                    "status": {"$in": [JobStatus::Queued.as_str(), JobStatus::Retrying.as_str()]},
+                },
+                doc! {"$set": {
+                    "payload": &job.payload,
+                    "priority": job.priority.level(),
+                    "run_at": DateTime::from_millis(job.run_at),
+                    "unique_until": job.unique_until.map(DateTime::from_millis),
+                    "updated_at": DateTime::from_millis(now_ms()),
+                }},
+            )
+            .await;
+        match r {
+            Ok(r) => Ok(r.matched_count == 1),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn claim(
+        &self,
+        queue: &str,
+        visibility: Duration,
+    ) -> Result<Option<Claimed>, QueueError> {
+        let now = DateTime::from_millis(now_ms());
+        let token = crate::backend::token();
+        let expires_at = now_ms() + visibility.as_millis() as i64;
+        let claimed = self
+            .jobs
+            .find_one_and_update(
+                doc! {"queue": queue, "$or": [
+                    {
+                        "status": {"$in": [JobStatus::Queued.as_str(), JobStatus::Retrying.as_str()]},
+                        "run_at": {"$lte": now},
+                    },
+                    {"status": JobStatus::Running.as_str(), "lease_expires_at": {"$lte": now}},
+                ]},
+                doc! {
+                    "$set": {
+                        "status": JobStatus::Running.as_str(),
+                        "lease_token": &token,
+                        "lease_expires_at": DateTime::from_millis(expires_at),
+                        "started_at": now,
+                        "updated_at": now,
+                        "progress": null,
+                    },
+                    "$inc": {"attempts": 1},
+                },
+            )
+            .sort(doc! {"priority": -1, "run_at": 1})
+            .return_document(ReturnDocument::After)
+            .await;
+        match claimed {
+            Ok(Some(job)) => {
+                let job = JobRecord::from(job);
+                Ok(Some(Claimed {
+                    lease: Lease {
+                        job_id: job.id.clone(),
+                        token,
+                        expires_at,
+                    },
+                    job,
+                }))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn extend(&self, lease: &Lease, visibility: Duration) -> Result<bool, QueueError> {
+        let now = now_ms();
+        let r = self
+            .jobs
+            .update_one(
+                leased(lease),
+                doc! {"$set": {
+                    "lease_expires_at": DateTime::from_millis(now + visibility.as_millis() as i64),
+                }},
+            )
+            .await;
+        match r {
+            Ok(r) => Ok(r.matched_count == 1),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn progress(&self, lease: &Lease, percent: u8) -> Result<bool, QueueError> {
+        let r = self
+            .jobs
+            .update_one(
+                leased(lease),
+                doc! {"$set": {
+                    "progress": i32::from(percent),
+                    "updated_at": DateTime::from_millis(now_ms()),
+                }},
+            )
+            .await;
+        match r {
+            Ok(r) => Ok(r.matched_count == 1),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn complete(&self, lease: &Lease, result: Option<String>) -> Result<bool, QueueError> {
+        let mut set = settled(JobStatus::Succeeded);
+        set.insert("finished_at", DateTime::from_millis(now_ms()));
+        set.insert("result", result);
+        let r = self
+            .jobs
+            .update_one(leased(lease), doc! {"$set": set})
+            .await;
+        match r {
+            Ok(r) => Ok(r.matched_count == 1),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn release(&self, lease: &Lease, run_at: i64) -> Result<bool, QueueError> {
+        let mut set = settled(JobStatus::Queued);
+        set.insert("run_at", DateTime::from_millis(run_at));
+        let r = self
+            .jobs
+            .update_one(leased(lease), doc! {"$set": set, "$inc": {"attempts": -1}})
+            .await;
+        match r {
+            Ok(r) => Ok(r.matched_count == 1),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn retry(
+        &self,
+        lease: &Lease,
+        run_at: i64,
+        failure: JobFailure,
+    ) -> Result<bool, QueueError> {
+        let mut set = settled(JobStatus::Retrying);
+        set.insert("run_at", DateTime::from_millis(run_at));
+        let r = self
+            .jobs
+            .update_one(
+                leased(lease),
+                doc! {"$set": set, "$push": {"errors": failure_doc(failure)}},
+            )
+            .await;
+        match r {
+            Ok(r) => Ok(r.matched_count == 1),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn bury(
+        &self,
+        lease: &Lease,
+        failure: JobFailure,
+        status: JobStatus,
+    ) -> Result<bool, QueueError> {
+        let mut set = settled(status);
+        set.insert("dead_at", DateTime::from_millis(now_ms()));
+        let r = self
+            .jobs
+            .update_one(
+                leased(lease),
+                doc! {"$set": set, "$push": {"errors": failure_doc(failure)}},
+            )
+            .await;
+        match r {
+            Ok(r) => Ok(r.matched_count == 1),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn dead(&self, limit: usize) -> Result<Vec<JobRecord>, QueueError> {
+        let cursor = self
+            .jobs
+            .find(doc! {"dead_at": {"$ne": null}})
+            .sort(doc! {"dead_at": -1})
+            .limit(limit as i64)
+            .await;
+        collect(cursor).await
+    }
+
+    async fn revive(&self, id: &JobId) -> Result<bool, QueueError> {
+        let r = self
+            .jobs
+            .update_one(
+                doc! {"_id": id.as_str(), "dead_at": {"$ne": null}},
+                doc! {"$set": {
+                    "status": JobStatus::Queued.as_str(),
+                    "dead_at": null,
+                    "attempts": 0_i64,
+                    "run_at": DateTime::from_millis(now_ms()),
+                    "progress": null,
+                    "updated_at": DateTime::from_millis(now_ms()),
+                }},
+            )
+            .await;
+        match r {
+            Ok(r) => Ok(r.matched_count == 1),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn purge(&self, id: Option<&JobId>) -> Result<u64, QueueError> {
+        let mut filter = doc! {"dead_at": {"$ne": null}};
+        if let Some(id) = id {
+            filter.insert("_id", id.as_str());
+        }
+        match self.jobs.delete_many(filter).await {
+            Ok(r) => Ok(r.deleted_count),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn get(&self, id: &JobId) -> Result<Option<JobRecord>, QueueError> {
+        match self.jobs.find_one(doc! {"_id": id.as_str()}).await {
+            Ok(job) => Ok(job.map(JobRecord::from)),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn list(&self, filter: &JobFilter) -> Result<Vec<JobRecord>, QueueError> {
+        let mut query = doc! {};
+        if let Some(status) = filter.status {
+            query.insert("status", status.as_str());
+        }
+        if let Some(queue) = &filter.queue {
+            query.insert("queue", queue);
+        }
+        if let Some(name) = &filter.name {
+            query.insert("name", name);
+        }
+        let cursor = self
+            .jobs
+            .find(query)
+            .sort(doc! {"updated_at": -1})
+            .limit(filter.limit as i64)
+            .await;
+        collect(cursor).await
+    }
+
+    async fn changed(&self, since: i64, limit: usize) -> Result<Vec<JobRecord>, QueueError> {
+        let cursor = self
+            .jobs
+            .find(doc! {"updated_at": {"$gte": DateTime::from_millis(since)}})
+            .sort(doc! {"updated_at": 1})
+            .limit(limit as i64)
+            .await;
+        collect(cursor).await
+    }
+
+    async fn lock(&self, name: &str, owner: &str, ttl: Duration) -> Result<bool, QueueError> {
+        let now = now_ms();
+        // matches when free, expired or already ours, otherwise the upsert
+        // collides with the held lock on `_id`
+        let r = self
+            .locks
+            .update_one(
+                doc! {"_id": name, "$or": [
+                    {"owner": owner},
+                    {"expires_at": {"$lte": DateTime::from_millis(now)}},
+                ]},
+                doc! {"$set": {
+                    "owner": owner,
+                    "expires_at": DateTime::from_millis(now + ttl.as_millis() as i64),
+                }},
+            )
+            .upsert(true)
+            .await;
+        match r {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn last_fired(&self, schedule: &str) -> Result<Option<i64>, QueueError> {
+        match self.schedules.find_one(doc! {"_id": schedule}).await {
+            Ok(Some(state)) => match state.get_datetime("last_fired") {
+                Ok(at) => Ok(Some(at.timestamp_millis())),
+                Err(_) => Ok(None),
+            },
+            Ok(None) => Ok(None),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    async fn set_last_fired(&self, schedule: &str, at: i64) -> Result<(), QueueError> {
+        let r = self
+            .schedules
+            .update_one(
+                doc! {"_id": schedule},
+                doc! {"$set": {"last_fired": DateTime::from_millis(at)}},
+            )
+            .upsert(true)
+            .await;
+        match r {
+            Ok(_) => Ok(()),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use testcontainers::runners::AsyncRunner;
+    use testcontainers_modules::mongo::Mongo;
+
+    use super::*;
+    use crate::{JobId, DEFAULT_QUEUE};
+
+    fn record() -> JobRecord {
+        JobRecord {
+            id: JobId::generate(),
+            name: "test".to_string(),
+            queue: DEFAULT_QUEUE.to_string(),
+            priority: Priority::Normal,
+            payload: "null".to_string(),
+            attempts: 0,
+            run_at: now_ms(),
+            errors: vec![],
+            dead_at: None,
+            status: JobStatus::Queued,
+            created_at: now_ms(),
+            updated_at: now_ms(),
+            started_at: None,
+            finished_at: None,
+            progress: None,
+            result: None,
+            unique_key: None,
+            unique_until: None,
+            correlation_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_claims_are_exclusive() {
+        let server = Mongo::default().start().await.unwrap();
+        let host = server.get_host().await.unwrap();
+        let port = server.get_host_port_ipv4(27017).await.unwrap();
+        let client = Client::with_uri_str(format!("mongodb://{host}:{port}/"))
+            .await
+            .unwrap();
+        let backend = MongoBackend::new(&client, "queue_test", "jobs");
+        backend.ensure_indexes().await.unwrap();
+
+        for _ in 0..20 {
+            backend.push(record()).await.unwrap();
+        }
+
+        let claims = (0..30).map(|_| {
+            let backend = backend.clone();
+            tokio::spawn(async move { backend.claim(DEFAULT_QUEUE, Duration::from_secs(30)).await })
+        });
+        let mut ids = HashSet::new();
+        for claim in claims {
+            if let Some(claimed) = claim.await.unwrap().unwrap() {
+                assert!(ids.insert(claimed.job.id.clone()));
+                assert_eq!(claimed.job.attempts, 1);
+            }
+        }
+        assert_eq!(ids.len(), 20);
+
+        // an expired lease is delivered again and the old lease is rejected
+        backend.push(record()).await.unwrap();
+        let first = backend
+            .claim(DEFAULT_QUEUE, Duration::from_millis(100))
+            .await
+            .unwrap()
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let second = backend
+            .claim(DEFAULT_QUEUE, Duration::from_secs(30))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.job.id, first.job.id);
+        assert_eq!(second.job.attempts, 2);
+        assert!(!backend.complete(&first.lease, None).await.unwrap());
+        assert!(backend.complete(&second.lease, None).await.unwrap());
+        let job = backend.get(&second.job.id).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Succeeded);
+    }
+}
@@ -0,0 +1,117 @@
+use async_trait::async_trait;
+use datastore::outbox::{OutboxMessage, OutboxSink};
+
+use crate::{
+    correlation_id, now_ms, Job, JobId, JobRecord, JobStatus, Priority, Queue, QueueError,
+    DEFAULT_QUEUE,
+};
+
+const QUEUE: &str = "queue";
+const PRIORITY: &str = "priority";
+const CORRELATION_ID: &str = "correlation_id";
+
+/// Outbox message dispatching `job` once relayed to a `Queue`.
+///
+/// It keeps the queue and priority of the job, and the current correlation id.
+pub fn outbox_message<J: Job>(job: &J) -> Result<OutboxMessage, QueueError> {
+    let payload = match serde_json::to_string(job) {
+        Ok(p) => p,
+        Err(e) => return Err(QueueError::Payload(e.to_string())),
+    };
+    let message = OutboxMessage::job(J::NAME, payload)
+        .header(QUEUE, J::QUEUE)
+        .header(PRIORITY, job.priority().level().to_string());
+    Ok(match correlation_id() {
+        Some(id) => message.header(CORRELATION_ID, id),
+        None => message,
+    })
+}
+
+/// Dispatches relayed messages as jobs, events being jobs named after their
+/// topic.
+///
+/// The job id is the message id, so a message relayed twice runs once.
+#[async_trait]
+impl OutboxSink for Queue {
+    async fn publish(&self, message: &OutboxMessage) -> Result<(), String> {
+        let header = |key| message.headers.get(key).cloned();
+        let priority = match header(PRIORITY).map(|p| p.parse::<i32>()) {
+            Some(Ok(level)) => Priority::from_level(level),
+            Some(Err(e)) => return Err(format!("invalid priority: {e}")),
+            None => Priority::default(),
+        };
+        let now = now_ms();
+        let record = JobRecord {
+            id: JobId::from(message.id.to_hex()),
+            name: message.name.clone(),
+            queue: header(QUEUE).unwrap_or_else(|| DEFAULT_QUEUE.to_string()),
+            priority,
+            payload: message.payload.clone(),
+            attempts: 0,
+            run_at: now,
+            errors: vec![],
+            dead_at: None,
+            status: JobStatus::Queued,
+            created_at: now,
+            updated_at: now,
+            started_at: None,
+            finished_at: None,
+            progress: None,
+            result: None,
+            unique_key: None,
+            unique_until: None,
+            correlation_id: header(CORRELATION_ID),
+        };
+        match self.backend.push(record).await {
+            Ok(()) | Err(QueueError::Duplicate(_)) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{with_correlation_id, JobContext, JobError, MemoryBackend};
+
+    #[derive(Serialize, Deserialize)]
+    struct Welcome {
+        email: String,
+    }
+
+    impl Job for Welcome {
+        const NAME: &'static str = "welcome";
+        const QUEUE: &'static str = "emails";
+
+        async fn handle(self, _ctx: JobContext) -> Result<(), JobError> {
+            Ok(())
+        }
+
+        fn priority(&self) -> Priority {
+            Priority::High
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relayed_twice_dispatches_once() {
+        let queue = Queue::new(MemoryBackend::default());
+        let welcome = Welcome {
+            email: "acme@gmail.com".to_string(),
+        };
+        let message = with_correlation_id("req-1".to_string(), async { outbox_message(&welcome) })
+            .await
+            .unwrap();
+        queue.publish(&message).await.unwrap();
+        queue.publish(&message).await.unwrap();
+
+        let jobs = queue.jobs(&Default::default()).await.unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].id.as_str(), message.id.to_hex());
+        assert_eq!(jobs[0].queue, "emails");
+        assert_eq!(jobs[0].priority, Priority::High);
+        assert_eq!(jobs[0].payload, r#"{"email":"acme@gmail.com"}"#);
+        assert_eq!(jobs[0].correlation_id.as_deref(), Some("req-1"));
+    }
+}
@@ -0,0 +1,137 @@
+use std::{fmt::Display, time::Duration};
+
+use rand::Rng;
+
+/// Error returned by `Job::handle`.
+///
+/// Any `String` converts to a transient error, so `?` on string errors retries the job.
+#[derive(Debug, PartialEq)]
+pub enum JobError {
+    /// Retried according to the job's `RetryPolicy`
+    Transient(String),
+    /// Retrying cannot help, the job is moved to the dead letters right away
+    Permanent(String),
+}
+
+impl Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transient(e) => f.write_str(e),
+            Self::Permanent(e) => write!(f, "{e} (permanent)"),
+        }
+    }
+}
+
+impl From<String> for JobError {
+    fn from(e: String) -> Self {
+        JobError::Transient(e)
+    }
+}
+
+impl From<&str> for JobError {
+    fn from(e: &str) -> Self {
+        JobError::Transient(e.to_string())
+    }
+}
+
+impl From<crate::QueueError> for JobError {
+    fn from(e: crate::QueueError) -> Self {
+        JobError::Transient(e.to_string())
+    }
+}
+
+/// How often and when a failed job runs again.
+///
+/// The delay before attempt `n + 1` is `base * 2^(n - 1)`, capped at `max_delay`.
+/// With jitter, a random half of it is dropped so that jobs failing together
+/// do not all come back at the same time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base: Duration,
+    max_delay: Duration,
+    jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60 * 60),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Runs the job once, failures go to the dead letters
+    pub fn none() -> Self {
+        Self::default().max_attempts(1)
+    }
+
+    /// Total number of runs, including the first one
+    pub fn max_attempts(mut self, n: u32) -> Self {
+        self.max_attempts = n.max(1);
+        self
+    }
+
+    pub fn backoff(mut self, base: Duration, max_delay: Duration) -> Self {
+        self.base = base;
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Whether a job that failed on `attempt` may run again
+    pub fn should_retry(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+
+    /// Delay before running again a job that failed on `attempt`, counted from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self.base.saturating_mul(1 << exponent).min(self.max_delay);
+        if !self.jitter {
+            return delay;
+        }
+        let half = delay / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exponential_backoff() {
+        let policy = RetryPolicy::default()
+            .backoff(Duration::from_secs(1), Duration::from_secs(10))
+            .jitter(false);
+        let delays: Vec<_> = (1..=5).map(|n| policy.delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10]);
+        assert_eq!(policy.delay(1000), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_jitter_stays_within_half() {
+        let policy = RetryPolicy::default().backoff(Duration::from_secs(4), Duration::MAX);
+        for _ in 0..100 {
+            let delay = policy.delay(2);
+            assert!(delay >= Duration::from_secs(4) && delay <= Duration::from_secs(8));
+        }
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let policy = RetryPolicy::default().max_attempts(3);
+        assert!(policy.should_retry(2));
+        assert!(!policy.should_retry(3));
+        assert!(!RetryPolicy::none().should_retry(1));
+    }
+}
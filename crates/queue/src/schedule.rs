@@ -0,0 +1,293 @@
+use std::{collections::VecDeque, str::FromStr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, TimeZone, Utc};
+use tokio::time::{interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+use crate::{backend::token, now_ms, Backend, Job, JobId, JobRecord, Queue, QueueError};
+
+/// Name of the lock held by the leading scheduler
+const LEADER_LOCK: &str = "scheduler";
+
+/// What to do with runs missed while no scheduler was running.
+///
+/// A run is missed when it is older than the scheduler lock TTL, so a leader
+/// change alone never counts as downtime.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CatchUp {
+    /// Drop missed runs
+    Skip,
+    /// Run once for all the missed runs
+    Once,
+    /// Run each missed run, at most the given number of most recent ones
+    All(usize),
+}
+
+struct Entry {
+    name: String,
+    schedule: cron::Schedule,
+    job: JobRecord,
+    catch_up: CatchUp,
+}
+
+impl Entry {
+    /// Run times after `last` and up to `now` to dispatch, oldest first
+    fn due(&self, last: i64, now: i64, grace: Duration) -> Vec<i64> {
+        let recent_from = last.max(now - grace.as_millis() as i64);
+        let keep = match self.catch_up {
+            CatchUp::Skip => 0,
+            CatchUp::Once => 1,
+            CatchUp::All(n) => n,
+        };
+        let mut missed = VecDeque::new();
+        if keep > 0 {
+            for at in self.runs(last, recent_from) {
+                if missed.len() == keep {
+                    missed.pop_front();
+                }
+                missed.push_back(at);
+            }
+        }
+        missed
+            .into_iter()
+            .chain(self.runs(recent_from, now))
+            .collect()
+    }
+
+    /// Run times after `from` and up to `to`
+    fn runs(&self, from: i64, to: i64) -> impl Iterator<Item = i64> + '_ {
+        let from = Utc
+            .timestamp_millis_opt(from)
+            .single()
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        self.schedule
+            .after(&from)
+            .map(|at| at.timestamp_millis())
+            .take_while(move |at| *at <= to)
+    }
+}
+
+/// Dispatches recurring jobs from cron expressions.
+///
+/// Every instance may run a scheduler: they elect a leader through a lock in the
+/// backend and only the leader dispatches. Each run is pushed with an id made
+/// of the schedule name and the run time, so a run dispatched twice around a
+/// leader change is stored once.
+///
+/// Expressions have six or seven fields, `sec min hour day month weekday [year]`,
+/// and are evaluated in UTC:
+///
+/// ```ignore
+/// Scheduler::new(&queue)
+///     .add("nightly_payout", "0 0 2 * * *", NightlyPayout, CatchUp::Once)?
+///     .run()
+///     .await;
+/// ```
+pub struct Scheduler {
+    backend: Arc<dyn Backend>,
+    entries: Vec<Entry>,
+    owner: String,
+    interval: Duration,
+    lock_ttl: Duration,
+}
+
+impl Scheduler {
+    pub fn new(queue: &Queue) -> Self {
+        Scheduler {
+            backend: queue.backend.clone(),
+            entries: vec![],
+            owner: token(),
+            interval: Duration::from_secs(1),
+            lock_ttl: Duration::from_secs(30),
+        }
+    }
+
+    /// Dispatches `job` at every time matching `expression`.
+    ///
+    /// `name` identifies the schedule across restarts and instances.
+    pub fn add<J: Job>(
+        mut self,
+        name: &str,
+        expression: &str,
+        job: J,
+        catch_up: CatchUp,
+    ) -> Result<Self, QueueError> {
+        let schedule = match cron::Schedule::from_str(expression) {
+            Ok(s) => s,
+            Err(e) => return Err(QueueError::InvalidSchedule(format!("{expression:?}: {e}"))),
+        };
+        self.entries.push(Entry {
+            name: name.to_string(),
+            schedule,
+            job: JobRecord::new(&job)?,
+            catch_up,
+        });
+        Ok(self)
+    }
+
+    /// How often schedules are checked
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long the leader keeps the lock without renewing it.
+    ///
+    /// Bounds the time without a scheduler when the leader stops.
+    pub fn lock_ttl(mut self, ttl: Duration) -> Self {
+        self.lock_ttl = ttl;
+        self
+    }
+
+    /// Checks schedules every interval until the task is dropped
+    pub async fn run(self) {
+        self.run_until(CancellationToken::new()).await
+    }
+
+    /// Checks schedules every interval until `shutdown` is cancelled
+    pub async fn run_until(self, shutdown: CancellationToken) {
+        let mut ticker = interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = ticker.tick() => (),
+            }
+            if let Err(e) = self.fire(now_ms()).await {
+                error!("Queue: scheduler failed: {e}");
+            }
+        }
+    }
+
+    /// Dispatches the runs due at `now` if this scheduler leads, returns how many were dispatched
+    pub(crate) async fn fire(&self, now: i64) -> Result<usize, QueueError> {
+        if !self
+            .backend
+            .lock(LEADER_LOCK, &self.owner, self.lock_ttl)
+            .await?
+        {
+            return Ok(0);
+        }
+
+        let mut dispatched = 0;
+        for entry in &self.entries {
+            let last = match self.backend.last_fired(&entry.name).await? {
+                Some(last) => last,
+                None => {
+                    // new schedule, nothing was missed
+                    self.backend.set_last_fired(&entry.name, now).await?;
+                    continue;
+                }
+            };
+            let due = entry.due(last, now, self.lock_ttl);
+            for &at in &due {
+                let mut job = entry.job.clone();
+                job.id = JobId::from(format!("{}@{at}", entry.name));
+                job.run_at = at;
+                job.created_at = now;
+                job.updated_at = now;
+                match self.backend.push(job).await {
+                    Ok(()) => dispatched += 1,
+                    Err(QueueError::Duplicate(id)) => debug!("Queue: {id} already dispatched"),
+                    Err(e) => return Err(e),
+                }
+            }
+            // skipped runs are settled too
+            if entry.runs(last, now).next().is_some() {
+                self.backend.set_last_fired(&entry.name, now).await?;
+            }
+        }
+        Ok(dispatched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{JobContext, JobError, MemoryBackend, DEFAULT_QUEUE};
+
+    /// A whole minute, in milliseconds
+    const MINUTE: i64 = 1_699_999_980_000;
+
+    #[derive(Serialize, Deserialize)]
+    struct Payout;
+
+    impl Job for Payout {
+        const NAME: &'static str = "payout";
+
+        async fn handle(self, _ctx: JobContext) -> Result<(), JobError> {
+            Ok(())
+        }
+    }
+
+    async fn missed(catch_up: CatchUp) -> usize {
+        let queue = Queue::new(MemoryBackend::default());
+        let scheduler = Scheduler::new(&queue)
+            .add("payout", "0 * * * * *", Payout, catch_up)
+            .unwrap();
+        // down for 10 minutes, the last one is not missed yet
+        queue
+            .backend
+            .set_last_fired("payout", MINUTE - 600_000)
+            .await
+            .unwrap();
+        scheduler.fire(MINUTE + 10_000).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_catch_up() {
+        assert_eq!(missed(CatchUp::Skip).await, 1);
+        assert_eq!(missed(CatchUp::Once).await, 2);
+        assert_eq!(missed(CatchUp::All(3)).await, 4);
+        assert_eq!(missed(CatchUp::All(100)).await, 10);
+    }
+
+    #[tokio::test]
+    async fn test_runs_once_across_schedulers() {
+        let queue = Queue::new(MemoryBackend::default());
+        let schedulers: Vec<_> = (0..3)
+            .map(|_| {
+                Scheduler::new(&queue)
+                    .add("payout", "0 * * * * *", Payout, CatchUp::Skip)
+                    .unwrap()
+            })
+            .collect();
+        for scheduler in &schedulers {
+            assert_eq!(scheduler.fire(MINUTE - 30_000).await.unwrap(), 0);
+        }
+
+        let mut dispatched = 0;
+        for scheduler in &schedulers {
+            dispatched += scheduler.fire(MINUTE + 1_000).await.unwrap();
+        }
+        assert_eq!(dispatched, 1);
+
+        // a run dispatched again is dropped
+        let leader = &schedulers[0];
+        queue
+            .backend
+            .set_last_fired("payout", MINUTE - 1_000)
+            .await
+            .unwrap();
+        assert_eq!(leader.fire(MINUTE + 2_000).await.unwrap(), 0);
+
+        let claimed = queue
+            .backend
+            .claim(DEFAULT_QUEUE, Duration::from_secs(30))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.job.id.as_str(), format!("payout@{MINUTE}"));
+        assert_eq!(claimed.job.run_at, MINUTE);
+    }
+
+    #[test]
+    fn test_invalid_expression() {
+        let queue = Queue::new(MemoryBackend::default());
+        let r = Scheduler::new(&queue).add("payout", "every night", Payout, CatchUp::Skip);
+        assert!(matches!(r, Err(QueueError::InvalidSchedule(_))));
+    }
+}
@@ -0,0 +1,291 @@
+use std::time::Duration;
+
+use crate::{now_ms, Backend, JobId, JobRecord, JobStatus, QueueError};
+
+/// Tries before giving up on a key contended by concurrent dispatches
+const ATTEMPTS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Drop,
+    Replace,
+    Debounce,
+    Throttle,
+}
+
+/// Deduplicates dispatches of the same work, see `Queue::dispatch_unique`.
+///
+/// Jobs dispatched with the same key share a single job: the dispatch returns
+/// the id of the job that will do the work, which may be an earlier one. Keys
+/// are global across job types, so they should name the job, for example
+/// `capture_payment:{order_id}`.
+///
+/// ```ignore
+/// // a webhook delivered twice captures once
+/// queue.dispatch_unique(&capture, Unique::new(format!("capture:{order}"), Duration::from_secs(3600))).await?;
+/// // reindex a product at most once per 10s
+/// queue.dispatch_unique(&reindex, Unique::throttle(format!("reindex:{product}"), Duration::from_secs(10))).await?;
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unique {
+    key: String,
+    period: Duration,
+    mode: Mode,
+}
+
+impl Unique {
+    /// Drops the dispatch while a job with `key` is pending or running, for at most `window`
+    pub fn new(key: impl Into<String>, window: Duration) -> Self {
+        Unique {
+            key: key.into(),
+            period: window,
+            mode: Mode::Drop,
+        }
+    }
+
+    /// Like `new`, but a job with `key` that has not started yet takes the
+    /// payload and run time of the new dispatch
+    pub fn replace(key: impl Into<String>, window: Duration) -> Self {
+        Unique {
+            mode: Mode::Replace,
+            ..Self::new(key, window)
+        }
+    }
+
+    /// Runs the job once `quiet` has passed without another dispatch with
+    /// `key`, with the payload of the last one
+    pub fn debounce(key: impl Into<String>, quiet: Duration) -> Self {
+        Unique {
+            mode: Mode::Debounce,
+            ..Self::new(key, quiet)
+        }
+    }
+
+    /// Starts jobs with `key` at most once per `period`. A dispatch made once
+    /// the job started runs again when the period is over, the ones made
+    /// before it started are dropped.
+    pub fn throttle(key: impl Into<String>, period: Duration) -> Self {
+        Unique {
+            mode: Mode::Throttle,
+            ..Self::new(key, period)
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+/// Pushes `job` under `unique`, returns the id of the job that will run
+pub(crate) async fn push(
+    backend: &dyn Backend,
+    mut job: JobRecord,
+    unique: &Unique,
+) -> Result<JobId, QueueError> {
+    let now = now_ms();
+    let period = unique.period.as_millis() as i64;
+    job.unique_key = Some(unique.key.clone());
+    match unique.mode {
+        Mode::Debounce => {
+            job.run_at = job.run_at.max(now + period);
+            job.unique_until = Some(job.run_at);
+        }
+        _ => job.unique_until = Some(now + period),
+    }
+
+    for _ in 0..ATTEMPTS {
+        match backend.push(job.clone()).await {
+            Ok(()) => return Ok(job.id),
+            Err(QueueError::DuplicateKey(_)) => (),
+            Err(e) => return Err(e),
+        }
+        let holder = match backend.holder(&unique.key).await? {
+            Some(holder) => holder,
+            None => continue,
+        };
+        let expired = holder.unique_until.is_none_or(|until| until <= now);
+        let waiting = matches!(holder.status, JobStatus::Queued | JobStatus::Retrying);
+        let running = holder.status == JobStatus::Running;
+        match unique.mode {
+            _ if expired => (),
+            Mode::Drop if waiting || running => return Ok(holder.id),
+            Mode::Replace if running => return Ok(holder.id),
+            Mode::Replace | Mode::Debounce if waiting => {
+                if backend.merge(&holder.id, &job).await? {
+                    return Ok(holder.id);
+                }
+                // claimed in the meantime
+                continue;
+            }
+            Mode::Throttle if waiting => return Ok(holder.id),
+            Mode::Throttle => {
+                // started within the period, run again once it is over
+                let until = holder.unique_until.unwrap_or(now);
+                job.run_at = job.run_at.max(until);
+                job.unique_until = Some(job.run_at + period);
+            }
+            // finished, the key is free
+            _ => (),
+        }
+        backend.unhold(&holder.id, &unique.key).await?;
+    }
+    Err(QueueError::Backend(format!(
+        "too many concurrent dispatches with the key {:?}",
+        unique.key
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{Job, JobContext, JobError, MemoryBackend, Queue, DEFAULT_QUEUE};
+
+    #[derive(Serialize, Deserialize)]
+    struct Reindex {
+        version: u32,
+    }
+
+    impl Job for Reindex {
+        const NAME: &'static str = "reindex";
+
+        async fn handle(self, _ctx: JobContext) -> Result<(), JobError> {
+            Ok(())
+        }
+    }
+
+    async fn run(queue: &Queue) -> Option<JobRecord> {
+        let claimed = queue
+            .backend
+            .claim(DEFAULT_QUEUE, Duration::from_secs(30))
+            .await
+            .unwrap()?;
+        queue.backend.complete(&claimed.lease, None).await.unwrap();
+        Some(claimed.job)
+    }
+
+    #[tokio::test]
+    async fn test_unique_drops_while_pending_or_running() {
+        let queue = Queue::new(MemoryBackend::default());
+        let unique = Unique::new("reindex:1", Duration::from_secs(60));
+        let first = queue
+            .dispatch_unique(&Reindex { version: 1 }, unique.clone())
+            .await
+            .unwrap();
+        let second = queue
+            .dispatch_unique(&Reindex { version: 2 }, unique.clone())
+            .await
+            .unwrap();
+        assert_eq!(second, first);
+
+        let claimed = queue
+            .backend
+            .claim(DEFAULT_QUEUE, Duration::from_secs(30))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.job.payload, r#"{"version":1}"#);
+        let running = queue
+            .dispatch_unique(&Reindex { version: 3 }, unique.clone())
+            .await
+            .unwrap();
+        assert_eq!(running, first);
+
+        // once it finished, the key is free again
+        queue.backend.complete(&claimed.lease, None).await.unwrap();
+        let next = queue
+            .dispatch_unique(&Reindex { version: 4 }, unique)
+            .await
+            .unwrap();
+        assert_ne!(next, first);
+    }
+
+    #[tokio::test]
+    async fn test_unique_key_expires() {
+        let queue = Queue::new(MemoryBackend::default());
+        let unique = Unique::new("reindex:1", Duration::from_millis(20));
+        let first = queue
+            .dispatch_unique(&Reindex { version: 1 }, unique.clone())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let second = queue
+            .dispatch_unique(&Reindex { version: 2 }, unique)
+            .await
+            .unwrap();
+        assert_ne!(second, first);
+        assert_eq!(
+            queue.backend.holder("reindex:1").await.unwrap().unwrap().id,
+            second
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replace_merges_into_waiting_job() {
+        let queue = Queue::new(MemoryBackend::default());
+        let unique = Unique::replace("reindex:1", Duration::from_secs(60));
+        let first = queue
+            .dispatch_unique(&Reindex { version: 1 }, unique.clone())
+            .await
+            .unwrap();
+        let second = queue
+            .dispatch_unique(&Reindex { version: 2 }, unique)
+            .await
+            .unwrap();
+        assert_eq!(second, first);
+        let job = run(&queue).await.unwrap();
+        assert_eq!(job.payload, r#"{"version":2}"#);
+        assert!(run(&queue).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_debounce_runs_last_after_quiet() {
+        let queue = Queue::new(MemoryBackend::default());
+        let unique = Unique::debounce("reindex:1", Duration::from_millis(40));
+        for version in 1..=3 {
+            queue
+                .dispatch_unique(&Reindex { version }, unique.clone())
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert!(run(&queue).await.is_none());
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let job = run(&queue).await.unwrap();
+        assert_eq!(job.payload, r#"{"version":3}"#);
+        assert!(run(&queue).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_throttle_runs_again_after_period() {
+        let queue = Queue::new(MemoryBackend::default());
+        let unique = Unique::throttle("reindex:1", Duration::from_millis(50));
+        let first = queue
+            .dispatch_unique(&Reindex { version: 1 }, unique.clone())
+            .await
+            .unwrap();
+        // not started yet, it will see the latest state
+        let dropped = queue
+            .dispatch_unique(&Reindex { version: 2 }, unique.clone())
+            .await
+            .unwrap();
+        assert_eq!(dropped, first);
+        assert_eq!(run(&queue).await.unwrap().id, first);
+
+        // changed once it ran, runs again when the period is over
+        let trailing = queue
+            .dispatch_unique(&Reindex { version: 3 }, unique.clone())
+            .await
+            .unwrap();
+        assert_ne!(trailing, first);
+        let again = queue
+            .dispatch_unique(&Reindex { version: 4 }, unique)
+            .await
+            .unwrap();
+        assert_eq!(again, trailing);
+        assert!(run(&queue).await.is_none());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(run(&queue).await.unwrap().id, trailing);
+    }
+}
@@ -0,0 +1,605 @@
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use futures_util::FutureExt;
+use tokio::{
+    sync::Semaphore,
+    task::JoinSet,
+    time::{interval_at, sleep, timeout, Instant},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    health::{Guard, WorkerState},
+    middleware::Next,
+    now_ms, with_correlation_id, Backend, Claimed, Job, JobContext, JobError, JobFailure,
+    JobMiddleware, JobRecord, JobStatus, Lease, Queue, QueueError, RetryPolicy, WorkerHealth,
+    DEFAULT_QUEUE,
+};
+
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<(), JobError>> + Send>>;
+pub(crate) type Handler = Arc<dyn Fn(&str, JobContext) -> HandlerFuture + Send + Sync>;
+
+/// Limits applied by a worker to one of its queues
+#[derive(Clone, Debug)]
+pub struct QueueOptions {
+    concurrency: usize,
+    rate_limit: Option<(u32, Duration)>,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions {
+            concurrency: 16,
+            rate_limit: None,
+        }
+    }
+}
+
+impl QueueOptions {
+    /// Maximum number of jobs of the queue running at once
+    pub fn concurrency(mut self, n: usize) -> Self {
+        self.concurrency = n.max(1);
+        self
+    }
+
+    /// Starts at most `max` jobs of the queue every `per`, in bursts of up to `max`
+    pub fn rate_limit(mut self, max: u32, per: Duration) -> Self {
+        self.rate_limit = Some((max.max(1), per));
+        self
+    }
+}
+
+/// Token bucket refilled continuously at `max` tokens per `per`
+struct RateLimiter {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    fn new(max: u32, per: Duration) -> Self {
+        RateLimiter {
+            capacity: f64::from(max),
+            per_second: f64::from(max) / per.as_secs_f64().max(f64::EPSILON),
+            tokens: f64::from(max),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.refilled = now;
+    }
+
+    /// Waits until a token is available, without taking it
+    async fn ready(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            sleep(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.per_second,
+            ))
+            .await;
+            self.refill();
+        }
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+/// Claims jobs from a backend and runs the registered handlers.
+///
+/// A worker subscribes to a set of named queues, each with its own limits, or
+/// to `DEFAULT_QUEUE` when none is given. Limits apply to this worker only.
+///
+/// ```ignore
+/// Worker::new(&queue)
+///     .register::<SendWelcomeEmail>()
+///     .register::<CapturePayment>()
+///     .subscribe("emails", QueueOptions::default().rate_limit(10, Duration::from_secs(1)))
+///     .subscribe("payments", QueueOptions::default().concurrency(2))
+///     .run()
+///     .await;
+/// ```
+pub struct Worker {
+    backend: Arc<dyn Backend>,
+    handlers: HashMap<&'static str, (Handler, RetryPolicy)>,
+    middlewares: Vec<Arc<dyn JobMiddleware>>,
+    queues: Vec<(String, QueueOptions)>,
+    visibility: Duration,
+    poll_interval: Duration,
+    shutdown_timeout: Duration,
+    health: WorkerHealth,
+}
+
+/// How a claimed job ended on this worker
+enum Outcome {
+    Finished(Result<(), JobError>),
+    LeaseLost,
+    /// Stopped by a shutdown past its deadline
+    Aborted,
+}
+
+impl Worker {
+    pub fn new(queue: &Queue) -> Self {
+        Worker {
+            backend: queue.backend.clone(),
+            handlers: HashMap::new(),
+            middlewares: vec![],
+            queues: vec![],
+            visibility: Duration::from_secs(30),
+            poll_interval: Duration::from_secs(1),
+            shutdown_timeout: Duration::from_secs(30),
+            health: WorkerHealth::default(),
+        }
+    }
+
+    /// Runs jobs of type `J` in this worker
+    pub fn register<J: Job>(mut self) -> Self {
+        let handler: Handler = Arc::new(|payload, ctx| {
+            let job = serde_json::from_str::<J>(payload);
+            Box::pin(async move {
+                match job {
+                    Ok(job) => job.handle(ctx).await,
+                    Err(e) => Err(JobError::Permanent(format!("invalid payload: {e}"))),
+                }
+            })
+        });
+        self.handlers.insert(J::NAME, (handler, J::retry_policy()));
+        self
+    }
+
+    /// Wraps every job run by this worker in `middleware`, the first one added
+    /// running outermost
+    pub fn hoop(mut self, middleware: impl JobMiddleware) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// How long a claimed job stays hidden from other workers without a heartbeat.
+    ///
+    /// The lease is extended every half period while the job runs, so this only
+    /// bounds how long a crashed worker delays its jobs.
+    pub fn visibility_timeout(mut self, timeout: Duration) -> Self {
+        self.visibility = timeout;
+        self
+    }
+
+    /// Wait between claims when the queue is empty
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Runs jobs of `queue` in this worker
+    pub fn subscribe(mut self, queue: &str, options: QueueOptions) -> Self {
+        self.queues.push((queue.to_string(), options));
+        self
+    }
+
+    /// How long a shutdown waits for running jobs before releasing them
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Handle reporting the liveness and readiness of this worker once it runs
+    pub fn health(&self) -> WorkerHealth {
+        self.health.clone()
+    }
+
+    /// Claims and runs jobs until the task is dropped
+    pub async fn run(self) {
+        self.run_until(CancellationToken::new()).await
+    }
+
+    /// Claims and runs jobs until `shutdown` is cancelled, see `shutdown_signal`.
+    ///
+    /// Once cancelled, no job is claimed anymore and running jobs get the
+    /// shutdown timeout to finish. Jobs still running after it are stopped and
+    /// released, claimable again right away without counting the attempt.
+    pub async fn run_until(mut self, shutdown: CancellationToken) {
+        if self.queues.is_empty() {
+            self.queues
+                .push((DEFAULT_QUEUE.to_string(), QueueOptions::default()));
+        }
+        let worker = Arc::new(self);
+        let health = worker.health.clone();
+        health.set_queues(worker.queues.len());
+        let running = TaskTracker::new();
+        let abort = CancellationToken::new();
+        let mut loops = JoinSet::new();
+        for i in 0..worker.queues.len() {
+            let (shutdown, running, abort) = (shutdown.clone(), running.clone(), abort.clone());
+            let guard = health.queue_loop();
+            loops.spawn(worker.clone().run_queue(i, shutdown, running, abort, guard));
+        }
+        health.set_state(WorkerState::Running);
+        while let Some(r) = loops.join_next().await {
+            if let Err(e) = r {
+                error!("Queue: a queue loop stopped: {e}");
+            }
+        }
+
+        health.set_state(WorkerState::Stopping);
+        running.close();
+        info!("Queue: worker stopping, waiting for {} jobs", running.len());
+        if timeout(worker.shutdown_timeout, running.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Queue: {} jobs still running after {:?}, releasing them",
+                running.len(),
+                worker.shutdown_timeout
+            );
+            abort.cancel();
+            running.wait().await;
+        }
+        health.set_state(WorkerState::Stopped);
+        info!("Queue: worker stopped");
+    }
+
+    async fn run_queue(
+        self: Arc<Self>,
+        i: usize,
+        shutdown: CancellationToken,
+        running: TaskTracker,
+        abort: CancellationToken,
+        _loop: Guard,
+    ) {
+        let (queue, options) = &self.queues[i];
+        let slots = Arc::new(Semaphore::new(options.concurrency));
+        let mut limiter = options
+            .rate_limit
+            .map(|(max, per)| RateLimiter::new(max, per));
+        loop {
+            let slot = tokio::select! {
+                _ = shutdown.cancelled() => return,
+                slot = slots.clone().acquire_owned() => match slot {
+                    Ok(slot) => slot,
+                    Err(_) => return,
+                },
+            };
+            if let Some(limiter) = limiter.as_mut() {
+                tokio::select! {
+                    _ = shutdown.cancelled() => return,
+                    _ = limiter.ready() => (),
+                }
+            }
+            let wait = match self.backend.claim(queue, self.visibility).await {
+                Ok(Some(claimed)) => {
+                    self.health.claimed(true);
+                    if let Some(limiter) = limiter.as_mut() {
+                        limiter.take();
+                    }
+                    let (worker, abort) = (self.clone(), abort.clone());
+                    let job = self.health.job();
+                    running.spawn(async move {
+                        worker.process(claimed, &abort).await;
+                        drop((slot, job));
+                    });
+                    continue;
+                }
+                Ok(None) => {
+                    self.health.claimed(true);
+                    self.poll_interval
+                }
+                Err(e) => {
+                    self.health.claimed(false);
+                    error!("Queue: claim from {queue} failed: {e}");
+                    self.poll_interval
+                }
+            };
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = sleep(wait) => (),
+            }
+        }
+    }
+
+    /// Claims and runs a single job of the subscribed queues on the current
+    /// task, ignoring their limits. Returns whether a job was claimed.
+    pub async fn run_once(&self) -> Result<bool, QueueError> {
+        let mut queues: Vec<_> = self.queues.iter().map(|(q, _)| q.as_str()).collect();
+        if queues.is_empty() {
+            queues.push(DEFAULT_QUEUE);
+        }
+        for queue in queues {
+            if let Some(claimed) = self.backend.claim(queue, self.visibility).await? {
+                self.process(claimed, &CancellationToken::new()).await;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn process(&self, claimed: Claimed, abort: &CancellationToken) {
+        let Claimed { job, lease } = claimed;
+        let ctx = JobContext::new(&job, lease.clone(), self.backend.clone());
+        let unknown = RetryPolicy::default();
+        let (handler, policy) = match self.handlers.get(job.name.as_str()) {
+            Some((handler, policy)) => (Some(handler), policy),
+            None => (None, &unknown),
+        };
+        let outcome = match handler {
+            // retried in case a worker that knows the job picks it up, as during a deploy
+            None => Outcome::Finished(Err(JobError::Transient(format!(
+                "no handler for job {}",
+                job.name
+            )))),
+            // claims past the policy were never settled, the process running them died
+            Some(_) if !policy.should_retry(job.attempts.saturating_sub(1)) => {
+                Outcome::Finished(Err(JobError::Transient(format!(
+                    "abandoned by the worker running it on attempt {}",
+                    job.attempts - 1
+                ))))
+            }
+            Some(handler) => {
+                self.execute(&job, &lease, handler, ctx.clone(), abort)
+                    .await
+            }
+        };
+
+        let done = match outcome {
+            Outcome::Finished(Ok(())) => {
+                debug!("Queue: job {} ({}) completed", job.name, job.id);
+                self.backend.complete(&lease, ctx.take_result()).await
+            }
+            Outcome::Finished(Err(e)) => {
+                let failure = JobFailure {
+                    attempt: job.attempts,
+                    error: e.to_string(),
+                    at: now_ms(),
+                };
+                let permanent = matches!(e, JobError::Permanent(_));
+                if !permanent && policy.should_retry(job.attempts) {
+                    let delay = policy.delay(job.attempts);
+                    warn!(
+                        "Queue: job {} ({}) failed on attempt {}, retrying in {delay:?}: {e}",
+                        job.name, job.id, job.attempts
+                    );
+                    let run_at = now_ms() + delay.as_millis() as i64;
+                    self.backend.retry(&lease, run_at, failure).await
+                } else {
+                    error!(
+                        "Queue: job {} ({}) failed on attempt {}, moved to the dead letters: {e}",
+                        job.name, job.id, job.attempts
+                    );
+                    let status = match permanent {
+                        true => JobStatus::Failed,
+                        false => JobStatus::Dead,
+                    };
+                    self.backend.bury(&lease, failure, status).await
+                }
+            }
+            Outcome::Aborted => {
+                warn!("Queue: job {} ({}) stopped by shutdown", job.name, job.id);
+                self.backend.release(&lease, now_ms()).await
+            }
+            Outcome::LeaseLost => Ok(false),
+        };
+        match done {
+            Ok(true) => (),
+            Ok(false) => warn!(
+                "Queue: lease of job {} ({}) was lost, it will run again",
+                job.name, job.id
+            ),
+            Err(e) => error!("Queue: could not settle job {}: {e}", job.id),
+        }
+    }
+
+    /// Runs `job` through the middlewares, extending its lease until it ends
+    async fn execute(
+        &self,
+        job: &JobRecord,
+        lease: &Lease,
+        handler: &Handler,
+        ctx: JobContext,
+        abort: &CancellationToken,
+    ) -> Outcome {
+        // jobs dispatched by this one share its correlation id
+        let run = Next::new(job, &self.middlewares, handler).run(ctx);
+        let run = match job.correlation_id.clone() {
+            Some(id) => with_correlation_id(id, run).boxed(),
+            None => run.boxed(),
+        };
+        tokio::pin!(run);
+        let period = self.visibility / 2;
+        let mut heartbeat = interval_at(Instant::now() + period, period);
+        loop {
+            tokio::select! {
+                result = &mut run => break Outcome::Finished(result),
+                _ = abort.cancelled() => break Outcome::Aborted,
+                _ = heartbeat.tick() => match self.backend.extend(lease, self.visibility).await {
+                    Ok(true) => (),
+                    Ok(false) => break Outcome::LeaseLost,
+                    Err(e) => warn!("Queue: could not extend the lease of job {}: {e}", job.id),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::MemoryBackend;
+
+    static RUNNING: AtomicUsize = AtomicUsize::new(0);
+    static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+    static SENT: AtomicUsize = AtomicUsize::new(0);
+    static REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Serialize, Deserialize)]
+    struct Capture;
+
+    impl Job for Capture {
+        const NAME: &'static str = "capture";
+        const QUEUE: &'static str = "payments";
+
+        async fn handle(self, _ctx: JobContext) -> Result<(), JobError> {
+            let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+            MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+            sleep(Duration::from_millis(20)).await;
+            RUNNING.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Email;
+
+    impl Job for Email {
+        const NAME: &'static str = "email";
+        const QUEUE: &'static str = "emails";
+
+        async fn handle(self, _ctx: JobContext) -> Result<(), JobError> {
+            SENT.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Report {
+        millis: u64,
+    }
+
+    impl Job for Report {
+        const NAME: &'static str = "report";
+        const QUEUE: &'static str = "reports";
+
+        async fn handle(self, _ctx: JobContext) -> Result<(), JobError> {
+            sleep(Duration::from_millis(self.millis)).await;
+            REPORTS.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_limits() {
+        let queue = Queue::new(MemoryBackend::default());
+        for _ in 0..6 {
+            queue.dispatch(&Capture).await.unwrap();
+            queue.dispatch(&Email).await.unwrap();
+        }
+
+        let worker = Worker::new(&queue)
+            .register::<Capture>()
+            .register::<Email>()
+            .subscribe("payments", QueueOptions::default().concurrency(2))
+            .subscribe(
+                "emails",
+                QueueOptions::default().rate_limit(2, Duration::from_millis(500)),
+            )
+            .poll_interval(Duration::from_millis(5));
+        let running = tokio::spawn(worker.run());
+
+        sleep(Duration::from_millis(150)).await;
+        // a burst of 2, then one every 250ms
+        assert_eq!(SENT.load(Ordering::SeqCst), 2);
+        assert_eq!(MAX_RUNNING.load(Ordering::SeqCst), 2);
+        sleep(Duration::from_millis(250)).await;
+        assert_eq!(SENT.load(Ordering::SeqCst), 3);
+        running.abort();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_and_abandoned_jobs_are_buried() {
+        let queue = Queue::new(MemoryBackend::default());
+        let worker = Worker::new(&queue)
+            .register::<Email>()
+            .subscribe("emails", QueueOptions::default());
+
+        let mut unknown = JobRecord::new(&Email).unwrap();
+        unknown.name = "renamed".to_string();
+        queue.backend.push(unknown.clone()).await.unwrap();
+        assert_eq!(worker.run_once().await, Ok(true));
+        let job = queue.job(&unknown.id).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Retrying);
+        assert_eq!(job.errors[0].error, "no handler for job renamed");
+
+        // claimed 5 times by workers that died without settling it
+        let mut abandoned = JobRecord::new(&Email).unwrap();
+        abandoned.attempts = 5;
+        queue.backend.push(abandoned.clone()).await.unwrap();
+        assert_eq!(worker.run_once().await, Ok(true));
+        let job = queue.job(&abandoned.id).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Dead);
+        assert_eq!(
+            job.errors[0].error,
+            "abandoned by the worker running it on attempt 5"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribed_queue_is_not_claimed() {
+        let queue = Queue::new(MemoryBackend::default());
+        queue.dispatch(&Email).await.unwrap();
+        let worker = Worker::new(&queue)
+            .register::<Email>()
+            .subscribe("payments", QueueOptions::default());
+        assert_eq!(worker.run_once().await, Ok(false));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_then_releases() {
+        let backend = Arc::new(MemoryBackend::default());
+        let queue = Queue {
+            backend: backend.clone(),
+        };
+        queue.dispatch(&Report { millis: 50 }).await.unwrap();
+        queue.dispatch(&Report { millis: 10_000 }).await.unwrap();
+
+        let shutdown = CancellationToken::new();
+        let worker = Worker::new(&queue)
+            .register::<Report>()
+            .subscribe("reports", QueueOptions::default())
+            .poll_interval(Duration::from_millis(5))
+            .shutdown_timeout(Duration::from_millis(200));
+        let health = worker.health();
+        assert_eq!(health.state(), WorkerState::Idle);
+        let stopped = tokio::spawn(worker.run_until(shutdown.clone()));
+        sleep(Duration::from_millis(20)).await;
+        let report = health.report();
+        assert!(report.live && report.ready);
+        assert_eq!(report.running, 2);
+        shutdown.cancel();
+        queue.dispatch(&Report { millis: 0 }).await.unwrap();
+
+        timeout(Duration::from_secs(1), stopped)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(health.state(), WorkerState::Stopped);
+        assert!(!health.is_live());
+        // the short job finished, the long one was released, the new one never claimed
+        assert_eq!(REPORTS.load(Ordering::SeqCst), 1);
+        assert_eq!(backend.len(), 3);
+        let mut left = vec![];
+        while let Some(claimed) = backend
+            .claim("reports", Duration::from_secs(30))
+            .await
+            .unwrap()
+        {
+            left.push((claimed.job.payload, claimed.job.attempts));
+        }
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                (r#"{"millis":0}"#.to_string(), 1),
+                (r#"{"millis":10000}"#.to_string(), 1)
+            ]
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use tokio::sync::watch::Receiver;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// Application settings, loaded at startup and reloaded with `env::watch`
+#[derive(env::Config, Debug)]
+pub struct AppConfig {
+    pub database_url: env::Secret<String>,
+    #[env(default = "127.0.0.1:5800")]
+    pub http_addr: SocketAddr,
+    #[env(default = "info")]
+    pub log_level: LogLevel,
+    /// Bearer token of the `/admin` endpoints, which are not served without it
+    pub admin_token: Option<env::Secret<String>>,
+    /// Runs a worker inside the HTTP server, for development
+    #[env(default = "false")]
+    pub embedded_worker: bool,
+    /// Queues of the embedded worker, as `name` or `name=concurrency`
+    #[env(default = "default")]
+    pub worker_queues: Vec<String>,
+    /// Health endpoints of `snapshop worker`
+    #[env(default = "127.0.0.1:5801")]
+    pub worker_health_addr: SocketAddr,
+    /// Minimum password length in characters, instead of the policy default
+    pub password_min_length: Option<usize>,
+    /// Minimum estimated password entropy in bits, instead of the policy default
+    pub password_min_entropy: Option<f64>,
+    /// File of breached passwords to reject, one per line
+    pub password_breached_list: Option<PathBuf>,
+}
+
+/// Tracing filter directives such as `info,salvo=debug`, validated when loaded
+#[derive(Debug, Clone)]
+pub struct LogLevel(String);
+
+impl LogLevel {
+    pub fn filter(&self) -> EnvFilter {
+        EnvFilter::new(&self.0)
+    }
+}
+
+impl env::FromEnv for LogLevel {
+    fn from_env(raw: &str) -> Result<Self, String> {
+        match EnvFilter::try_new(raw) {
+            Ok(_) => Ok(LogLevel(raw.to_string())),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+/// Applies `log_level` updates to the running subscriber
+pub async fn reload_log_level(
+    mut config: Receiver<Arc<AppConfig>>,
+    handle: reload::Handle<EnvFilter, Registry>,
+) {
+    while config.changed().await.is_ok() {
+        let filter = config.borrow_and_update().log_level.filter();
+        if let Err(err) = handle.reload(filter) {
+            tracing::warn!("could not update the log level: {err}");
+        }
+    }
+}
@@ -1,19 +1,194 @@
-use modules::account;
-use salvo::{conn::TcpListener, Listener, Server};
+use std::sync::Arc;
+
+use config::AppConfig;
+use datastore::{
+    outbox::{Outbox, Relay},
+    reencrypt::ReencryptJob,
+    Datastore,
+};
+use modules::{account, health, jobs, password, request_id};
+use queue::Queue;
+use salvo::{conn::TcpListener, Listener, Router, Server};
 use tokio;
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt};
 
+mod config;
 mod modules;
+mod worker;
 #[tokio::main]
 async fn main() {
-    let router = salvo::Router::new();
-    let store = datastore::Datastore::new(env::get("DATABASE_URL").unwrap().as_str()).await;
-    let acceptor = TcpListener::new("127.0.0.1:5800").bind().await;
-    let router = account::bind_http_route(router, store);
+    let watcher = match env::watch::<config::AppConfig>() {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    let config = watcher.borrow().clone();
+
+    let (filter, log_level) = reload::Layer::new(config.log_level.filter());
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    tokio::spawn(config::reload_log_level(watcher, log_level));
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None | Some("serve") => serve(config).await,
+        Some("worker") => run_worker(config, &args[1..]).await,
+        Some("reencrypt") => reencrypt(config, &args[1..]).await,
+        Some(command) => {
+            eprintln!(
+                "unknown command {command:?}, expected `serve`, `worker [queue[=concurrency]]...` \
+                 or `reencrypt <collection> <field>...`"
+            );
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Connects to the database, installs the keyring and the job queue
+async fn connect(config: &AppConfig) -> (Datastore, Queue, Outbox) {
+    let store = Datastore::new(config.database_url.expose()).await;
+    // registration writes the user and its outbox messages in one transaction
+    match store.supports_transactions().await {
+        Ok(true) => (),
+        Ok(false) => {
+            eprintln!("MongoDB must run as a replica set, transactions are not supported");
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("could not reach MongoDB: {err}");
+            std::process::exit(1);
+        }
+    }
+    // fail at startup rather than on the first encrypted field
+    let keyring = crypto::keyring::Keyring::from_env().expect("Error loading AES keys");
+    crypto::keyring::install(keyring);
+    match password::PasswordPolicy::from_config(config) {
+        Ok(policy) => password::install(policy),
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    }
+    let jobs_backend = queue::MongoBackend::new(&store.client, "snapshop", "jobs");
+    if let Err(err) = jobs_backend.ensure_indexes().await {
+        tracing::warn!("could not create the job indexes: {err}");
+    }
+    let queue = Queue::new(jobs_backend);
+    queue::install(queue.clone());
+    let outbox = Outbox::new(&store.client, "snapshop", "outbox");
+    if let Err(err) = outbox.ensure_indexes().await {
+        tracing::warn!("could not create the outbox indexes: {err}");
+    }
+    (store, queue, outbox)
+}
+
+/// Relays the outbox to the queue until `shutdown`, in every process so that
+/// messages are published while any of them runs
+fn spawn_relay(outbox: Outbox, queue: Queue, shutdown: queue::CancellationToken) {
+    let relay = Relay::new(outbox, queue);
+    tokio::spawn(async move { relay.run_until(shutdown.cancelled_owned()).await });
+}
+
+fn build_worker(queue: &Queue, queues: &[String], metrics: &queue::Metrics) -> queue::Worker {
+    match worker::build(queue, queues, metrics) {
+        Ok(worker) => worker,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Serves the HTTP API, with a worker when `embedded_worker` is set
+async fn serve(config: Arc<AppConfig>) {
+    let (store, queue, outbox) = connect(&config).await;
+    let shutdown = queue::shutdown_signal();
+    spawn_relay(outbox.clone(), queue.clone(), shutdown.clone());
+    let mut health = health::Health::new(store.clone());
+    let mut embedded = None;
+    if config.embedded_worker {
+        let metrics = queue::Metrics::default();
+        let worker = build_worker(&queue, &config.worker_queues, &metrics);
+        health = health.with_worker(worker.health(), metrics);
+        tracing::info!("running an embedded worker on {:?}", config.worker_queues);
+        embedded = Some(tokio::spawn(worker.run_until(shutdown.clone())));
+    }
+
+    let acceptor = TcpListener::new(config.http_addr).bind().await;
+    let router = Router::new().hoop(request_id::RequestId);
+    let router = health::bind_http_route(router, health);
+    let router = account::bind_http_route(router, store, outbox);
+    let router = match &config.admin_token {
+        Some(token) => jobs::bind_http_route(router, queue, token.expose().clone()),
+        None => router,
+    };
 
     println!("{:#?}", &router.routers);
     println!(
         "Server started on: http://{}",
         &acceptor.local_addr().unwrap()
     );
-    Server::new(acceptor).serve(router).await;
+    let server = Server::new(acceptor);
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown.cancelled().await;
+        handle.stop_graceful(None);
+    });
+    server.serve(router).await;
+    if let Some(worker) = embedded {
+        let _ = worker.await;
+    }
+}
+
+/// `snapshop worker [queue[=concurrency]]...` runs jobs of the given queues,
+/// or of the default queue, until SIGTERM or Ctrl-C
+async fn run_worker(config: Arc<AppConfig>, queues: &[String]) {
+    let (store, queue, outbox) = connect(&config).await;
+    let shutdown = queue::shutdown_signal();
+    spawn_relay(outbox, queue.clone(), shutdown.clone());
+    let queues = match queues.is_empty() {
+        true => vec![queue::DEFAULT_QUEUE.to_string()],
+        false => queues.to_vec(),
+    };
+    let metrics = queue::Metrics::default();
+    let worker = build_worker(&queue, &queues, &metrics);
+    let health = health::Health::new(store).with_worker(worker.health(), metrics);
+
+    let acceptor = TcpListener::new(config.worker_health_addr).bind().await;
+    tracing::info!(
+        "worker running on {queues:?}, health on http://{}",
+        config.worker_health_addr
+    );
+    tokio::spawn(Server::new(acceptor).serve(health::bind_http_route(Router::new(), health)));
+    worker.run_until(shutdown).await;
+}
+
+/// `snapshop reencrypt <collection> <field>...` moves encrypted fields of a
+/// collection to the active key once keys are rotated
+async fn reencrypt(config: Arc<AppConfig>, args: &[String]) {
+    let (collection, fields) = match args.split_first() {
+        Some((collection, fields)) if !fields.is_empty() => (collection, fields),
+        _ => {
+            eprintln!("usage: snapshop reencrypt <collection> <field>...");
+            std::process::exit(2);
+        }
+    };
+    let store = Datastore::new(config.database_url.expose()).await;
+    let keyring = crypto::keyring::Keyring::from_env().expect("Error loading AES keys");
+    let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+    let job = ReencryptJob::new("snapshop", collection, &fields);
+    match job.run(&store.client, &keyring).await {
+        Ok(done) => println!(
+            "{} fields re-encrypted, {} skipped",
+            done.updated, done.skipped
+        ),
+        Err(err) => {
+            eprintln!("re-encryption failed: {err}");
+            std::process::exit(1);
+        }
+    }
 }
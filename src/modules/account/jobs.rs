@@ -0,0 +1,23 @@
+use queue::{Job, JobContext, JobError};
+use serde::{Deserialize, Serialize};
+
+/// Welcomes a newly registered user, written to the outbox by `register`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SendWelcomeEmail {
+    pub user_id: String,
+    pub email: String,
+}
+
+impl Job for SendWelcomeEmail {
+    const NAME: &'static str = "send_welcome_email";
+
+    async fn handle(self, ctx: JobContext) -> Result<(), JobError> {
+        // no mail provider yet, the job id deduplicates relayed copies once there is one
+        tracing::info!(
+            "sending the welcome email of user {} (job {})",
+            self.user_id,
+            ctx.id()
+        );
+        Ok(())
+    }
+}
@@ -1,16 +1,17 @@
-use datastore::Datastore;
+use datastore::{outbox::Outbox, Datastore};
 use json_response::{ApiResponse, RequestError};
 use salvo::{affix_state, handler, Depot, Request, Response, Router};
 use serde::{Deserialize, Serialize};
 use service::{error::AccountError, AccountService};
 
-use super::utils::{validate_email, validate_passowrd};
+use super::{password, utils::validate_email};
 
+pub mod jobs;
 mod model;
 mod service;
 
-pub fn bind_http_route<'a>(router: Router, store: Datastore) -> Router {
-    let svc = AccountService::new(store);
+pub fn bind_http_route<'a>(router: Router, store: Datastore, outbox: Outbox) -> Router {
+    let svc = AccountService::new(store, outbox);
     router
         .hoop(affix_state::inject(svc))
         .push(Router::new().path("/account/profile").post(profile_handler))
@@ -36,8 +37,11 @@ async fn register_handler(
         Ok(req) => {
             let RegisterRequest { email, password } = req;
             // validate password
-            if !validate_passowrd(password.as_str()) {
-                return ApiResponse::error(RequestError::BadRequest("invalid_password".into()));
+            if let Err(issues) = password::policy().check(password.as_str(), email.as_str()) {
+                return ApiResponse::error(RequestError::BadRequest(format!(
+                    "invalid_password:{}",
+                    password::reasons(&issues)
+                )));
             }
 
             if !validate_email(email.as_str()) {
@@ -98,7 +102,7 @@ mod register_tests {
         println!("{}", &content);
         assert_eq!(
             content,
-            r#"{"status":"failed","error":{"code":400,"message":"BadRequest:invalid_password"}}"#
+            r#"{"status":"failed","error":{"code":400,"message":"BadRequest:invalid_password:too_short,too_weak"}}"#
         );
     }
 }
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 
+use crypto::field::{self, Encrypted};
 use datastore::Model;
-use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, from_bson, oid::ObjectId, Bson, Document};
 use serde::{Deserialize, Serialize};
 
 // User model
@@ -40,6 +41,10 @@ pub(crate) struct User {
     #[serde(skip_serializing)]
     credentials: MetaData,
     pub profiles: HashMap<ProfileType, Profile>,
+
+    /// `credentials` as stored when the user was loaded
+    #[serde(skip)]
+    stored_credentials: Option<Bson>,
 }
 
 impl User {
@@ -51,6 +56,7 @@ impl User {
             meta: HashMap::default(),
             profiles: HashMap::default(),
             credentials: HashMap::default(),
+            stored_credentials: None,
         }
     }
 
@@ -59,6 +65,36 @@ impl User {
             .insert("password".to_string(), serde_json::Value::String(password));
         self
     }
+
+    /// Stored password hash, `None` for accounts without a password
+    pub fn password_hash(&self) -> Option<&str> {
+        self.credentials.get("password").and_then(|v| v.as_str())
+    }
+
+    /// Update document replacing the stored, encrypted credentials
+    pub fn credentials_update(&self) -> Result<Document, mongodb::bson::ser::Error> {
+        let id = match self._id {
+            Some(id) => id,
+            None => return Err(serde::ser::Error::custom("user without an id")),
+        };
+        let credentials = seal_credentials(&self.credentials, &id)?;
+        Ok(doc! {"$set": {"credentials": credentials}})
+    }
+
+    /// Whether the credentials are still stored in plaintext, as they were
+    /// before being encrypted at rest
+    pub fn has_plaintext_credentials(&self) -> bool {
+        matches!(self.stored_credentials, Some(Bson::Document(_)))
+    }
+
+    /// Filter matching this user only while its credentials are still the
+    /// ones it was loaded with, so that a concurrent change wins
+    pub fn credentials_filter(&self) -> Document {
+        match &self.stored_credentials {
+            Some(stored) => doc! {"_id": self._id, "credentials": stored.clone()},
+            None => doc! {"_id": self._id, "credentials": {"$exists": false}},
+        }
+    }
 }
 
 impl Model for User {
@@ -76,7 +112,7 @@ impl Model for User {
             .await
         {
             Ok(ok) => match ok {
-                Some(ok) => Ok(Some(ok.into())),
+                Some(ok) => Ok(Some(ok.try_into()?)),
                 None => Ok(None),
             },
             Err(err) => Err(err),
@@ -100,7 +136,7 @@ impl Model for User {
     where
         Self: Sized,
     {
-        let user_for_db: UserForDB = data.clone().into();
+        let user_for_db: UserForDB = data.clone().try_into()?;
         match client
             .database("snapshop")
             .collection::<UserForDB>("users")
@@ -115,6 +151,50 @@ impl Model for User {
             Err(err) => return Err(err),
         }
     }
+
+    async fn insert_one_with_session(
+        client: &mongodb::Client,
+        session: &mut mongodb::ClientSession,
+        data: &mut Self,
+    ) -> Result<ObjectId, mongodb::error::Error>
+    where
+        Self: Sized,
+    {
+        let user_for_db: UserForDB = data.clone().try_into()?;
+        match client
+            .database("snapshop")
+            .collection::<UserForDB>("users")
+            .insert_one(user_for_db)
+            .session(session)
+            .await
+        {
+            Ok(result) => {
+                let id = result.inserted_id.as_object_id().unwrap();
+                data._id = Some(id);
+                Ok(id)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn update_one(
+        client: &mongodb::Client,
+        query: mongodb::bson::Document,
+        update: mongodb::bson::Document,
+    ) -> Result<u64, mongodb::error::Error>
+    where
+        Self: Sized,
+    {
+        match client
+            .database("snapshop")
+            .collection::<UserForDB>("users")
+            .update_one(query, update)
+            .await
+        {
+            Ok(result) => Ok(result.modified_count),
+            Err(err) => Err(err),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -124,33 +204,69 @@ struct UserForDB {
     email: String,
     providers: Vec<AuthProvider>,
     meta: MetaData,
-    credentials: MetaData,
+    /// `Encrypted<MetaData>`, or a plaintext `MetaData` for users stored before
+    /// credentials were encrypted, kept as stored for conditional updates
+    credentials: Bson,
     profiles: HashMap<ProfileType, Profile>,
 }
 
-impl From<User> for UserForDB {
-    fn from(user: User) -> Self {
-        Self {
-            _id: user._id,
+impl TryFrom<User> for UserForDB {
+    type Error = mongodb::bson::ser::Error;
+
+    fn try_from(user: User) -> Result<Self, Self::Error> {
+        // new users get their id here, as it is part of the credentials context
+        let id = user._id.unwrap_or_default();
+        Ok(Self {
+            _id: Some(id),
             email: user.email,
             providers: user.providers,
             meta: user.meta,
-            credentials: user.credentials,
+            credentials: seal_credentials(&user.credentials, &id)?,
             profiles: user.profiles,
-        }
+        })
     }
 }
 
-impl From<UserForDB> for User {
-    fn from(user_db: UserForDB) -> Self {
-        Self {
+impl TryFrom<UserForDB> for User {
+    type Error = mongodb::bson::de::Error;
+
+    fn try_from(user_db: UserForDB) -> Result<Self, Self::Error> {
+        let credentials = match (&user_db.credentials, &user_db._id) {
+            // re-encrypted by the next write of the credentials
+            (Bson::Document(_), _) => from_bson::<MetaData>(user_db.credentials.clone())?,
+            (Bson::String(sealed), Some(id)) => {
+                match Encrypted::<MetaData>::open(sealed, &credentials_context(id)) {
+                    Ok(credentials) => credentials.into_inner(),
+                    Err(e) => return Err(serde::de::Error::custom(e)),
+                }
+            }
+            _ => return Err(serde::de::Error::custom("invalid credentials")),
+        };
+        Ok(Self {
             _id: user_db._id,
             email: user_db.email,
             providers: user_db.providers,
             meta: user_db.meta,
-            credentials: user_db.credentials,
+            credentials,
             profiles: user_db.profiles,
-        }
+            stored_credentials: Some(user_db.credentials),
+        })
+    }
+}
+
+/// Associated data of the credentials of the user `id`, so that they can't
+/// be moved to another user
+fn credentials_context(id: &ObjectId) -> String {
+    field::context("users", "credentials", &id.to_hex())
+}
+
+fn seal_credentials(
+    credentials: &MetaData,
+    id: &ObjectId,
+) -> Result<Bson, mongodb::bson::ser::Error> {
+    match Encrypted::new(credentials.clone()).seal(&credentials_context(id)) {
+        Ok(sealed) => Ok(Bson::String(sealed)),
+        Err(e) => Err(serde::ser::Error::custom(e)),
     }
 }
 
@@ -190,3 +306,26 @@ pub(crate) struct Profile {
     meta: MetaData,
     phone: Phone,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials_are_bound_to_their_user() {
+        crypto::keyring::install(
+            crypto::keyring::Keyring::new(1, b"Z44JJuldrAXxYpg0Z44JJuldrAXxYpg0").unwrap(),
+        );
+        let alice: UserForDB = User::new("alice@gmail.com".into())
+            .with_password("alice-hash".into())
+            .try_into()
+            .unwrap();
+        let loaded: User = alice.clone().try_into().unwrap();
+        assert_eq!(loaded.password_hash(), Some("alice-hash"));
+
+        // alice's credentials copied onto bob
+        let mut bob: UserForDB = User::new("bob@gmail.com".into()).try_into().unwrap();
+        bob.credentials = alice.credentials;
+        assert!(User::try_from(bob).is_err());
+    }
+}
@@ -1,5 +1,5 @@
-use super::model::User;
-use datastore::Datastore;
+use super::{jobs::SendWelcomeEmail, model::User};
+use datastore::{outbox::Outbox, Datastore};
 use error::AccountError;
 use mongodb::bson::doc;
 pub mod error {
@@ -12,6 +12,8 @@ pub mod error {
     pub enum AccountError {
         #[error_code(4001)]
         UserAlreadyExist,
+        #[error_code(4002)]
+        InvalidCredentials,
         InternalServerError(String),
     }
 
@@ -25,6 +27,7 @@ pub mod error {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             match self {
                 Self::UserAlreadyExist => f.write_str("UserAlreadyExist"),
+                Self::InvalidCredentials => f.write_str("InvalidCredentials"),
                 Self::InternalServerError(_) => f.write_str("InternalServerError"),
             }
         }
@@ -34,11 +37,12 @@ pub mod error {
 #[derive(Clone)]
 pub struct AccountService {
     store: Datastore,
+    outbox: Outbox,
 }
 
 impl AccountService {
-    pub fn new(store: Datastore) -> Self {
-        AccountService { store }
+    pub fn new(store: Datastore, outbox: Outbox) -> Self {
+        AccountService { store, outbox }
     }
 }
 
@@ -61,26 +65,99 @@ impl AccountService {
             Err(err) => return Err(error::AccountError::InternalServerError(err.to_string())),
         };
 
-        // Hash password using Bcrypt
-        let password = match crypto::hash::make(password.as_str()) {
+        // Hash password using Argon2id
+        let password = match hash_password(password).await {
             Ok(s) => s,
             Err(err) => return Err(error::AccountError::InternalServerError(err.to_string())),
         };
 
         let mut u = User::new(email).with_password(password);
-        match self.store.insert_one(&mut u).await {
+        match self.insert_with_welcome_email(&mut u).await {
             Ok(_) => Ok(()),
             Err(err) => return Err(error::AccountError::InternalServerError(err.to_string())),
         }
     }
 
+    /// Inserts `u` and the welcome email in one transaction, so that the
+    /// email is sent if and only if the user exists
+    async fn insert_with_welcome_email(&self, u: &mut User) -> Result<(), String> {
+        let mut session = match self.store.start_transaction().await {
+            Ok(session) => session,
+            Err(err) => return Err(err.to_string()),
+        };
+        let id = match self.store.insert_one_with_session(&mut session, u).await {
+            Ok(id) => id,
+            Err(err) => return Err(err.to_string()),
+        };
+        let welcome = SendWelcomeEmail {
+            user_id: id.to_hex(),
+            email: u.email.clone(),
+        };
+        let message = match queue::outbox_message(&welcome) {
+            Ok(message) => message,
+            Err(err) => return Err(err.to_string()),
+        };
+        if let Err(err) = self.outbox.write(&mut session, &message).await {
+            return Err(err.to_string());
+        }
+        match datastore::commit(&mut session).await {
+            Ok(()) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
     pub(crate) async fn login(
         &self,
         email: String,
         password: String,
     ) -> Result<(), error::AccountError> {
-        let r = self.store.find_one::<User>(doc! {"email": email}).await;
-        todo!()
+        let u = match self.store.find_one::<User>(doc! {"email": email}).await {
+            Ok(Some(u)) => u,
+            Ok(None) => return Err(error::AccountError::InvalidCredentials),
+            Err(err) => return Err(error::AccountError::InternalServerError(err.to_string())),
+        };
+        let hash = match u.password_hash() {
+            Some(h) => h.to_string(),
+            None => return Err(error::AccountError::InvalidCredentials),
+        };
+        match check_password(password.clone(), hash.clone()).await {
+            Ok(true) => (),
+            Ok(false) => return Err(error::AccountError::InvalidCredentials),
+            Err(err) => return Err(error::AccountError::InternalServerError(err)),
+        }
+
+        // Upgrade bcrypt or outdated Argon2 hashes while the plaintext is at hand,
+        // and encrypt credentials stored before encryption at rest
+        if crypto::hash::needs_rehash(hash.as_str()) || u.has_plaintext_credentials() {
+            if let Err(err) = self.rehash(u, password).await {
+                tracing::warn!("failed to rehash password: {err}");
+            }
+        }
+        Ok(())
+    }
+
+    async fn rehash(&self, u: User, password: String) -> Result<(), String> {
+        let u = match u.password_hash().is_some_and(crypto::hash::needs_rehash) {
+            true => match hash_password(password).await {
+                Ok(h) => u.with_password(h),
+                Err(err) => return Err(err),
+            },
+            false => u,
+        };
+        // conditional on the loaded credentials so a concurrent password change wins
+        let filter = u.credentials_filter();
+        let update = match u.credentials_update() {
+            Ok(update) => update,
+            Err(err) => return Err(err.to_string()),
+        };
+        match self.store.update_one::<User>(filter, update).await {
+            Ok(0) => {
+                tracing::info!("credentials changed during login, password not rehashed");
+                Ok(())
+            }
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        }
     }
 
     pub(crate) async fn verify_email(&self, email: String) -> Result<(), error::AccountError> {
@@ -92,11 +169,35 @@ impl AccountService {
     }
 }
 
+/// Hashes `password` on the blocking pool, Argon2 being too slow to run on
+/// the async workers
+async fn hash_password(password: String) -> Result<String, String> {
+    match tokio::task::spawn_blocking(move || crypto::hash::make(&password)).await {
+        Ok(Ok(hash)) => Ok(hash),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Checks `password` against `hash` on the blocking pool
+async fn check_password(password: String, hash: String) -> Result<bool, String> {
+    match tokio::task::spawn_blocking(move || crypto::hash::check(&password, &hash)).await {
+        Ok(valid) => Ok(valid),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use datastore::outbox::{MessageKind, Outbox};
+
     use crate::modules::account::service::{error::AccountError, AccountService};
 
-    use super::User;
+    use super::{doc, SendWelcomeEmail, User};
+
+    fn outbox(store: &datastore::Datastore) -> Outbox {
+        Outbox::new(&store.client, "snapshop", "outbox")
+    }
 
     #[tokio::test]
     async fn test_register_failed_email_exist() {
@@ -110,7 +211,7 @@ mod tests {
             .await
             .unwrap();
 
-        let svc = AccountService::new(store);
+        let svc = AccountService::new(store.clone(), outbox(&store));
         let r = svc
             .register("acme@gmail.com".into(), "password".into())
             .await;
@@ -122,4 +223,135 @@ mod tests {
             },
         };
     }
+
+    #[tokio::test]
+    async fn test_login_rehashes_bcrypt_password() {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (_server, connection_string) = crate::modules::utils::setup_test_db().await;
+        let store = datastore::Datastore::new(connection_string.as_str()).await;
+        // bcrypt hash of "password" with cost 4
+        let legacy = "$2b$04$BOo30IATMyqN/pLoxWxfTugzssJQkt38OIaGqPmvcA2IMArh9cgpq".to_string();
+        let _ = store
+            .clone()
+            .insert_one(&mut User::new("acme@gmail.com".into()).with_password(legacy))
+            .await
+            .unwrap();
+
+        let svc = AccountService::new(store.clone(), outbox(&store));
+        let r = svc.login("acme@gmail.com".into(), "wrong".into()).await;
+        assert_eq!(r, Err(AccountError::InvalidCredentials));
+        let r = svc
+            .login("nobody@gmail.com".into(), "password".into())
+            .await;
+        assert_eq!(r, Err(AccountError::InvalidCredentials));
+
+        svc.login("acme@gmail.com".into(), "password".into())
+            .await
+            .unwrap();
+        let u = store
+            .find_one::<User>(doc! {"email": "acme@gmail.com"})
+            .await
+            .unwrap()
+            .unwrap();
+        let hash = u.password_hash().unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(!crypto::hash::needs_rehash(hash));
+        svc.login("acme@gmail.com".into(), "password".into())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_register_writes_welcome_email() {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (_server, connection_string) = crate::modules::utils::setup_test_db().await;
+        let store = datastore::Datastore::new(connection_string.as_str()).await;
+        let outbox = outbox(&store);
+
+        let svc = AccountService::new(store.clone(), outbox.clone());
+        svc.register("acme@gmail.com".into(), "Tr0ub4dor&3-horse".into())
+            .await
+            .unwrap();
+        let u = store
+            .find_one::<User>(doc! {"email": "acme@gmail.com"})
+            .await
+            .unwrap()
+            .unwrap();
+        let pending = outbox.pending(10).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].kind, MessageKind::Job);
+        assert_eq!(pending[0].name, "send_welcome_email");
+        let welcome: SendWelcomeEmail = serde_json::from_str(&pending[0].payload).unwrap();
+        assert_eq!(welcome.user_id, u._id.unwrap().to_hex());
+    }
+
+    #[tokio::test]
+    async fn test_rehash_loses_to_concurrent_change() {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (_server, connection_string) = crate::modules::utils::setup_test_db().await;
+        let store = datastore::Datastore::new(connection_string.as_str()).await;
+        let legacy = "$2b$04$BOo30IATMyqN/pLoxWxfTugzssJQkt38OIaGqPmvcA2IMArh9cgpq".to_string();
+        let _ = store
+            .clone()
+            .insert_one(&mut User::new("acme@gmail.com".into()).with_password(legacy))
+            .await
+            .unwrap();
+        let query = doc! {"email": "acme@gmail.com"};
+        let loaded = store
+            .find_one::<User>(query.clone())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // a password reset lands between loading and rehashing
+        let reset = crypto::hash::make("new password").unwrap();
+        let update = loaded
+            .clone()
+            .with_password(reset.clone())
+            .credentials_update()
+            .unwrap();
+        store
+            .update_one::<User>(doc! {"_id": loaded._id}, update)
+            .await
+            .unwrap();
+
+        let svc = AccountService::new(store.clone(), outbox(&store));
+        svc.rehash(loaded, "password".to_string()).await.unwrap();
+        let u = store.find_one::<User>(query).await.unwrap().unwrap();
+        assert_eq!(u.password_hash(), Some(reset.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_login_encrypts_plaintext_credentials() {
+        let _ = tracing_subscriber::fmt::try_init();
+        let (_server, connection_string) = crate::modules::utils::setup_test_db().await;
+        let store = datastore::Datastore::new(connection_string.as_str()).await;
+        // stored before credentials were encrypted
+        let hash = crypto::hash::make("password").unwrap();
+        store
+            .client
+            .database("snapshop")
+            .collection::<mongodb::bson::Document>("users")
+            .insert_one(doc! {
+                "email": "acme@gmail.com",
+                "providers": [],
+                "meta": {},
+                "credentials": {"password": hash.as_str()},
+                "profiles": {},
+            })
+            .await
+            .unwrap();
+
+        let svc = AccountService::new(store.clone(), outbox(&store));
+        svc.login("acme@gmail.com".into(), "password".into())
+            .await
+            .unwrap();
+        let u = store
+            .find_one::<User>(doc! {"email": "acme@gmail.com"})
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!u.has_plaintext_credentials());
+        assert_eq!(u.password_hash(), Some(hash.as_str()));
+    }
 }
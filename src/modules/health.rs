@@ -0,0 +1,135 @@
+use std::time::Duration;
+
+use datastore::Datastore;
+use mongodb::bson::doc;
+use queue::{HealthReport, Metrics, WorkerHealth};
+use salvo::{affix_state, handler, http::StatusCode, writing::Json, Depot, Response, Router};
+use serde::Serialize;
+
+/// Longest wait for the database before reporting it down
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What the health endpoints of a process check
+#[derive(Clone)]
+pub struct Health {
+    store: Datastore,
+    worker: Option<(WorkerHealth, Metrics)>,
+}
+
+impl Health {
+    pub fn new(store: Datastore) -> Self {
+        Health {
+            store,
+            worker: None,
+        }
+    }
+
+    /// Also reports on a worker running in this process, and serves the
+    /// counters of its jobs
+    pub fn with_worker(mut self, worker: WorkerHealth, metrics: Metrics) -> Self {
+        self.worker = Some((worker, metrics));
+        self
+    }
+
+    async fn database_ok(&self) -> bool {
+        let admin = self.store.client.database("admin");
+        let ping = admin.run_command(doc! {"ping": 1});
+        matches!(tokio::time::timeout(PING_TIMEOUT, ping).await, Ok(Ok(_)))
+    }
+}
+
+/// `/health/live` and `/health/ready`, answering 503 when failing, and
+/// `/health/jobs` with the job counters of the worker
+pub fn bind_http_route(router: Router, health: Health) -> Router {
+    router.push(
+        Router::new()
+            .path("/health")
+            .hoop(affix_state::inject(health))
+            .push(Router::new().path("live").get(live_handler))
+            .push(Router::new().path("ready").get(ready_handler))
+            .push(Router::new().path("jobs").get(jobs_handler)),
+    )
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    worker: Option<HealthReport>,
+}
+
+fn render(res: &mut Response, body: HealthResponse) {
+    if !body.ok {
+        res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    res.render(Json(body));
+}
+
+/// The process is serving and its worker, if any, still runs
+#[handler]
+async fn live_handler(res: &mut Response, depot: &mut Depot) {
+    let health = depot.obtain::<Health>().unwrap();
+    let worker = health.worker.as_ref().map(|(w, _)| w.report());
+    let ok = worker.as_ref().is_none_or(|w| w.live);
+    render(
+        res,
+        HealthResponse {
+            ok,
+            database: None,
+            worker,
+        },
+    );
+}
+
+/// The database answers and the worker, if any, can claim jobs
+#[handler]
+async fn ready_handler(res: &mut Response, depot: &mut Depot) {
+    let health = depot.obtain::<Health>().unwrap();
+    let database = health.database_ok().await;
+    let worker = health.worker.as_ref().map(|(w, _)| w.report());
+    let ok = database && worker.as_ref().is_none_or(|w| w.ready);
+    render(
+        res,
+        HealthResponse {
+            ok,
+            database: Some(database),
+            worker,
+        },
+    );
+}
+
+/// Runs, failures and durations by job name, empty without a worker
+#[handler]
+async fn jobs_handler(res: &mut Response, depot: &mut Depot) {
+    let health = depot.obtain::<Health>().unwrap();
+    let metrics = match &health.worker {
+        Some((_, metrics)) => metrics.snapshot(),
+        None => Default::default(),
+    };
+    res.render(Json(metrics));
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use salvo::Service;
+
+    use super::{bind_http_route, Health};
+
+    #[tokio::test]
+    async fn test_live_reports_worker() {
+        let store = datastore::Datastore::new("mongodb://127.0.0.1:1/").await;
+        let worker = queue::Worker::new(&queue::Queue::new(queue::MemoryBackend::default()));
+        let health = Health::new(store).with_worker(worker.health(), queue::Metrics::default());
+        let service = Service::new(bind_http_route(salvo::Router::new(), health));
+
+        let mut res = TestClient::get("http://127.0.0.1:5800/health/live")
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(salvo::http::StatusCode::OK));
+        let content = res.take_string().await.unwrap();
+        assert!(content.starts_with(r#"{"ok":true,"worker":{"state":"idle""#));
+    }
+}
@@ -0,0 +1,224 @@
+use std::{fmt::Display, time::Duration};
+
+use futures_util::StreamExt;
+use json_response::{ApiResponse, Error, ErrorLogger, RequestError, ToJson};
+use queue::{JobFilter, JobId, JobRecord, JobStatus, Queue, QueueError};
+use salvo::{
+    affix_state, handler,
+    http::StatusCode,
+    sse::{SseEvent, SseKeepAlive},
+    Depot, FlowCtrl, Request, Response, Router,
+};
+use serde::Serialize;
+
+/// How often the event stream polls the queue for changes
+const EVENTS_POLL: Duration = Duration::from_secs(1);
+
+/// Upper bound of the `limit` query parameter
+const MAX_LIMIT: usize = 500;
+
+/// Admin endpoints to inspect jobs, behind a bearer token
+pub fn bind_http_route(router: Router, queue: Queue, admin_token: String) -> Router {
+    router.push(
+        Router::new()
+            .path("/admin/jobs")
+            .hoop(AdminAuth { token: admin_token })
+            .hoop(affix_state::inject(queue))
+            .get(list_handler)
+            .push(Router::new().path("events").get(events_handler))
+            .push(Router::new().path("<id>").get(job_handler)),
+    )
+}
+
+#[derive(Debug, PartialEq, Error, Serialize)]
+pub enum JobsError {
+    #[error_code(404)]
+    NotFound,
+    InternalServerError(String),
+}
+
+impl ErrorLogger for JobsError {
+    fn log_error(&self, _: &mut Request) {
+        if let Self::InternalServerError(err) = self {
+            tracing::error!("jobs admin: {err}");
+        }
+    }
+}
+
+impl Display for JobsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => f.write_str("NotFound"),
+            Self::InternalServerError(_) => f.write_str("InternalServerError"),
+        }
+    }
+}
+
+struct AdminAuth {
+    token: String,
+}
+
+#[handler]
+impl AdminAuth {
+    async fn handle(&self, req: &mut Request, res: &mut Response, ctrl: &mut FlowCtrl) {
+        let given = req
+            .header::<String>("authorization")
+            .and_then(|h| h.strip_prefix("Bearer ").map(str::to_string));
+        let authorized = match given {
+            Some(given) => constant_time_eq(given.as_bytes(), self.token.as_bytes()),
+            None => false,
+        };
+        if !authorized {
+            res.status_code(StatusCode::UNAUTHORIZED);
+            res.render(
+                ApiResponse::<(), RequestError<JobsError>>::error(RequestError::Unauthorized)
+                    .to_json(),
+            );
+            ctrl.skip_rest();
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// `GET /admin/jobs?status=&queue=&name=&limit=`, most recently updated first
+#[handler]
+async fn list_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+) -> ApiResponse<Vec<JobRecord>, RequestError<JobsError>> {
+    let mut filter = JobFilter::default();
+    if let Some(status) = req.query::<String>("status") {
+        match status.parse::<JobStatus>() {
+            Ok(status) => filter.status = Some(status),
+            Err(_) => return ApiResponse::error(RequestError::BadRequest("invalid_status".into())),
+        }
+    }
+    filter.queue = req.query::<String>("queue");
+    filter.name = req.query::<String>("name");
+    if let Some(limit) = req.query::<usize>("limit") {
+        filter.limit = limit.min(MAX_LIMIT);
+    }
+
+    let queue = depot.obtain::<Queue>().unwrap();
+    match queue.jobs(&filter).await {
+        Ok(jobs) => ApiResponse::success(jobs),
+        Err(err) => ApiResponse::error(RequestError::ServiceError(JobsError::InternalServerError(
+            err.to_string(),
+        ))),
+    }
+}
+
+/// `GET /admin/jobs/<id>`
+#[handler]
+async fn job_handler(
+    req: &mut Request,
+    depot: &mut Depot,
+) -> ApiResponse<JobRecord, RequestError<JobsError>> {
+    let id = match req.param::<String>("id") {
+        Some(id) => JobId::from(id),
+        None => return ApiResponse::error(RequestError::BadRequest("invalid_id".into())),
+    };
+    let queue = depot.obtain::<Queue>().unwrap();
+    match queue.job(&id).await {
+        Ok(Some(job)) => ApiResponse::success(job),
+        Ok(None) => ApiResponse::error(RequestError::ServiceError(JobsError::NotFound)),
+        Err(err) => ApiResponse::error(RequestError::ServiceError(JobsError::InternalServerError(
+            err.to_string(),
+        ))),
+    }
+}
+
+/// `GET /admin/jobs/events`, a `job` event with the job after each change
+#[handler]
+async fn events_handler(res: &mut Response, depot: &mut Depot) {
+    let queue = depot.obtain::<Queue>().unwrap();
+    let events = queue.changes(EVENTS_POLL).map(|job| match job {
+        Ok(job) => match SseEvent::default().name("job").json(&job) {
+            Ok(event) => Ok(event),
+            Err(err) => Err(QueueError::Payload(err.to_string())),
+        },
+        Err(err) => Err(err),
+    });
+    SseKeepAlive::new(events).stream(res);
+}
+
+#[cfg(test)]
+mod tests {
+    use queue::{MemoryBackend, Queue};
+    use salvo::http::header;
+    use salvo::test::{ResponseExt, TestClient};
+    use salvo::Service;
+    use serde::{Deserialize, Serialize};
+
+    use super::bind_http_route;
+
+    #[derive(Serialize, Deserialize)]
+    struct Noop;
+
+    impl queue::Job for Noop {
+        const NAME: &'static str = "noop";
+
+        async fn handle(self, _ctx: queue::JobContext) -> Result<(), queue::JobError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_jobs() {
+        let queue = Queue::new(MemoryBackend::default());
+        let id = queue.dispatch(&Noop).await.unwrap();
+        let service = Service::new(bind_http_route(
+            salvo::Router::new(),
+            queue,
+            "secret".to_string(),
+        ));
+
+        let content = TestClient::get("http://127.0.0.1:5800/admin/jobs")
+            .send(&service)
+            .await
+            .take_string()
+            .await
+            .unwrap();
+        assert_eq!(
+            content,
+            r#"{"status":"failed","error":{"code":401,"message":"Unauthorized"}}"#
+        );
+
+        let content = TestClient::get("http://127.0.0.1:5800/admin/jobs?status=queued&name=noop")
+            .add_header(header::AUTHORIZATION, "Bearer secret", true)
+            .send(&service)
+            .await
+            .take_string()
+            .await
+            .unwrap();
+        assert!(content.contains(&format!(r#""id":"{id}""#)));
+
+        let content = TestClient::get("http://127.0.0.1:5800/admin/jobs?status=done")
+            .add_header(header::AUTHORIZATION, "Bearer secret", true)
+            .send(&service)
+            .await
+            .take_string()
+            .await
+            .unwrap();
+        assert_eq!(
+            content,
+            r#"{"status":"failed","error":{"code":400,"message":"BadRequest:invalid_status"}}"#
+        );
+
+        let content = TestClient::get("http://127.0.0.1:5800/admin/jobs/unknown")
+            .add_header(header::AUTHORIZATION, "Bearer secret", true)
+            .send(&service)
+            .await
+            .take_string()
+            .await
+            .unwrap();
+        // `RequestError` reports service errors with its own default code
+        assert_eq!(
+            content,
+            r#"{"status":"failed","error":{"code":200,"message":"NotFound"}}"#
+        );
+    }
+}
@@ -1,2 +1,6 @@
 pub mod account;
+pub mod health;
+pub mod jobs;
+pub mod password;
+pub mod request_id;
 mod utils;
@@ -0,0 +1,288 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs,
+    sync::{Arc, RwLock},
+};
+
+use lazy_static::lazy_static;
+
+use crate::config::AppConfig;
+
+/// Most common passwords from public breach corpora, always rejected
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456",
+    "123456789",
+    "12345678",
+    "1234567890",
+    "password",
+    "password1",
+    "password123",
+    "qwerty",
+    "qwerty123",
+    "qwertyuiop",
+    "1q2w3e4r",
+    "1qaz2wsx",
+    "abc123",
+    "111111",
+    "000000",
+    "iloveyou",
+    "admin",
+    "admin123",
+    "welcome",
+    "letmein",
+    "monkey",
+    "dragon",
+    "football",
+    "baseball",
+    "sunshine",
+    "princess",
+    "superman",
+    "trustno1",
+    "passw0rd",
+    "zaq12wsx",
+];
+
+lazy_static! {
+    static ref GLOBAL: RwLock<Arc<PasswordPolicy>> = RwLock::new(Arc::default());
+}
+
+/// Sets the process-wide policy, see `PasswordPolicy::from_config`
+pub fn install(policy: PasswordPolicy) {
+    *GLOBAL.write().unwrap() = Arc::new(policy);
+}
+
+/// Policy set by `install`, or the default one
+pub fn policy() -> Arc<PasswordPolicy> {
+    GLOBAL.read().unwrap().clone()
+}
+
+/// Reason a password was rejected, displayed as a stable snake_case code
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PasswordIssue {
+    TooShort,
+    TooLong,
+    /// The estimated entropy is below the policy minimum
+    TooWeak,
+    /// The password appears in the breached password list
+    Breached,
+    /// The password contains the account email or its local part
+    ContainsEmail,
+}
+
+impl Display for PasswordIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooShort => f.write_str("too_short"),
+            Self::TooLong => f.write_str("too_long"),
+            Self::TooWeak => f.write_str("too_weak"),
+            Self::Breached => f.write_str("breached"),
+            Self::ContainsEmail => f.write_str("contains_email"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    min_entropy: f64,
+    breached: HashSet<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: crypto::hash::MAX_PASSWORD_BYTES,
+            min_entropy: 40.0,
+            breached: COMMON_PASSWORDS.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Minimum length in characters
+    pub fn min_length(mut self, n: usize) -> Self {
+        self.min_length = n;
+        self
+    }
+
+    /// Minimum estimated entropy in bits
+    pub fn min_entropy(mut self, bits: f64) -> Self {
+        self.min_entropy = bits;
+        self
+    }
+
+    /// Adds passwords to the breached list, compared case-insensitively
+    pub fn breached<I, S>(mut self, passwords: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.breached.extend(
+            passwords
+                .into_iter()
+                .map(|p| p.as_ref().trim().to_lowercase())
+                .filter(|p| !p.is_empty()),
+        );
+        self
+    }
+
+    /// Default policy tuned by the `password_*` settings, which are validated
+    /// when the config is loaded. Fails when the breached list can't be read.
+    pub fn from_config(config: &AppConfig) -> Result<Self, String> {
+        let mut policy = Self::default();
+        if let Some(n) = config.password_min_length {
+            policy = policy.min_length(n);
+        }
+        if let Some(bits) = config.password_min_entropy {
+            policy = policy.min_entropy(bits);
+        }
+        if let Some(path) = &config.password_breached_list {
+            match fs::read_to_string(path) {
+                Ok(list) => policy = policy.breached(list.lines()),
+                Err(err) => {
+                    return Err(format!(
+                        "could not read breached password list {}: {err}",
+                        path.display()
+                    ))
+                }
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Checks `password` for an account registered with `email`, returns every issue found
+    pub fn check(&self, password: &str, email: &str) -> Result<(), Vec<PasswordIssue>> {
+        let mut issues = vec![];
+        let length = password.chars().count();
+        if length < self.min_length {
+            issues.push(PasswordIssue::TooShort);
+        }
+        if password.len() > self.max_length {
+            issues.push(PasswordIssue::TooLong);
+        }
+        if entropy(password) < self.min_entropy {
+            issues.push(PasswordIssue::TooWeak);
+        }
+        let lower = password.to_lowercase();
+        if self.breached.contains(&lower) {
+            issues.push(PasswordIssue::Breached);
+        }
+        let email = email.to_lowercase();
+        let local = email.split('@').next().unwrap_or_default();
+        if !email.is_empty()
+            && (lower.contains(&email) || (local.len() >= 3 && lower.contains(local)))
+        {
+            issues.push(PasswordIssue::ContainsEmail);
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+}
+
+/// Estimated entropy in bits, from the character classes in use and the
+/// length without immediate repetitions
+pub fn entropy(password: &str) -> f64 {
+    let (mut lower, mut upper, mut digit, mut symbol, mut space, mut other) =
+        (false, false, false, false, false, false);
+    let mut length = 0;
+    let mut previous = None;
+    for c in password.chars() {
+        match c {
+            'a'..='z' => lower = true,
+            'A'..='Z' => upper = true,
+            '0'..='9' => digit = true,
+            ' ' => space = true,
+            c if c.is_ascii_punctuation() => symbol = true,
+            _ => other = true,
+        }
+        if previous != Some(c) {
+            length += 1;
+        }
+        previous = Some(c);
+    }
+
+    let pool = [
+        (lower, 26),
+        (upper, 26),
+        (digit, 10),
+        (symbol, 32),
+        (space, 1),
+        (other, 100),
+    ]
+    .iter()
+    .filter(|(used, _)| *used)
+    .map(|(_, size)| size)
+    .sum::<u32>();
+    if pool == 0 {
+        return 0.0;
+    }
+    length as f64 * f64::from(pool).log2()
+}
+
+/// Formats issues as `reason,reason` for `RequestError::BadRequest`
+pub fn reasons(issues: &[PasswordIssue]) -> String {
+    issues
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_short_and_weak() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(
+            policy.check("a", "acme@gmail.com"),
+            Err(vec![PasswordIssue::TooShort, PasswordIssue::TooWeak])
+        );
+        assert_eq!(
+            policy.check("aaaaaaaaaaaaaaaa", "acme@gmail.com"),
+            Err(vec![PasswordIssue::TooWeak])
+        );
+    }
+
+    #[test]
+    fn test_accepts_passphrase_with_spaces() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(
+            policy.check("correct horse battery staple", "acme@gmail.com"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_rejects_breached_and_email() {
+        let policy = PasswordPolicy::default().breached(["Tr0ub4dor&3"]);
+        assert_eq!(
+            policy.check("tr0ub4dor&3", "acme@gmail.com"),
+            Err(vec![PasswordIssue::Breached])
+        );
+        assert_eq!(
+            policy.check("Password", "acme@gmail.com"),
+            Err(vec![PasswordIssue::TooWeak, PasswordIssue::Breached])
+        );
+        assert_eq!(
+            policy.check("acme-Secure-2024!", "acme@gmail.com"),
+            Err(vec![PasswordIssue::ContainsEmail])
+        );
+    }
+
+    #[test]
+    fn test_reasons() {
+        assert_eq!(
+            reasons(&[PasswordIssue::TooShort, PasswordIssue::Breached]),
+            "too_short,breached"
+        );
+    }
+}
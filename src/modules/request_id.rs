@@ -0,0 +1,82 @@
+use mongodb::bson::oid::ObjectId;
+use salvo::{
+    handler,
+    http::{HeaderName, HeaderValue},
+    Depot, FlowCtrl, Request, Response,
+};
+use tracing::Instrument;
+
+const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request id taken from a client, longer ones are replaced
+const MAX_LEN: usize = 128;
+
+/// Tags each request with the `x-request-id` it came with, or a new one.
+///
+/// The id is echoed in the response, recorded on a `request` span and used as
+/// the correlation id of the jobs dispatched while handling the request.
+pub struct RequestId;
+
+#[handler]
+impl RequestId {
+    async fn handle(
+        &self,
+        req: &mut Request,
+        depot: &mut Depot,
+        res: &mut Response,
+        ctrl: &mut FlowCtrl,
+    ) {
+        let id = match req.header::<String>(HEADER) {
+            Some(id) if is_valid(&id) => id,
+            _ => ObjectId::new().to_hex(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            res.headers_mut().insert(HEADER, value);
+        }
+        let span = tracing::info_span!("request", request_id = %id);
+        queue::with_correlation_id(id, ctrl.call_next(req, depot, res))
+            .instrument(span)
+            .await;
+    }
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use salvo::{handler, Router, Service};
+
+    use super::RequestId;
+
+    #[handler]
+    async fn correlation_handler() -> String {
+        queue::correlation_id().unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let router = Router::new().hoop(RequestId).get(correlation_handler);
+        let service = Service::new(router);
+
+        let mut res = TestClient::get("http://127.0.0.1:5800/")
+            .add_header("x-request-id", "checkout-42", true)
+            .send(&service)
+            .await;
+        assert_eq!(res.headers().get("x-request-id").unwrap(), "checkout-42");
+        assert_eq!(res.take_string().await.unwrap(), "checkout-42");
+
+        let mut res = TestClient::get("http://127.0.0.1:5800/")
+            .add_header("x-request-id", "not valid", true)
+            .send(&service)
+            .await;
+        let generated = res.headers().get("x-request-id").unwrap().clone();
+        assert_eq!(generated.len(), 24);
+        assert_eq!(
+            res.take_string().await.unwrap(),
+            generated.to_str().unwrap()
+        );
+    }
+}
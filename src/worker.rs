@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use queue::{CatchPanic, Metrics, Queue, QueueOptions, Timeout, Trace, Worker};
+
+use crate::modules::account::jobs::SendWelcomeEmail;
+
+/// Longest a job may run before it fails and is retried
+const JOB_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Worker running the jobs of the app on `queues`, each given as `name` or
+/// `name=concurrency`, counting their runs in `metrics`
+pub fn build(queue: &Queue, queues: &[String], metrics: &Metrics) -> Result<Worker, String> {
+    // metrics count panics and timeouts as failures
+    let mut worker = Worker::new(queue)
+        .hoop(Trace)
+        .hoop(metrics.clone())
+        .hoop(CatchPanic)
+        .hoop(Timeout::new(JOB_TIMEOUT))
+        .register::<SendWelcomeEmail>();
+    for spec in queues {
+        let (name, options) = parse_queue(spec)?;
+        worker = worker.subscribe(name, options);
+    }
+    Ok(worker)
+}
+
+fn parse_queue(spec: &str) -> Result<(&str, QueueOptions), String> {
+    let (name, concurrency) = match spec.split_once('=') {
+        Some((name, concurrency)) => match concurrency.parse::<usize>() {
+            Ok(n) => (name, Some(n)),
+            Err(_) => return Err(format!("invalid concurrency in queue {spec:?}")),
+        },
+        None => (spec, None),
+    };
+    if name.is_empty() {
+        return Err(format!("invalid queue {spec:?}"));
+    }
+    let options = match concurrency {
+        Some(n) => QueueOptions::default().concurrency(n),
+        None => QueueOptions::default(),
+    };
+    Ok((name, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_queue;
+
+    #[test]
+    fn test_parse_queue() {
+        assert_eq!(parse_queue("emails").unwrap().0, "emails");
+        assert_eq!(parse_queue("payments=2").unwrap().0, "payments");
+        assert!(parse_queue("payments=two").is_err());
+        assert!(parse_queue("=2").is_err());
+    }
+}